@@ -0,0 +1,131 @@
+//! Sockets passed by systemd socket activation, see
+//! [sd_listen_fds(3)](https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html)
+//!
+//! The `FileDescriptorName=` of a socket in the `.socket` unit says what it is for: `dot`, `doh`,
+//! `doq` or `doh3` for the encrypted listeners, anything else for plain DNS over UDP or TCP.
+
+use std::net::{TcpListener, UdpSocket};
+use std::os::fd::{FromRawFd, RawFd};
+
+use socket2::{Socket, Type};
+
+use crate::config::Config;
+
+/// Listening sockets handed over by the service manager, by what they serve
+#[derive(Debug, Default)]
+pub struct ActivatedSockets {
+    /// Plain DNS over UDP
+    pub udp: Vec<UdpSocket>,
+    /// Plain DNS over TCP
+    pub tcp: Vec<TcpListener>,
+    /// DNS-over-TLS
+    pub dot: Vec<TcpListener>,
+    /// DNS-over-HTTPS
+    pub doh: Vec<TcpListener>,
+    /// DNS-over-QUIC
+    pub doq: Vec<UdpSocket>,
+    /// DNS-over-HTTP/3
+    pub doh3: Vec<UdpSocket>,
+}
+
+impl ActivatedSockets {
+    /// Takes over the sockets in `$LISTEN_FDS`, none when the daemon wasn't socket activated
+    pub fn from_env() -> Result<Self, String> {
+        let fds = sd_notify::listen_fds_with_names(true)
+            .map_err(|err| format!("invalid socket activation: {err}"))?;
+        // SAFETY: the service manager passed these to us alone, and the variables describing
+        // them were just removed, so nothing else takes them over
+        unsafe { Self::from_fds(fds) }
+    }
+
+    /// Sorts the sockets `fds` by their names.
+    ///
+    /// # Safety
+    ///
+    /// Each descriptor must be an open socket which isn't owned by anything else.
+    unsafe fn from_fds(fds: impl Iterator<Item = (RawFd, String)>) -> Result<Self, String> {
+        let mut sockets = Self::default();
+        for (fd, name) in fds {
+            let socket = Socket::from_raw_fd(fd);
+            let socket_type = socket
+                .r#type()
+                .map_err(|err| format!("activated socket {name} (fd {fd}): {err}"))?;
+            socket
+                .set_nonblocking(true)
+                .map_err(|err| format!("activated socket {name} (fd {fd}): {err}"))?;
+            match (name.as_str(), socket_type) {
+                ("dot", Type::STREAM) => sockets.dot.push(socket.into()),
+                ("doh", Type::STREAM) => sockets.doh.push(socket.into()),
+                ("doq", Type::DGRAM) => sockets.doq.push(socket.into()),
+                ("doh3", Type::DGRAM) => sockets.doh3.push(socket.into()),
+                ("dot" | "doh" | "doq" | "doh3", _) => {
+                    return Err(format!(
+                        "activated socket {name} (fd {fd}) has the wrong type {socket_type:?}"
+                    ))
+                }
+                (_, Type::DGRAM) => sockets.udp.push(socket.into()),
+                (_, Type::STREAM) => sockets.tcp.push(socket.into()),
+                (_, _) => {
+                    return Err(format!(
+                        "activated socket {name} (fd {fd}) is neither UDP nor TCP"
+                    ))
+                }
+            }
+        }
+        Ok(sockets)
+    }
+
+    /// Checks that every encrypted listener which got sockets is configured
+    pub fn check(&self, config: &Config) -> Result<(), String> {
+        for (name, passed, configured) in [
+            ("dot", !self.dot.is_empty(), config.dot.is_some()),
+            ("doh", !self.doh.is_empty(), config.doh.is_some()),
+            ("doq", !self.doq.is_empty(), config.doq.is_some()),
+            ("doh3", !self.doh3.is_empty(), config.doh3.is_some()),
+        ] {
+            if passed && !configured {
+                return Err(format!(
+                    "systemd passed {name} sockets, but [{name}] isn't configured"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::IntoRawFd;
+
+    #[test]
+    fn test_from_fds() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dot = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dot_addr = dot.local_addr().unwrap();
+        let doq = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fds = vec![
+            (udp.into_raw_fd(), "dns".to_string()),
+            (tcp.into_raw_fd(), "unknown".to_string()),
+            (dot.into_raw_fd(), "dot".to_string()),
+            (doq.into_raw_fd(), "doq".to_string()),
+        ];
+
+        let sockets = unsafe { ActivatedSockets::from_fds(fds.into_iter()) }.unwrap();
+        assert_eq!(sockets.udp.len(), 1);
+        assert_eq!(sockets.udp[0].local_addr().unwrap(), udp_addr);
+        assert_eq!(sockets.tcp.len(), 1);
+        assert_eq!(sockets.dot[0].local_addr().unwrap(), dot_addr);
+        assert_eq!(sockets.doq.len(), 1);
+        assert!(sockets.doh.is_empty() && sockets.doh3.is_empty());
+        // DNS-over-QUIC isn't configured
+        assert!(sockets.check(&Config::default()).is_err());
+
+        // DoT is served over TCP only
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fds = vec![(udp.into_raw_fd(), "dot".to_string())];
+        assert!(unsafe { ActivatedSockets::from_fds(fds.into_iter()) }.is_err());
+    }
+}
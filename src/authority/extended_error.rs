@@ -0,0 +1,74 @@
+//! Extended DNS Errors, [RFC 8914](https://www.rfc-editor.org/rfc/rfc8914)
+
+use hickory_proto::rr::rdata::opt::EdnsOption;
+
+/// EDNS option code of an Extended DNS Error
+pub const EDE_OPTION_CODE: u16 = 15;
+
+/// The INFO-CODEs of Extended DNS Errors that we send
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtendedErrorCode {
+    /// The error doesn't match any of the other codes, see the extra text
+    Other,
+    /// The query or the requested operation is not supported
+    NotSupported,
+    /// No nameserver could be reached, or none answered in time
+    NoReachableAuthority,
+    /// A nameserver could not be talked to because of a network error
+    NetworkError,
+}
+
+impl From<ExtendedErrorCode> for u16 {
+    fn from(code: ExtendedErrorCode) -> Self {
+        match code {
+            ExtendedErrorCode::Other => 0,
+            ExtendedErrorCode::NotSupported => 21,
+            ExtendedErrorCode::NoReachableAuthority => 22,
+            ExtendedErrorCode::NetworkError => 23,
+        }
+    }
+}
+
+/// An Extended DNS Error, explaining why a response is an error
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtendedError {
+    /// What went wrong
+    pub code: ExtendedErrorCode,
+    /// Human readable details, may be empty
+    pub extra_text: String,
+}
+
+impl ExtendedError {
+    /// Creates a new Extended DNS Error
+    pub fn new(code: ExtendedErrorCode, extra_text: impl Into<String>) -> Self {
+        Self {
+            code,
+            extra_text: extra_text.into(),
+        }
+    }
+}
+
+impl From<ExtendedError> for EdnsOption {
+    fn from(error: ExtendedError) -> Self {
+        let mut data = u16::from(error.code).to_be_bytes().to_vec();
+        data.extend_from_slice(error.extra_text.as_bytes());
+        Self::Unknown(EDE_OPTION_CODE, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_edns_option() {
+        let option = EdnsOption::from(ExtendedError::new(
+            ExtendedErrorCode::NoReachableAuthority,
+            "timed out",
+        ));
+        assert_eq!(
+            option,
+            EdnsOption::Unknown(15, b"\x00\x16timed out".to_vec())
+        );
+    }
+}
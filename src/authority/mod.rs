@@ -16,6 +16,7 @@ mod authority;
 pub(crate) mod authority_object;
 mod catalog;
 mod error;
+pub mod extended_error;
 pub(crate) mod message_request;
 mod message_response;
 pub mod mushroom;
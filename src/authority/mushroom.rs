@@ -1,13 +1,127 @@
+use crate::authority::extended_error::{ExtendedError, ExtendedErrorCode};
 use crate::authority::MessageResponseBuilder;
-use crate::lookup::hickory_lookup;
+use crate::config::Config;
+use crate::ipv6::Ipv6Connectivity;
+use crate::lookup::{hickory_lookup, spawn_network_refresh, Strategy};
+use crate::network::NetworkLinks;
+use crate::routing::Router;
 use crate::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
-use hickory_proto::op::Header;
-use hickory_resolver::TokioResolver;
+use crate::split_dns::SplitDns;
+use crate::upstream::Upstream;
+use hickory_proto::op::{Edns, Header, MessageType, OpCode, ResponseCode};
+use hickory_proto::rr::{Record, RecordType};
+use hickory_proto::ProtoErrorKind;
+use hickory_resolver::config::NameServerConfigGroup;
+use hickory_resolver::ResolveError;
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Instant;
+use tracing::{info, warn};
+
+/// The EDNS version we implement
+const EDNS_VERSION: u8 = 0;
 
 pub struct Mushroom {
-    pub resolver: TokioResolver,
-    pub ipv4_resolver: TokioResolver,
+    /// All upstream nameservers, used while IPv6 upstreams can be reached
+    pub resolver: Upstream,
+    /// Only the IPv4 upstream nameservers
+    pub ipv4_resolver: Upstream,
+    /// Whether IPv6 upstreams can be reached, picking between the two resolvers
+    pub ipv6: Arc<Ipv6Connectivity>,
+    /// The links of the network and the nameservers they were handed
+    pub links: Arc<NetworkLinks>,
+    /// Nameservers and routing domains of each interface, answering below their domains
+    pub split_dns: Arc<SplitDns>,
+    pub router: Router,
+    pub strategies: HashMap<String, Strategy>,
+    /// EDNS UDP payload size advertised to clients
+    pub edns_max_payload: u16,
+}
+
+impl Mushroom {
+    /// Sets up the upstream resolvers and the routing strategies from the config, with the links
+    /// NetworkManager reports on the system bus
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        Self::with_links(config, NetworkLinks::new())
+    }
+
+    /// Sets up the upstream resolvers and the routing strategies from the config, with the links
+    /// read from elsewhere
+    pub fn with_links(config: &Config, links: Arc<NetworkLinks>) -> Result<Self, String> {
+        let opts = config.upstream_options();
+        let mut resolver_config = NameServerConfigGroup::new();
+        let mut ipv4_resolver_config = NameServerConfigGroup::new();
+        for name_server_cfg in config.upstream.all_name_servers().iter() {
+            if name_server_cfg.socket_addr.is_ipv4() {
+                ipv4_resolver_config.push(name_server_cfg.clone());
+            }
+            resolver_config.push(name_server_cfg.clone());
+        }
+
+        let ipv6 = Ipv6Connectivity::new();
+        let mut strategies = HashMap::new();
+        for (name, strategy) in &config.strategies {
+            let strategy = Strategy::from_config(strategy, &links.current(), ipv6.is_enabled())
+                .map_err(|err| format!("failed to set up strategy {name}: {err}"))?;
+            strategies.insert(name.clone(), strategy);
+        }
+        let split_dns = SplitDns::new(&config.links)?;
+        split_dns.refresh(&links.current(), ipv6.is_enabled());
+
+        Ok(Self {
+            resolver: Upstream::new(&resolver_config, opts.clone()),
+            ipv4_resolver: Upstream::new(&ipv4_resolver_config, opts),
+            ipv6,
+            links,
+            split_dns: Arc::new(split_dns),
+            router: Router::new(&config.routes),
+            strategies,
+            edns_max_payload: config.edns_udp_payload_size,
+        })
+    }
+
+    /// Starts keeping the IPv6 connectivity, and the strategies that depend on the network, up to
+    /// date with it
+    pub fn watch_networks(&self) {
+        self.ipv6.watch();
+        self.links.watch();
+        let networks = self
+            .strategies
+            .values()
+            .filter_map(|strategy| match strategy {
+                Strategy::Network(network) => Some(network.clone()),
+                _ => None,
+            })
+            .collect();
+        spawn_network_refresh(
+            networks,
+            self.split_dns.clone(),
+            self.links.clone(),
+            self.ipv6.clone(),
+        );
+    }
+
+    /// Drops the cached responses of every forwarder
+    pub fn flush_caches(&self) {
+        self.for_each_upstream(Upstream::clear_cache);
+        info!("Flushed all caches");
+    }
+
+    /// Forgets the round trip times of every forwarder's servers
+    pub fn reset_statistics(&self) {
+        self.for_each_upstream(Upstream::reset_statistics);
+        info!("Reset the server statistics");
+    }
+
+    /// Calls `f` with the upstreams, the forwarders of the strategies and those of the links
+    fn for_each_upstream(&self, f: impl Fn(&Upstream)) {
+        f(&self.resolver);
+        f(&self.ipv4_resolver);
+        let strategies = self.strategies.values().filter_map(Strategy::upstream);
+        for upstream in strategies.chain(self.split_dns.upstreams()) {
+            f(&upstream);
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -18,22 +132,101 @@ impl RequestHandler for Mushroom {
         mut response_handle: R,
     ) -> ResponseInfo {
         let x = request.request_info().query;
+        let mut mb = MessageResponseBuilder::new(Some(request.raw_query()));
+        let mut header = Header::response_from_request(request.header());
+
+        if let Some(req_edns) = request.edns() {
+            if req_edns.version() > EDNS_VERSION {
+                warn!(
+                    "request edns version greater than {}: {}",
+                    EDNS_VERSION,
+                    req_edns.version()
+                );
+                let mut resp_edns = response_edns(req_edns, self.edns_max_payload);
+                header.set_response_code(ResponseCode::BADVERS);
+                resp_edns.set_rcode_high(ResponseCode::BADVERS.high());
+                mb.edns(resp_edns);
+                return response_handle
+                    .send_response(mb.build_no_records(header), 0, false)
+                    .await
+                    .expect("being able to send a dns response");
+            }
+        }
+        let response_edns = request
+            .edns()
+            .map(|req_edns| response_edns(req_edns, self.edns_max_payload));
+        let dnssec_ok = response_edns
+            .as_ref()
+            .is_some_and(|edns| edns.flags().dnssec_ok);
+
+        // requests without exactly one question never get here, decoding them already fails and
+        // the server answers FORMERR, and it answers NOTIMP to opcodes it can't decode
+        match request.message_type() {
+            MessageType::Query if request.op_code() == OpCode::Query => {}
+            MessageType::Query => {
+                let op_code = request.op_code();
+                warn!("unimplemented op_code: {:?}", op_code);
+                let failure = LookupFailure::new(
+                    ResponseCode::NotImp,
+                    ExtendedError::new(
+                        ExtendedErrorCode::NotSupported,
+                        format!("opcode {op_code} is not supported"),
+                    ),
+                );
+                return failure
+                    .send(response_edns, mb, header, response_handle, 0, false)
+                    .await;
+            }
+            MessageType::Response => {
+                warn!("got a response as a request from id: {}", request.id());
+                let failure = LookupFailure {
+                    response_code: ResponseCode::FormErr,
+                    soa: None,
+                    extended_error: None,
+                };
+                return failure
+                    .send(response_edns, mb, header, response_handle, 0, false)
+                    .await;
+            }
+        }
+
+        if matches!(x.query_type(), RecordType::AXFR | RecordType::IXFR) {
+            let failure = LookupFailure::new(
+                ResponseCode::Refused,
+                ExtendedError::new(
+                    ExtendedErrorCode::NotSupported,
+                    "zone transfers are not supported",
+                ),
+            );
+            return failure
+                .send(response_edns, mb, header, response_handle, 0, false)
+                .await;
+        }
+
         let now = Instant::now();
-        let (result, ipv6_enabled) = hickory_lookup(self, &x.name().to_string(), x.query_type()).await;
+        let (result, ipv6_enabled) =
+            hickory_lookup(self, x.name(), x.query_type(), dnssec_ok).await;
         let lookup_time = now.elapsed().as_millis();
 
-        let mb = MessageResponseBuilder::new(Some(request.raw_query()));
-
         let response_info = match result {
             Ok(result) => {
+                // only zone strategies answer authoritatively, forwarders clear the AA bit
+                header
+                    .set_response_code(result.response_code())
+                    .set_authoritative(result.authoritative())
+                    .set_authentic_data(result.authentic_data())
+                    .set_recursion_available(true);
+                if let Some(resp_edns) = response_edns {
+                    mb.edns(resp_edns);
+                }
+
+                // sections as the upstream sent them, its OPT record was already split off
                 let message_response = mb.build(
-                    Header::response_from_request(request.header()),
-                    result
-                        .record_iter()
-                        .filter(|x1| !(x1.record_type().is_soa() || x1.record_type().is_ns())),
-                    result.record_iter().filter(|x1| x1.record_type().is_ns()),
-                    result.record_iter().filter(|x1| x1.record_type().is_soa()),
-                    vec![].into_iter(),
+                    header,
+                    result.answers(),
+                    result.name_servers(),
+                    None,
+                    result.additionals(),
                 );
                 response_handle
                     .send_response(message_response, lookup_time, ipv6_enabled)
@@ -41,25 +234,331 @@ impl RequestHandler for Mushroom {
                     .expect("being able to send a dns response")
             }
             Err(err) => {
-                if err.is_no_records_found() {
-                    let message_response =
-                        mb.build_no_records(Header::response_from_request(request.header()));
-                    response_handle
-                        .send_response(message_response, lookup_time, ipv6_enabled)
-                        .await
-                        .expect("being able to send a dns response")
-                } else {
-                    // TODO: Return proper error lol
-                    let message_response =
-                        mb.build_no_records(Header::response_from_request(request.header()));
-                    response_handle
-                        .send_response(message_response, lookup_time, ipv6_enabled)
-                        .await
-                        .expect("being able to send a dns response")
+                let failure = LookupFailure::from(&err);
+                if let Some(extended_error) = &failure.extended_error {
+                    warn!(
+                        "{} {} failed with {}: {} ({:?})",
+                        x.name(),
+                        x.query_type(),
+                        failure.response_code,
+                        err,
+                        extended_error.code
+                    );
                 }
+                failure
+                    .send(
+                        response_edns,
+                        mb,
+                        header,
+                        response_handle,
+                        lookup_time,
+                        ipv6_enabled,
+                    )
+                    .await
             }
         };
 
         response_info
     }
 }
+
+/// The OPT record answering the client's, RFC 6891 section 6.1.1.
+///
+/// The payload size is the smaller of the client's and ours, so responses fit both buffers, and
+/// the DO bit is echoed as RFC 3225 asks.
+fn response_edns(req_edns: &Edns, max_payload: u16) -> Edns {
+    let mut resp_edns = Edns::new();
+    resp_edns
+        .set_version(EDNS_VERSION)
+        .set_dnssec_ok(req_edns.flags().dnssec_ok)
+        .set_max_payload(req_edns.max_payload().max(512).min(max_payload));
+    resp_edns
+}
+
+/// How a lookup that didn't produce any records is answered
+#[derive(Debug, PartialEq)]
+struct LookupFailure {
+    response_code: ResponseCode,
+    /// The SOA of the zone for negative answers, so clients know how long to cache them
+    soa: Option<Record>,
+    extended_error: Option<ExtendedError>,
+}
+
+impl LookupFailure {
+    fn new(response_code: ResponseCode, extended_error: ExtendedError) -> Self {
+        Self {
+            response_code,
+            soa: None,
+            extended_error: Some(extended_error),
+        }
+    }
+
+    fn server_failure(code: ExtendedErrorCode, extra_text: impl Into<String>) -> Self {
+        Self::new(ResponseCode::ServFail, ExtendedError::new(code, extra_text))
+    }
+
+    /// Sends the failure, with the extended error in the OPT record if the client sent one
+    async fn send<R: ResponseHandler>(
+        self,
+        response_edns: Option<Edns>,
+        mut mb: MessageResponseBuilder<'_>,
+        mut header: Header,
+        mut response_handle: R,
+        lookup_time: u128,
+        ipv6_enabled: bool,
+    ) -> ResponseInfo {
+        header.set_response_code(self.response_code);
+
+        // an OPT record, and with it the extended error, may only be sent to clients that sent one
+        if let Some(mut resp_edns) = response_edns {
+            if let Some(extended_error) = self.extended_error {
+                resp_edns.options_mut().insert(extended_error.into());
+            }
+            mb.edns(resp_edns);
+        }
+
+        let message_response = mb.build(header, None, None, self.soa.iter(), None);
+        response_handle
+            .send_response(message_response, lookup_time, ipv6_enabled)
+            .await
+            .expect("being able to send a dns response")
+    }
+}
+
+impl From<&ResolveError> for LookupFailure {
+    fn from(err: &ResolveError) -> Self {
+        let Some(proto) = err.proto() else {
+            return Self::server_failure(ExtendedErrorCode::Other, err.to_string());
+        };
+
+        match proto.kind() {
+            ProtoErrorKind::NoRecordsFound {
+                response_code: response_code @ (ResponseCode::NXDomain | ResponseCode::NoError),
+                soa,
+                ..
+            } => Self {
+                response_code: *response_code,
+                soa: soa
+                    .as_ref()
+                    .map(|soa| soa.as_ref().clone().into_record_of_rdata()),
+                extended_error: None,
+            },
+            ProtoErrorKind::NoRecordsFound { response_code, .. } => Self::new(
+                *response_code,
+                ExtendedError::new(
+                    ExtendedErrorCode::Other,
+                    format!("upstream answered {response_code}"),
+                ),
+            ),
+            ProtoErrorKind::RequestRefused => Self::new(
+                ResponseCode::Refused,
+                ExtendedError::new(ExtendedErrorCode::Other, "upstream refused the request"),
+            ),
+            ProtoErrorKind::Timeout | ProtoErrorKind::NoConnections | ProtoErrorKind::Busy => {
+                Self::server_failure(ExtendedErrorCode::NoReachableAuthority, proto.to_string())
+            }
+            ProtoErrorKind::Io(_) => {
+                Self::server_failure(ExtendedErrorCode::NetworkError, proto.to_string())
+            }
+            _ => Self::server_failure(ExtendedErrorCode::Other, proto.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authority::extended_error::EDE_OPTION_CODE;
+    use crate::authority::MessageRequest;
+    use crate::server::{ClientIdentity, ResponseHandle, ServerFuture};
+    use crate::store::forwarder::ForwardConfig;
+    use futures_util::StreamExt;
+    use hickory_proto::serialize::binary::BinDecodable;
+    use hickory_proto::op::{Message, Query};
+    use hickory_proto::rr::rdata::opt::EdnsCode;
+    use hickory_proto::xfer::Protocol;
+    use hickory_proto::BufDnsStreamHandle;
+    use hickory_proto::rr::rdata::SOA;
+    use hickory_proto::rr::Name;
+    use hickory_proto::ProtoError;
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use tokio::time::timeout;
+
+    fn no_records(response_code: ResponseCode, soa: Option<Record<SOA>>) -> ResolveError {
+        let query = Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::A);
+        ProtoError::nx_error(
+            Box::new(query),
+            soa.map(Box::new),
+            None,
+            None,
+            response_code,
+            true,
+            None,
+        )
+        .into()
+    }
+
+    /// A Mushroom without upstreams, strategies or links, for requests that never get to a lookup
+    fn mushroom() -> Mushroom {
+        let config = Config {
+            upstream: ForwardConfig {
+                presets: vec![],
+                name_servers: NameServerConfigGroup::new(),
+                options: None,
+            },
+            strategies: BTreeMap::new(),
+            routes: vec![],
+            ..Config::default()
+        };
+        // nothing listens there, so there are no links
+        let links = NetworkLinks::on_bus(Some("unix:path=/nonexistent".to_string()));
+        Mushroom::with_links(&config, links).unwrap()
+    }
+
+    /// Hands `message` to the Mushroom as if it came over UDP and returns its response
+    async fn handle(mushroom: &Mushroom, message: &Message) -> Message {
+        let src = SocketAddr::from(([127, 0, 0, 1], 5353));
+        let request = MessageRequest::from_bytes(&message.to_vec().unwrap()).unwrap();
+        let request = Request::new(request, src, Protocol::Udp, ClientIdentity::default());
+        let (stream_handle, mut sent) = BufDnsStreamHandle::new(src);
+        let response_handle = ResponseHandle::new(src, stream_handle, Protocol::Udp);
+        mushroom.handle_request(&request, response_handle).await;
+        Message::from_vec(sent.next().await.unwrap().bytes()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_op_codes() {
+        let mushroom = mushroom();
+        let mut request = Message::new();
+        request
+            .add_query(Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::SOA))
+            .set_edns(Edns::new());
+
+        for op_code in [OpCode::Update, OpCode::Notify, OpCode::Status] {
+            let response = handle(&mushroom, request.set_op_code(op_code)).await;
+            assert_eq!(response.response_code(), ResponseCode::NotImp);
+            let extended_error = ExtendedError::new(
+                ExtendedErrorCode::NotSupported,
+                format!("opcode {op_code} is not supported"),
+            );
+            assert_eq!(
+                response.extensions().as_ref().unwrap().option(EdnsCode::from(EDE_OPTION_CODE)),
+                Some(&extended_error.into())
+            );
+        }
+
+        // a response sent as a request
+        request
+            .set_op_code(OpCode::Query)
+            .set_message_type(MessageType::Response);
+        let response = handle(&mushroom, &request).await;
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_requests() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let mut server = ServerFuture::new(mushroom());
+        server.register_socket(socket);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let response = |request: Vec<u8>| {
+            let client = &client;
+            async move {
+                client.send_to(&request, server_addr).await.unwrap();
+                let mut buffer = [0; 512];
+                let recv = client.recv_from(&mut buffer);
+                let (len, _) = timeout(Duration::from_secs(5), recv).await.unwrap().unwrap();
+                Message::from_vec(&buffer[..len]).unwrap()
+            }
+        };
+
+        // requests without exactly one question don't even decode
+        let question: &[u8] = b"\x03www\x07example\x03com\x00\x00\x01\x00\x01";
+        for (count, questions) in [(0, vec![]), (2, [question, question].concat())] {
+            let mut request = vec![0, 1, 1, 0, 0, count, 0, 0, 0, 0, 0, 0];
+            request.extend_from_slice(&questions);
+            assert!(MessageRequest::from_bytes(&request).is_err());
+            let response = response(request).await;
+            assert_eq!(response.id(), 1);
+            assert_eq!(response.response_code(), ResponseCode::FormErr);
+        }
+
+        // neither does the obsolete IQUERY, which hickory has no opcode for
+        let mut request = vec![0, 2, 0b0000_1001, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        request.extend_from_slice(question);
+        assert!(MessageRequest::from_bytes(&request).is_err());
+        let response = response(request).await;
+        assert_eq!(response.id(), 2);
+        assert_eq!(response.response_code(), ResponseCode::NotImp);
+
+        server.shutdown_gracefully().await.unwrap();
+    }
+
+    #[test]
+    fn test_response_edns() {
+        let mut req_edns = Edns::new();
+        req_edns.set_max_payload(4096).set_dnssec_ok(true);
+        let resp_edns = response_edns(&req_edns, 1232);
+        assert_eq!(resp_edns.max_payload(), 1232);
+        assert_eq!(resp_edns.version(), 0);
+        assert!(resp_edns.flags().dnssec_ok);
+
+        req_edns.set_max_payload(100).set_dnssec_ok(false);
+        let resp_edns = response_edns(&req_edns, 1232);
+        assert_eq!(resp_edns.max_payload(), 512);
+        assert!(!resp_edns.flags().dnssec_ok);
+    }
+
+    #[test]
+    fn test_negative_answers() {
+        let origin = Name::from_str("example.com.").unwrap();
+        let soa = Record::from_rdata(
+            origin.clone(),
+            3600,
+            SOA::new(origin.clone(), origin, 1, 3600, 600, 86400, 300),
+        );
+
+        let failure = LookupFailure::from(&no_records(ResponseCode::NXDomain, Some(soa.clone())));
+        assert_eq!(failure.response_code, ResponseCode::NXDomain);
+        assert_eq!(failure.soa, Some(soa.clone().into_record_of_rdata()));
+        assert_eq!(failure.extended_error, None);
+
+        let failure = LookupFailure::from(&no_records(ResponseCode::NoError, Some(soa)));
+        assert_eq!(failure.response_code, ResponseCode::NoError);
+        assert!(failure.soa.is_some());
+    }
+
+    #[test]
+    fn test_failures() {
+        let failure = LookupFailure::from(&no_records(ResponseCode::ServFail, None));
+        assert_eq!(failure.response_code, ResponseCode::ServFail);
+        assert_eq!(failure.soa, None);
+
+        let failure = LookupFailure::from(&no_records(ResponseCode::Refused, None));
+        assert_eq!(failure.response_code, ResponseCode::Refused);
+
+        let timeout = ResolveError::from(ProtoError::from(ProtoErrorKind::Timeout));
+        let failure = LookupFailure::from(&timeout);
+        assert_eq!(failure.response_code, ResponseCode::ServFail);
+        assert_eq!(
+            failure.extended_error.map(|ede| ede.code),
+            Some(ExtendedErrorCode::NoReachableAuthority)
+        );
+
+        let io = ProtoError::from(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        let failure = LookupFailure::from(&ResolveError::from(io));
+        assert_eq!(
+            failure.extended_error.map(|ede| ede.code),
+            Some(ExtendedErrorCode::NetworkError)
+        );
+
+        let failure = LookupFailure::from(&ResolveError::from("no strategies"));
+        assert_eq!(failure.response_code, ResponseCode::ServFail);
+    }
+}
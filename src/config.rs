@@ -0,0 +1,1051 @@
+//! Configuration of the daemon, read from a TOML file
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use hickory_proto::rr::Name;
+use hickory_resolver::config::{
+    NameServerConfigGroup, ResolveHosts, ResolverOpts, ServerOrderingStrategy,
+};
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::error::{ConfigError, ConfigErrorKind};
+use crate::resolve1::DEFAULT_BUS_NAME;
+use crate::routing::{RouteConfig, StrategyConfig, DEFAULT_STRATEGY};
+use crate::split_dns::{routing_domain, LinkConfig};
+use crate::store::forwarder::{ForwardConfig, NameServerPreset};
+
+/// Location of the config file when none is given explicitly
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/mushroom-dnresolver/config.toml";
+
+/// Environment variable that overrides [`DEFAULT_CONFIG_PATH`]
+pub const CONFIG_PATH_ENV: &str = "MUSHROOM_DNRESOLVER_CONFIG";
+
+/// Path of DNS-over-HTTPS queries suggested by RFC 8484
+pub const DEFAULT_DOH_PATH: &str = "/dns-query";
+
+/// Path of the JSON API of the DNS-over-HTTPS listeners
+pub const DOH_JSON_PATH: &str = "/resolve";
+
+/// Shortest token a DNS-over-HTTPS client may authenticate with
+pub const MIN_DOH_TOKEN_LEN: usize = 16;
+
+/// EDNS UDP payload size recommended by DNS flag day 2020, small enough to avoid IP fragmentation
+pub const DEFAULT_EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// Name of the default strategy forwarding to the network's nameservers, the default route uses it
+const NETWORK_STRATEGY: &str = "network";
+
+/// Top level configuration of the resolver daemon
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Number of worker threads of the tokio runtime. Defaults to 8.
+    pub worker_threads: usize,
+
+    /// Addresses to serve plain DNS on, over both UDP and TCP. Defaults to `127.0.0.1:53` and
+    /// `[::1]:53`.
+    pub listen: Vec<SocketAddr>,
+
+    /// Seconds a TCP connection may sit idle before it is closed. Defaults to 10.
+    pub tcp_idle_timeout: u64,
+
+    /// Networks that are denied access to the server, see [`crate::access::AccessControl`]
+    pub deny_networks: Vec<IpNet>,
+
+    /// Networks that are allowed access to the server, see [`crate::access::AccessControl`]
+    pub allow_networks: Vec<IpNet>,
+
+    /// EDNS UDP payload size advertised to clients, responses to EDNS clients are kept within the
+    /// smaller of it and the client's size. Defaults to 1232, see <https://www.dnsflagday.net/2020/>.
+    pub edns_udp_payload_size: u16,
+
+    /// Certificate and key of the encrypted listeners, required by `dot`, `doh`, `doq` and `doh3`
+    pub tls: Option<TlsConfig>,
+
+    /// DNS-over-TLS listener, off unless the section is present
+    pub dot: Option<DotConfig>,
+
+    /// DNS-over-HTTPS listener, off unless the section is present
+    pub doh: Option<DohConfig>,
+
+    /// DNS-over-QUIC listener, off unless the section is present
+    pub doq: Option<DoqConfig>,
+
+    /// DNS-over-HTTP/3 listener, off unless the section is present
+    pub doh3: Option<Doh3Config>,
+
+    /// User to run as once the listeners are bound, and the sandboxes to apply. Everything is kept
+    /// unless the section is present.
+    pub privileges: Option<PrivilegesConfig>,
+
+    /// The `org.freedesktop.resolve1` D-Bus API on the system bus, off unless the section is there
+    pub dbus: Option<DbusConfig>,
+
+    /// Upstream nameservers that queries are forwarded to, and the options of their resolver.
+    /// Defaults to the TLS, HTTPS and H3 endpoints of Cloudflare, Quad9 and Google.
+    pub upstream: ForwardConfig,
+
+    /// Named strategies that routes can send queries to. Defaults to a `network` strategy
+    /// forwarding to the nameservers handed out by DHCP.
+    pub strategies: BTreeMap<String, StrategyConfig>,
+
+    /// Routes sending queries below a domain suffix through a chain of the `strategies`, queries
+    /// matching no route go to `upstream`. Defaults to routing `nordvpn.com.` to `network`, as long
+    /// as there is a strategy of that name.
+    pub routes: Vec<RouteConfig>,
+
+    /// Nameservers and routing domains of interfaces, replacing what NetworkManager reports for
+    /// them. Queries for names below a routing domain that match no route go to the nameservers
+    /// of its interface instead of `upstream`.
+    pub links: Vec<LinkConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            worker_threads: 8,
+            listen: vec![
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53),
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 53),
+            ],
+            tcp_idle_timeout: 10,
+            deny_networks: vec![],
+            allow_networks: vec![],
+            edns_udp_payload_size: DEFAULT_EDNS_UDP_PAYLOAD_SIZE,
+            tls: None,
+            dot: None,
+            doh: None,
+            doq: None,
+            doh3: None,
+            privileges: None,
+            dbus: None,
+            upstream: default_upstream(),
+            strategies: BTreeMap::from([(
+                NETWORK_STRATEGY.to_string(),
+                StrategyConfig::Network {
+                    fallback: vec![NameServerPreset::Google],
+                    interface: None,
+                    connection: None,
+                    vpn: false,
+                },
+            )]),
+            routes: default_routes(),
+            links: vec![],
+        }
+    }
+}
+
+impl Config {
+    /// The resolver options of the upstream, or [`default_resolver_opts`] if there are none
+    pub fn upstream_options(&self) -> ResolverOpts {
+        self.upstream
+            .options
+            .clone()
+            .unwrap_or_else(default_resolver_opts)
+    }
+
+    /// How long a TCP connection may sit idle before it is closed
+    pub fn tcp_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.tcp_idle_timeout)
+    }
+
+    /// Checks for settings that parse fine, but leave the daemon unable to do anything useful
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.worker_threads == 0 {
+            return Err(
+                ConfigErrorKind::Invalid("worker_threads must be at least 1".into()).into(),
+            );
+        }
+        if self.listen.is_empty() {
+            return Err(ConfigErrorKind::Invalid("no listen addresses".into()).into());
+        }
+        if self.tcp_idle_timeout == 0 {
+            return Err(
+                ConfigErrorKind::Invalid("tcp_idle_timeout must be at least 1".into()).into(),
+            );
+        }
+        if self.edns_udp_payload_size < 512 {
+            return Err(ConfigErrorKind::Invalid(
+                "edns_udp_payload_size must be at least 512".into(),
+            )
+            .into());
+        }
+        if let Some(dot) = &self.dot {
+            if cfg!(not(feature = "dns-over-rustls")) {
+                return Err(ConfigErrorKind::Invalid(
+                    "dot needs the dns-over-rustls feature, which this build lacks".into(),
+                )
+                .into());
+            }
+            if self.tls.is_none() {
+                return Err(
+                    ConfigErrorKind::Invalid("dot needs a [tls] certificate".into()).into(),
+                );
+            }
+            if dot.listen.is_empty() {
+                return Err(ConfigErrorKind::Invalid("no dot listen addresses".into()).into());
+            }
+            if dot.idle_timeout == 0 {
+                return Err(
+                    ConfigErrorKind::Invalid("dot idle_timeout must be at least 1".into()).into(),
+                );
+            }
+        }
+        if let Some(doh) = &self.doh {
+            if cfg!(not(feature = "dns-over-https-rustls")) {
+                return Err(ConfigErrorKind::Invalid(
+                    "doh needs the dns-over-https-rustls feature, which this build lacks".into(),
+                )
+                .into());
+            }
+            if self.tls.is_none() {
+                return Err(
+                    ConfigErrorKind::Invalid("doh needs a [tls] certificate".into()).into(),
+                );
+            }
+            if doh.listen.is_empty() {
+                return Err(ConfigErrorKind::Invalid("no doh listen addresses".into()).into());
+            }
+            if !doh.path.starts_with('/') {
+                return Err(ConfigErrorKind::Invalid(format!(
+                    "doh path {} must start with /",
+                    doh.path
+                ))
+                .into());
+            }
+            if doh.path == DOH_JSON_PATH {
+                return Err(ConfigErrorKind::Invalid(format!(
+                    "doh path {DOH_JSON_PATH} is taken by the JSON API"
+                ))
+                .into());
+            }
+            if doh.handshake_timeout == 0 {
+                return Err(ConfigErrorKind::Invalid(
+                    "doh handshake_timeout must be at least 1".into(),
+                )
+                .into());
+            }
+            if doh.max_concurrent_streams == 0 {
+                return Err(ConfigErrorKind::Invalid(
+                    "doh max_concurrent_streams must be at least 1".into(),
+                )
+                .into());
+            }
+            if doh.idle_timeout == 0 {
+                return Err(
+                    ConfigErrorKind::Invalid("doh idle_timeout must be at least 1".into()).into(),
+                );
+            }
+            validate_doh_clients("doh", &doh.clients)?;
+        }
+        let client_auth = self
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.client_ca_path.is_some());
+        if client_auth && (self.doq.is_some() || self.doh3.is_some()) {
+            return Err(ConfigErrorKind::Invalid(
+                "client certificates are only checked by dot and doh, not doq or doh3".into(),
+            )
+            .into());
+        }
+        if let Some(doq) = &self.doq {
+            if cfg!(not(feature = "dns-over-quic")) {
+                return Err(ConfigErrorKind::Invalid(
+                    "doq needs the dns-over-quic feature, which this build lacks".into(),
+                )
+                .into());
+            }
+            if self.tls.is_none() {
+                return Err(
+                    ConfigErrorKind::Invalid("doq needs a [tls] certificate".into()).into(),
+                );
+            }
+            if doq.listen.is_empty() {
+                return Err(ConfigErrorKind::Invalid("no doq listen addresses".into()).into());
+            }
+        }
+        if let Some(doh3) = &self.doh3 {
+            if cfg!(not(feature = "dns-over-h3")) {
+                return Err(ConfigErrorKind::Invalid(
+                    "doh3 needs the dns-over-h3 feature, which this build lacks".into(),
+                )
+                .into());
+            }
+            if self.tls.is_none() {
+                return Err(
+                    ConfigErrorKind::Invalid("doh3 needs a [tls] certificate".into()).into(),
+                );
+            }
+            if doh3.listen.is_empty() {
+                return Err(ConfigErrorKind::Invalid("no doh3 listen addresses".into()).into());
+            }
+            if !doh3.path.starts_with('/') {
+                return Err(ConfigErrorKind::Invalid(format!(
+                    "doh3 path {} must start with /",
+                    doh3.path
+                ))
+                .into());
+            }
+            if doh3.path == DOH_JSON_PATH {
+                return Err(ConfigErrorKind::Invalid(format!(
+                    "doh3 path {DOH_JSON_PATH} is taken by the JSON API"
+                ))
+                .into());
+            }
+            validate_doh_clients("doh3", &doh3.clients)?;
+        }
+        if let Some(privileges) = &self.privileges {
+            if privileges.group.is_some() && privileges.user.is_none() {
+                return Err(
+                    ConfigErrorKind::Invalid("privileges group needs a user".into()).into(),
+                );
+            }
+        }
+        for (i, link) in self.links.iter().enumerate() {
+            if link.interface.is_empty() {
+                return Err(ConfigErrorKind::Invalid("link without an interface".into()).into());
+            }
+            if self.links[..i].iter().any(|other| other.interface == link.interface) {
+                return Err(ConfigErrorKind::Invalid(format!(
+                    "link {} is configured twice",
+                    link.interface
+                ))
+                .into());
+            }
+            for domain in &link.domains {
+                if let Err(err) = routing_domain(domain) {
+                    return Err(ConfigErrorKind::Invalid(format!(
+                        "invalid domain {domain} of link {}: {err}",
+                        link.interface
+                    ))
+                    .into());
+                }
+            }
+        }
+        if let Some(dbus) = &self.dbus {
+            if let Err(err) = dbus::strings::BusName::new(dbus.name.as_str()) {
+                return Err(
+                    ConfigErrorKind::Invalid(format!("invalid dbus name: {err}")).into(),
+                );
+            }
+        }
+        if self.upstream.all_name_servers().is_empty() {
+            return Err(ConfigErrorKind::Invalid("no upstream name_servers".into()).into());
+        }
+        if self.strategies.contains_key(DEFAULT_STRATEGY) {
+            return Err(ConfigErrorKind::Invalid(format!(
+                "strategy name {DEFAULT_STRATEGY} is reserved for upstream"
+            ))
+            .into());
+        }
+        for route in &self.routes {
+            if route.strategies.is_empty() {
+                return Err(ConfigErrorKind::Invalid(format!(
+                    "route for {} has no strategies",
+                    route.suffix
+                ))
+                .into());
+            }
+            let unknown = route.strategies.iter().find(|strategy| {
+                *strategy != DEFAULT_STRATEGY && !self.strategies.contains_key(*strategy)
+            });
+            if let Some(strategy) = unknown {
+                return Err(ConfigErrorKind::Invalid(format!(
+                    "route for {} uses unknown strategy {strategy}",
+                    route.suffix
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a [`Config`] from the given TOML file
+    pub fn read_config(path: &Path) -> Result<Self, ConfigError> {
+        let mut file = File::open(path)?;
+        let mut toml = String::new();
+        file.read_to_string(&mut toml)?;
+        toml.parse()
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(toml: &str) -> Result<Self, Self::Err> {
+        let mut config: Self = toml::de::from_str(toml)?;
+        // strategies of the file replace the default ones, the default route needs `network`
+        if !config.strategies.contains_key(NETWORK_STRATEGY) && config.routes == default_routes() {
+            config.routes.clear();
+        }
+        Ok(config)
+    }
+}
+
+/// Certificate presented by the encrypted listeners
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, the server's own certificate first
+    pub cert_chain_path: PathBuf,
+    /// PEM file with the private key of the certificate, PKCS#8, PKCS#1 or SEC1
+    pub private_key_path: PathBuf,
+    /// PEM file with the CAs that sign client certificates. When set, DoT and DoH clients must
+    /// present a certificate signed by one of them, its subject is passed on with their requests.
+    pub client_ca_path: Option<PathBuf>,
+}
+
+/// DNS-over-TLS listener, [RFC 7858](https://www.rfc-editor.org/rfc/rfc7858)
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DotConfig {
+    /// Addresses to serve DNS-over-TLS on. Defaults to port 853 on all IPv4 and IPv6 addresses.
+    pub listen: Vec<SocketAddr>,
+    /// Seconds a connection may sit idle before it is closed. Defaults to 10.
+    pub idle_timeout: u64,
+}
+
+impl Default for DotConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 853),
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 853),
+            ],
+            idle_timeout: 10,
+        }
+    }
+}
+
+impl DotConfig {
+    /// How long a connection may sit idle before it is closed
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+}
+
+/// DNS-over-HTTPS listener, [RFC 8484](https://www.rfc-editor.org/rfc/rfc8484)
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DohConfig {
+    /// Addresses to serve DNS-over-HTTPS on. Defaults to port 443 on all IPv4 and IPv6 addresses.
+    pub listen: Vec<SocketAddr>,
+    /// Path that GET and POST queries are sent to. Defaults to `/dns-query`.
+    pub path: String,
+    /// Host name clients must address, requests for other hosts are refused. Any by default.
+    pub hostname: Option<String>,
+    /// Seconds a client may take for the TLS handshake. Defaults to 10.
+    pub handshake_timeout: u64,
+    /// Most requests a client may have open at once on one connection. Defaults to 100.
+    pub max_concurrent_streams: u32,
+    /// Seconds a connection without open requests is kept before it is closed. Defaults to 30.
+    pub idle_timeout: u64,
+    /// Tokens of the clients allowed to query, by client name. When any are set, requests must
+    /// carry one as `Authorization: Bearer <token>` or at the end of the path, like
+    /// `/dns-query/<token>`. Anyone may query by default.
+    pub clients: BTreeMap<String, String>,
+}
+
+impl Default for DohConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 443),
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 443),
+            ],
+            path: DEFAULT_DOH_PATH.to_string(),
+            hostname: None,
+            handshake_timeout: 10,
+            max_concurrent_streams: 100,
+            idle_timeout: 30,
+            clients: BTreeMap::new(),
+        }
+    }
+}
+
+impl DohConfig {
+    /// How long a client may take for the TLS handshake
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout)
+    }
+
+    /// How long a connection without open requests is kept
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+}
+
+/// DNS-over-QUIC listener, [RFC 9250](https://www.rfc-editor.org/rfc/rfc9250)
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DoqConfig {
+    /// UDP addresses to serve DNS-over-QUIC on. Defaults to port 853 on all IPv4 and IPv6
+    /// addresses.
+    pub listen: Vec<SocketAddr>,
+}
+
+impl Default for DoqConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 853),
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 853),
+            ],
+        }
+    }
+}
+
+/// DNS-over-HTTPS over HTTP/3, RFC 8484 queries carried by QUIC
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Doh3Config {
+    /// UDP addresses to serve DNS-over-HTTP/3 on. Defaults to port 443 on all IPv4 and IPv6
+    /// addresses.
+    pub listen: Vec<SocketAddr>,
+    /// Path that GET and POST queries are sent to. Defaults to `/dns-query`.
+    pub path: String,
+    /// Host name clients must address, requests for other hosts are refused. Any by default.
+    pub hostname: Option<String>,
+    /// Tokens of the clients allowed to query, by client name, see [`DohConfig::clients`]
+    pub clients: BTreeMap<String, String>,
+}
+
+impl Default for Doh3Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 443),
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 443),
+            ],
+            path: DEFAULT_DOH_PATH.to_string(),
+            hostname: None,
+            clients: BTreeMap::new(),
+        }
+    }
+}
+
+/// Privileges given up after binding the listeners. The user must still be able to read the
+/// certificate for it to be reloaded, and the zone files.
+#[derive(Clone, Default, Deserialize, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivilegesConfig {
+    /// User to switch to, dropping root and its capabilities
+    pub user: Option<String>,
+    /// Group to switch to. Defaults to the primary group of `user`.
+    pub group: Option<String>,
+    /// Make the file system read-only with Landlock, Linux 5.13 or newer
+    pub landlock: bool,
+    /// Deny syscalls a resolver never makes, like execve, ptrace and mount, with seccomp
+    pub seccomp: bool,
+}
+
+/// The D-Bus API systemd-resolved offers, for resolvectl, NetworkManager and the like
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbusConfig {
+    /// Bus name to claim. Defaults to `org.freedesktop.resolve1`, the name clients of
+    /// systemd-resolved look for, so it can't run next to us.
+    pub name: String,
+}
+
+impl Default for DbusConfig {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_BUS_NAME.to_string(),
+        }
+    }
+}
+
+/// Checks that the tokens of a DoH listener are long, distinct and can be put in a path
+fn validate_doh_clients(
+    listener: &str,
+    clients: &BTreeMap<String, String>,
+) -> Result<(), ConfigError> {
+    for (name, token) in clients {
+        if token.len() < MIN_DOH_TOKEN_LEN {
+            return Err(ConfigErrorKind::Invalid(format!(
+                "{listener} token of {name} must be at least {MIN_DOH_TOKEN_LEN} characters"
+            ))
+            .into());
+        }
+        // the unreserved characters of RFC 3986, which need no escaping in a path
+        if !token
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'.' | b'_' | b'~'))
+        {
+            return Err(ConfigErrorKind::Invalid(format!(
+                "{listener} token of {name} may only hold letters, digits, -, ., _ and ~"
+            ))
+            .into());
+        }
+        if let Some((other, _)) = clients
+            .iter()
+            .find(|(other, other_token)| *other != name && *other_token == token)
+        {
+            return Err(ConfigErrorKind::Invalid(format!(
+                "{listener} clients {name} and {other} share a token"
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+fn default_routes() -> Vec<RouteConfig> {
+    vec![RouteConfig {
+        suffix: Name::from_ascii("nordvpn.com.").expect("valid domain name"),
+        query_types: vec![],
+        strategies: vec![NETWORK_STRATEGY.to_string()],
+        fall_through_rcodes: vec![],
+        nxdomain_ends_chain: true,
+    }]
+}
+
+fn default_upstream() -> ForwardConfig {
+    ForwardConfig {
+        presets: vec![
+            NameServerPreset::CloudflareTls,
+            NameServerPreset::CloudflareHttps,
+            NameServerPreset::Quad9Tls,
+            NameServerPreset::Quad9Https,
+            NameServerPreset::GoogleH3,
+        ],
+        name_servers: NameServerConfigGroup::new(),
+        options: None,
+    }
+}
+
+/// Resolver options used when the upstream doesn't specify its own
+pub fn default_resolver_opts() -> ResolverOpts {
+    let mut opts = ResolverOpts::default();
+    opts.cache_size = 256;
+    opts.timeout = Duration::from_secs(5);
+    opts.try_tcp_on_error = false;
+    opts.attempts = 1;
+    opts.server_ordering_strategy = ServerOrderingStrategy::QueryStatistics;
+    opts.num_concurrent_reqs = 2;
+    opts.use_hosts_file = ResolveHosts::Always;
+    opts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::RecordType;
+    use hickory_proto::xfer::Protocol;
+
+    #[test]
+    fn test_empty_config() {
+        let config: Config = "".parse().unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.upstream_options(), default_resolver_opts());
+        assert!(!config.upstream.all_name_servers().is_empty());
+    }
+
+    #[test]
+    fn test_example_config() {
+        let config: Config = include_str!("../config.example.toml").parse().unwrap();
+        let default = Config::default();
+        assert_eq!(config.upstream_options(), default.upstream_options());
+        assert_eq!(config.upstream.presets, default.upstream.presets);
+        assert_eq!(config.listen, default.listen);
+        assert_eq!(config.tcp_idle_timeout, default.tcp_idle_timeout);
+        assert_eq!(config.edns_udp_payload_size, default.edns_udp_payload_size);
+        assert_eq!(config.strategies, default.strategies);
+        assert_eq!(config.routes, default.routes);
+    }
+
+    #[test]
+    fn test_full_config() {
+        let config: Config = r#"
+            worker_threads = 2
+            listen = ["127.0.0.1:5353"]
+            tcp_idle_timeout = 30
+            deny_networks = ["0.0.0.0/0"]
+            allow_networks = ["192.168.1.0/24"]
+            edns_udp_payload_size = 4096
+
+            [upstream]
+            presets = ["quad9"]
+
+            [[upstream.name_servers]]
+            socket_addr = "1.1.1.1:853"
+            protocol = "tls"
+            tls_dns_name = "cloudflare-dns.com"
+
+            [upstream.options]
+            cache_size = 16
+            timeout = { secs = 2, nanos = 0 }
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.worker_threads, 2);
+        assert_eq!(config.listen, vec!["127.0.0.1:5353".parse().unwrap()]);
+        assert_eq!(config.tcp_idle_timeout(), Duration::from_secs(30));
+        assert_eq!(config.deny_networks, vec!["0.0.0.0/0".parse().unwrap()]);
+        assert_eq!(
+            config.allow_networks,
+            vec!["192.168.1.0/24".parse().unwrap()]
+        );
+        assert_eq!(config.edns_udp_payload_size, 4096);
+
+        let name_servers = config.upstream.all_name_servers();
+        assert_eq!(name_servers.len(), NameServerConfigGroup::quad9().len() + 1);
+        let last = name_servers.last().unwrap();
+        assert_eq!(last.protocol, Protocol::Tls);
+        assert_eq!(last.tls_dns_name.as_deref(), Some("cloudflare-dns.com"));
+
+        let options = config.upstream_options();
+        assert_eq!(options.cache_size, 16);
+        assert_eq!(options.timeout, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+        assert!("listen = []".parse::<Config>().unwrap().validate().is_err());
+        assert!("worker_threads = 0"
+            .parse::<Config>()
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!("[upstream]".parse::<Config>().unwrap().validate().is_err());
+        assert!("tcp_idle_timeout = 0"
+            .parse::<Config>()
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!("edns_udp_payload_size = 511"
+            .parse::<Config>()
+            .unwrap()
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_routes() {
+        let config: Config = r#"
+            [strategies.vpn]
+            kind = "upstream"
+            presets = ["quad9"]
+
+            [strategies.lan]
+            kind = "network"
+
+            [strategies.home]
+            kind = "zone"
+            origin = "home.arpa."
+            zone_file_path = "/etc/mushroom-dnresolver/home.arpa.zone"
+
+            [[routes]]
+            suffix = "corp.example."
+            strategies = ["vpn", "lan", "default"]
+            fall_through_rcodes = ["REFUSED"]
+
+            [[routes]]
+            suffix = "home.arpa."
+            query_types = ["A", "AAAA"]
+            strategies = ["home"]
+        "#
+        .parse()
+        .unwrap();
+
+        assert!(config.validate().is_ok());
+        assert_eq!(config.strategies.len(), 3);
+        assert_eq!(
+            config.strategies["lan"],
+            StrategyConfig::Network {
+                fallback: vec![NameServerPreset::Google],
+                interface: None,
+                connection: None,
+                vpn: false,
+            }
+        );
+        let wg: Config = "[strategies.wg]\nkind = \"network\"\ninterface = \"wg0\"\nvpn = true"
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            &wg.strategies["wg"],
+            StrategyConfig::Network {
+                interface: Some(interface),
+                connection: None,
+                vpn: true,
+                ..
+            } if interface == "wg0"
+        ));
+        assert!(matches!(
+            &config.strategies["vpn"],
+            StrategyConfig::Upstream(forward) if forward.presets == vec![NameServerPreset::Quad9]
+        ));
+        assert_eq!(config.routes.len(), 2);
+        assert_eq!(
+            config.routes[1].query_types,
+            vec![RecordType::A, RecordType::AAAA]
+        );
+
+        assert!(
+            "[[routes]]\nsuffix = \"example.com.\"\nstrategies = [\"network\", \"missing\"]"
+                .parse::<Config>()
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!("[[routes]]\nsuffix = \"example.com.\"\nstrategies = []"
+            .parse::<Config>()
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!("[strategies.default]\nkind = \"network\""
+            .parse::<Config>()
+            .unwrap()
+            .validate()
+            .is_err());
+        assert!("[strategies.lan]\nkind = \"dhcp\""
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_custom_strategies() {
+        let config: Config = "[strategies.vpn]\nkind = \"network\"\nvpn = true"
+            .parse()
+            .unwrap();
+        assert!(config.validate().is_ok());
+        assert!(!config.strategies.contains_key("network"));
+        assert!(config.routes.is_empty());
+
+        let config: Config = "[strategies.network]\nkind = \"network\"\ninterface = \"eth0\""
+            .parse()
+            .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.routes, Config::default().routes);
+
+        let config: Config = r#"
+            [strategies.vpn]
+            kind = "network"
+
+            [[routes]]
+            suffix = "corp.example."
+            strategies = ["vpn"]
+        "#
+        .parse()
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.routes.len(), 1);
+    }
+
+    #[test]
+    fn test_dot() {
+        let config: Config = r#"
+            [tls]
+            cert_chain_path = "/etc/mushroom-dnresolver/fullchain.pem"
+            private_key_path = "/etc/mushroom-dnresolver/key.pem"
+
+            [dot]
+        "#
+        .parse()
+        .unwrap();
+
+        assert!(config.validate().is_ok());
+        assert_eq!(config.dot, Some(DotConfig::default()));
+        assert_eq!(
+            config.tls.unwrap().private_key_path,
+            PathBuf::from("/etc/mushroom-dnresolver/key.pem")
+        );
+
+        let config: Config = "[dot]\nlisten = [\"192.168.1.2:853\"]\nidle_timeout = 30"
+            .parse()
+            .unwrap();
+        let dot = config.dot.as_ref().unwrap();
+        assert_eq!(dot.listen, vec!["192.168.1.2:853".parse().unwrap()]);
+        assert_eq!(dot.idle_timeout(), Duration::from_secs(30));
+        // no certificate
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_doh() {
+        let config: Config = r#"
+            [tls]
+            cert_chain_path = "/etc/mushroom-dnresolver/fullchain.pem"
+            private_key_path = "/etc/mushroom-dnresolver/key.pem"
+
+            [doh]
+        "#
+        .parse()
+        .unwrap();
+
+        assert!(config.validate().is_ok());
+        let doh = config.doh.unwrap();
+        assert_eq!(doh, DohConfig::default());
+        assert_eq!(doh.path, "/dns-query");
+
+        let config: Config = r#"
+            [tls]
+            cert_chain_path = "/etc/mushroom-dnresolver/fullchain.pem"
+            private_key_path = "/etc/mushroom-dnresolver/key.pem"
+
+            [doh]
+            listen = ["192.168.1.2:8443"]
+            path = "resolve"
+            hostname = "dns.home.arpa"
+            max_concurrent_streams = 10
+            idle_timeout = 120
+        "#
+        .parse()
+        .unwrap();
+        let doh = config.doh.as_ref().unwrap();
+        assert_eq!(doh.listen, vec!["192.168.1.2:8443".parse().unwrap()]);
+        assert_eq!(doh.max_concurrent_streams, 10);
+        assert_eq!(doh.idle_timeout(), Duration::from_secs(120));
+        assert_eq!(doh.hostname.as_deref(), Some("dns.home.arpa"));
+        // relative path
+        assert!(config.validate().is_err());
+        let mut config = config;
+        config.doh.as_mut().unwrap().path = "/resolve".into();
+        assert!(config.validate().is_err());
+
+        // no certificate
+        let config: Config = "[doh]".parse().unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_doh_clients() {
+        let mut config: Config = r#"
+            [tls]
+            cert_chain_path = "/etc/mushroom-dnresolver/fullchain.pem"
+            private_key_path = "/etc/mushroom-dnresolver/key.pem"
+
+            [doh.clients]
+            alice = "0123456789abcdef-alice"
+            bob = "0123456789abcdef-bob"
+        "#
+        .parse()
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let clients = &config.doh.as_ref().unwrap().clients;
+        assert_eq!(clients["alice"], "0123456789abcdef-alice");
+
+        let clients = &mut config.doh.as_mut().unwrap().clients;
+        clients.insert("carol".into(), "0123456789abcdef-bob".into());
+        assert!(config.validate().is_err());
+
+        let clients = &mut config.doh.as_mut().unwrap().clients;
+        clients.insert("carol".into(), "short".into());
+        assert!(config.validate().is_err());
+
+        let clients = &mut config.doh.as_mut().unwrap().clients;
+        clients.insert("carol".into(), "0123456789abcdef/carol".into());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_privileges() {
+        let mut config: Config = r#"
+            [privileges]
+            user = "mushroom"
+            seccomp = true
+        "#
+        .parse()
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let privileges = config.privileges.as_mut().unwrap();
+        assert_eq!(privileges.user.as_deref(), Some("mushroom"));
+        assert!(privileges.seccomp && !privileges.landlock);
+
+        privileges.user = None;
+        privileges.group = Some("mushroom".into());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_links() {
+        let mut config: Config = r#"
+            [[links]]
+            interface = "wg0"
+            name_servers = ["10.8.0.1"]
+            domains = ["~corp.example", "vpn.example"]
+
+            [[links]]
+            interface = "wlan0"
+            domains = ["~fritz.box"]
+        "#
+        .parse()
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.links[1].interface, "wlan0");
+        assert!(config.links[1].name_servers.is_empty());
+
+        config.links[1].domains.push("bad..domain".into());
+        assert!(config.validate().is_err());
+
+        config.links[1].interface = "wg0".into();
+        config.links[1].domains.pop();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_client_ca() {
+        let mut config: Config = r#"
+            [tls]
+            cert_chain_path = "/etc/mushroom-dnresolver/fullchain.pem"
+            private_key_path = "/etc/mushroom-dnresolver/key.pem"
+            client_ca_path = "/etc/mushroom-dnresolver/clients.pem"
+
+            [dot]
+            [doh]
+        "#
+        .parse()
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.tls.as_ref().unwrap().client_ca_path,
+            Some(PathBuf::from("/etc/mushroom-dnresolver/clients.pem"))
+        );
+
+        config.doq = Some(DoqConfig::default());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_quic() {
+        let config: Config = r#"
+            [tls]
+            cert_chain_path = "/etc/mushroom-dnresolver/fullchain.pem"
+            private_key_path = "/etc/mushroom-dnresolver/key.pem"
+
+            [doq]
+            [doh3]
+            listen = ["192.168.1.2:443"]
+        "#
+        .parse()
+        .unwrap();
+
+        assert!(config.validate().is_ok());
+        assert_eq!(config.doq, Some(DoqConfig::default()));
+        let doh3 = config.doh3.unwrap();
+        assert_eq!(doh3.listen, vec!["192.168.1.2:443".parse().unwrap()]);
+        assert_eq!(doh3.path, "/dns-query");
+
+        // no certificate
+        assert!("[doq]".parse::<Config>().unwrap().validate().is_err());
+        assert!("[doh3]".parse::<Config>().unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!("listen_on = []".parse::<Config>().is_err());
+        assert!("[upstream]\nservers = []".parse::<Config>().is_err());
+    }
+}
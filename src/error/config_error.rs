@@ -22,10 +22,13 @@ pub enum ErrorKind {
     Io(#[from] io::Error),
 
     /// An error occurred while decoding toml data
-    #[cfg(feature = "toml")]
     #[error("toml decode error: {0}")]
     TomlDecode(#[from] toml::de::Error),
 
+    /// The configuration was read, but its contents are unusable
+    #[error("invalid config: {0}")]
+    Invalid(String),
+
     /// An error occurred while parsing a zone file
     #[error("failed to parse the zone file: {0}")]
     ZoneParse(#[from] hickory_proto::serialize::txt::ParseError),
@@ -0,0 +1,345 @@
+//! Whether IPv6 upstreams can be reached
+//!
+//! That takes IPv6 being enabled, a global IPv6 address and a default IPv6 route. The kernel
+//! reports addresses and routes over rtnetlink, and tells us when they change, so the upstream set
+//! follows the network as it comes and goes.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use sysctl::{CtlValue, Sysctl};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// How often connectivity is checked without the kernel reporting a change, catching the sysctl
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait for a burst of address and route changes to settle before checking
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// Size of `struct nlmsghdr`
+const HEADER_LEN: usize = 16;
+
+/// The current IPv6 connectivity, with a channel announcing its changes
+pub struct Ipv6Connectivity {
+    state: watch::Sender<bool>,
+}
+
+impl Ipv6Connectivity {
+    /// Checks the connectivity once, call [`Self::watch`] to keep it up to date
+    pub fn new() -> Arc<Self> {
+        let enabled = check();
+        info!("IPv6 upstreams are {}", if enabled { "used" } else { "not used" });
+        Arc::new(Self {
+            state: watch::Sender::new(enabled),
+        })
+    }
+
+    /// Whether IPv6 upstreams can be reached
+    pub fn is_enabled(&self) -> bool {
+        *self.state.borrow()
+    }
+
+    /// A receiver that is notified whenever the connectivity changes
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.state.subscribe()
+    }
+
+    fn update(&self, enabled: bool) {
+        let changed = self.state.send_if_modified(|state| {
+            let changed = *state != enabled;
+            *state = enabled;
+            changed
+        });
+        if changed {
+            info!(
+                "IPv6 connectivity {}, switching to {} upstreams",
+                if enabled { "appeared" } else { "went away" },
+                if enabled { "dual-stack" } else { "IPv4-only" }
+            );
+        }
+    }
+
+    /// Rechecks the connectivity in the background whenever the kernel reports a change of IPv6
+    /// addresses or routes, and every [`CHECK_INTERVAL`]
+    pub fn watch(self: &Arc<Self>) {
+        let connectivity = self.clone();
+        tokio::spawn(async move {
+            let mut events = match subscribe().and_then(AsyncFd::new) {
+                Ok(events) => Some(events),
+                Err(err) => {
+                    warn!("Can't follow IPv6 changes, checking every {CHECK_INTERVAL:?}: {err}");
+                    None
+                }
+            };
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            interval.tick().await;
+            loop {
+                match &mut events {
+                    Some(socket) => tokio::select! {
+                        readable = socket.readable() => {
+                            if let Ok(mut guard) = readable {
+                                guard.clear_ready();
+                            }
+                            tokio::time::sleep(SETTLE_DELAY).await;
+                            drain(socket.get_ref());
+                        }
+                        _ = interval.tick() => {}
+                    },
+                    None => {
+                        interval.tick().await;
+                    }
+                }
+
+                match tokio::task::spawn_blocking(check).await {
+                    Ok(enabled) => connectivity.update(enabled),
+                    Err(err) => warn!("Checking IPv6 connectivity failed: {err}"),
+                }
+            }
+        });
+    }
+}
+
+/// Whether IPv6 is enabled, and there is a global address and a default route
+pub fn check() -> bool {
+    if !sysctl_enabled() {
+        return false;
+    }
+    let routable = has_global_address().and_then(|address| Ok(address && has_default_route()?));
+    routable.unwrap_or_else(|err| {
+        warn!("Can't ask the kernel about IPv6 addresses and routes: {err}");
+        false
+    })
+}
+
+/// Whether `net.ipv6.conf.all.disable_ipv6` is off
+fn sysctl_enabled() -> bool {
+    sysctl::Ctl::new("net.ipv6.conf.all.disable_ipv6")
+        .and_then(|ctl| ctl.value())
+        .is_ok_and(|value| value == CtlValue::String("0".to_string()))
+}
+
+fn has_global_address() -> io::Result<bool> {
+    // struct ifaddrmsg, with only the family set
+    let request = [libc::AF_INET6 as u8, 0, 0, 0, 0, 0, 0, 0];
+    let mut found = false;
+    dump(libc::RTM_GETADDR, &request, |message_type, payload| {
+        found |= message_type == libc::RTM_NEWADDR && is_global_address(payload);
+    })?;
+    Ok(found)
+}
+
+fn has_default_route() -> io::Result<bool> {
+    // struct rtmsg, with only the family set
+    let request = [libc::AF_INET6 as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut found = false;
+    dump(libc::RTM_GETROUTE, &request, |message_type, payload| {
+        found |= message_type == libc::RTM_NEWROUTE && is_default_route(payload);
+    })?;
+    Ok(found)
+}
+
+/// Whether the `struct ifaddrmsg` is of a global address that finished duplicate address detection
+fn is_global_address(payload: &[u8]) -> bool {
+    let [family, _prefix_len, flags, scope, ..] = *payload else {
+        return false;
+    };
+    let unusable = (libc::IFA_F_TENTATIVE | libc::IFA_F_DADFAILED) as u8;
+    i32::from(family) == libc::AF_INET6 && scope == libc::RT_SCOPE_UNIVERSE && flags & unusable == 0
+}
+
+/// Whether the `struct rtmsg` is of a default route that packets can leave by. Routes in every
+/// table count, VPNs often keep theirs out of the main one.
+fn is_default_route(payload: &[u8]) -> bool {
+    let [family, dst_len, _src_len, _tos, _table, _protocol, _scope, route_type, ..] = *payload
+    else {
+        return false;
+    };
+    i32::from(family) == libc::AF_INET6 && dst_len == 0 && route_type == libc::RTN_UNICAST
+}
+
+fn netlink_socket() -> io::Result<OwnedFd> {
+    // SAFETY: socket only takes integers
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the socket was just created and isn't owned by anything else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// A non-blocking socket receiving the kernel's messages about IPv6 addresses and routes
+fn subscribe() -> io::Result<OwnedFd> {
+    let socket = netlink_socket()?;
+    // SAFETY: sockaddr_nl is plain data, for which all zeroes is valid
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = (libc::RTMGRP_IPV6_IFADDR | libc::RTMGRP_IPV6_ROUTE) as u32;
+    // SAFETY: the address is valid and its size is passed along
+    let bound = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if bound != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fcntl on an open descriptor
+    if unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Throws away the pending messages of the non-blocking `socket`, only their arrival matters
+fn drain(socket: &OwnedFd) {
+    let mut buffer = [0u8; 8192];
+    // SAFETY: the buffer is valid for its length
+    while unsafe {
+        libc::recv(
+            socket.as_raw_fd(),
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+            0,
+        )
+    } > 0
+    {}
+}
+
+/// Asks the kernel for all objects of a kind, passing the type and payload of each message of the
+/// answer to `on_message`
+fn dump(
+    request_type: u16,
+    request: &[u8],
+    mut on_message: impl FnMut(u16, &[u8]),
+) -> io::Result<()> {
+    let socket = netlink_socket()?;
+
+    let len = HEADER_LEN + request.len();
+    let mut message = Vec::with_capacity(len);
+    message.extend_from_slice(&(len as u32).to_ne_bytes());
+    message.extend_from_slice(&request_type.to_ne_bytes());
+    message.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    message.extend_from_slice(&1u32.to_ne_bytes());
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    message.extend_from_slice(request);
+    // SAFETY: the message is valid for its length
+    if unsafe { libc::send(socket.as_raw_fd(), message.as_ptr().cast(), len, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buffer = vec![0u8; 32 * 1024];
+    loop {
+        // SAFETY: the buffer is valid for its length
+        let received = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+                0,
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (message_type, payload) in messages(&buffer[..received as usize]) {
+            match i32::from(message_type) {
+                libc::NLMSG_DONE => return Ok(()),
+                libc::NLMSG_ERROR => {
+                    let errno = payload
+                        .get(..4)
+                        .map_or(0, |errno| i32::from_ne_bytes(errno.try_into().unwrap()));
+                    if errno != 0 {
+                        return Err(io::Error::from_raw_os_error(-errno));
+                    }
+                }
+                _ => on_message(message_type, payload),
+            }
+        }
+    }
+}
+
+/// The type and payload of each netlink message in `buffer`
+fn messages(mut buffer: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut messages = Vec::new();
+    while buffer.len() >= HEADER_LEN {
+        let len = u32::from_ne_bytes(buffer[..4].try_into().unwrap()) as usize;
+        let message_type = u16::from_ne_bytes(buffer[4..6].try_into().unwrap());
+        if len < HEADER_LEN || len > buffer.len() {
+            break;
+        }
+        messages.push((message_type, &buffer[HEADER_LEN..len]));
+        // messages are aligned to 4 bytes
+        buffer = &buffer[len.next_multiple_of(4).min(buffer.len())..];
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(message_type: u16, payload: &[u8]) -> Vec<u8> {
+        let len = HEADER_LEN + payload.len();
+        let mut message = Vec::new();
+        message.extend_from_slice(&(len as u32).to_ne_bytes());
+        message.extend_from_slice(&message_type.to_ne_bytes());
+        message.extend_from_slice(&[0; 10]);
+        message.extend_from_slice(payload);
+        message.resize(len.next_multiple_of(4), 0);
+        message
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut buffer = message(libc::RTM_NEWADDR, &[1, 2, 3, 4, 5]);
+        buffer.extend(message(libc::NLMSG_DONE as u16, &[0; 4]));
+        let messages = messages(&buffer);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], (libc::RTM_NEWADDR, &[1, 2, 3, 4, 5][..]));
+        assert_eq!(messages[1].0, libc::NLMSG_DONE as u16);
+
+        // a truncated message is dropped
+        assert!(super::messages(&buffer[..20]).is_empty());
+    }
+
+    #[test]
+    fn test_global_address() {
+        let inet6 = libc::AF_INET6 as u8;
+        assert!(is_global_address(&[inet6, 64, 0, libc::RT_SCOPE_UNIVERSE, 2, 0, 0, 0]));
+        // link local
+        assert!(!is_global_address(&[inet6, 64, 0, libc::RT_SCOPE_LINK, 2, 0, 0, 0]));
+        // loopback
+        assert!(!is_global_address(&[inet6, 128, 0, libc::RT_SCOPE_HOST, 1, 0, 0, 0]));
+        let tentative = libc::IFA_F_TENTATIVE as u8;
+        assert!(!is_global_address(&[inet6, 64, tentative, 0, 2, 0, 0, 0]));
+        assert!(!is_global_address(&[libc::AF_INET as u8, 24, 0, 0, 2, 0, 0, 0]));
+        assert!(!is_global_address(&[inet6]));
+    }
+
+    #[test]
+    fn test_default_route() {
+        let inet6 = libc::AF_INET6 as u8;
+        let route = |dst_len, route_type| [inet6, dst_len, 0, 0, 254, 3, 0, route_type, 0, 0, 0, 0];
+        assert!(is_default_route(&route(0, libc::RTN_UNICAST)));
+        assert!(!is_default_route(&route(64, libc::RTN_UNICAST)));
+        assert!(!is_default_route(&route(0, libc::RTN_UNREACHABLE)));
+    }
+
+    #[test]
+    fn test_dump() {
+        assert!(has_global_address().is_ok());
+        assert!(has_default_route().is_ok());
+    }
+}
@@ -1,163 +1,381 @@
 use crate::authority::mushroom::Mushroom;
-use dbus::arg::{RefArg, Variant};
-use dbus::blocking::Connection;
-use hickory_resolver::config::{
-    NameServerConfig, NameServerConfigGroup, ResolverConfig, ResolverOpts,
-};
-use hickory_resolver::lookup::Lookup;
+use crate::authority::{Authority, LookupError, LookupOptions, ZoneType};
+use crate::config::default_resolver_opts;
+use crate::ipv6::Ipv6Connectivity;
+use crate::network::{Link, Links, NetworkLinks};
+use crate::routing::{StrategyConfig, DEFAULT_STRATEGY};
+use crate::split_dns::SplitDns;
+use crate::store::file::{FileAuthority, FileConfig};
+use crate::store::in_memory::InMemoryAuthority;
+use crate::upstream::Upstream;
+use hickory_proto::op::{Message, MessageType, Query, ResponseCode};
+use hickory_proto::rr::{LowerName, Name};
+use hickory_resolver::config::{NameServerConfig, NameServerConfigGroup, ResolverOpts};
 use hickory_resolver::proto::rr::RecordType;
 use hickory_resolver::proto::xfer::Protocol;
-use hickory_resolver::{ResolveError, TokioResolver};
-use networkmanager::devices::{Any, Device, Wired};
-use networkmanager::{Error, NetworkManager};
-use std::collections::HashMap;
-use std::fs::read_to_string;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::str::FromStr;
-use sysctl::{CtlValue, Sysctl};
-use tracing::{error, info, warn};
+use hickory_resolver::ResolveError;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tracing::{debug, info};
 
-pub(crate) async fn hickory_lookup(
-    mushroom: &Mushroom,
-    x0: &String,
-    record_type: RecordType,
-) -> (Result<Lookup, ResolveError>, bool) {
-    let mut resolver_opts = ResolverOpts::default();
-    resolver_opts.try_tcp_on_error = false;
+/// A configured strategy, ready to answer the queries routed to it
+pub enum Strategy {
+    /// Forwards to a fixed set of upstream nameservers
+    Upstream(Arc<Upstream>),
+    /// Forwards to the nameservers the network handed out
+    Network(Arc<NetworkResolver>),
+    /// Answers from a local zone
+    Zone(InMemoryAuthority),
+}
+
+impl Strategy {
+    /// Builds the strategy, loading zone files and setting up resolvers
+    pub fn from_config(
+        config: &StrategyConfig,
+        links: &Links,
+        ipv6_support: bool,
+    ) -> Result<Self, String> {
+        Ok(match config {
+            StrategyConfig::Upstream(forward) => {
+                let opts = forward.options.clone().unwrap_or_else(default_resolver_opts);
+                Self::Upstream(Arc::new(Upstream::new(&forward.all_name_servers(), opts)))
+            }
+            StrategyConfig::Network {
+                fallback,
+                interface,
+                connection,
+                vpn,
+            } => {
+                let mut name_servers = NameServerConfigGroup::new();
+                for preset in fallback {
+                    name_servers.merge(preset.name_servers());
+                }
+                let filter = LinkFilter {
+                    interface: interface.clone(),
+                    connection: connection.clone(),
+                    vpn: *vpn,
+                };
+                let network = NetworkResolver::new(filter, name_servers);
+                network.refresh(links, ipv6_support);
+                Self::Network(Arc::new(network))
+            }
+            StrategyConfig::Zone {
+                origin,
+                zone_file_path,
+            } => {
+                let zone = FileAuthority::try_from_config(
+                    origin.clone(),
+                    ZoneType::Primary,
+                    false,
+                    None,
+                    &FileConfig {
+                        zone_file_path: zone_file_path.clone(),
+                    },
+                )?;
+                Self::Zone(zone.unwrap())
+            }
+        })
+    }
 
-    let ipv6_support = is_ipv6_enabled();
+    /// The forwarder of the strategy, zones have none
+    pub fn upstream(&self) -> Option<Arc<Upstream>> {
+        match self {
+            Self::Upstream(upstream) => Some(upstream.clone()),
+            Self::Network(network) => Some(network.upstream()),
+            Self::Zone(_) => None,
+        }
+    }
 
-    let final_resolver = if x0.ends_with("nordvpn.com.") {
-        let mut resolver_config = ResolverConfig::new();
-        try_adding_ns_from_dhcp(&mut resolver_config, false);
+    async fn lookup(&self, query: Query, dnssec_ok: bool) -> Result<Message, ResolveError> {
+        match self {
+            Self::Upstream(upstream) => upstream.lookup(query, dnssec_ok).await,
+            Self::Network(network) => network.upstream().lookup(query, dnssec_ok).await,
+            Self::Zone(zone) => zone_lookup(zone, query, dnssec_ok).await,
+        }
+    }
+}
 
-        if resolver_config.name_servers().is_empty() {
-            for ns in NameServerConfigGroup::google().iter() {
-                if ns.socket_addr.is_ipv4() {
-                    resolver_config.add_name_server(ns.clone())
-                }
+/// Refreshes the network resolvers and the split DNS table in the background whenever the links or
+/// IPv6 connectivity change
+pub fn spawn_network_refresh(
+    networks: Vec<Arc<NetworkResolver>>,
+    split_dns: Arc<SplitDns>,
+    links: Arc<NetworkLinks>,
+    ipv6: Arc<Ipv6Connectivity>,
+) {
+    tokio::spawn(async move {
+        let mut link_changes = links.subscribe();
+        let mut ipv6_changes = ipv6.subscribe();
+        loop {
+            let changed = tokio::select! {
+                changed = link_changes.changed() => changed,
+                changed = ipv6_changes.changed() => changed,
+            };
+            if changed.is_err() {
+                return;
+            }
+            let links = link_changes.borrow_and_update().clone();
+            let ipv6_support = *ipv6_changes.borrow_and_update();
+            for network in &networks {
+                network.refresh(&links, ipv6_support);
             }
+            split_dns.refresh(&links, ipv6_support);
         }
+    });
+}
 
-        info!(
-            "Forwarding {} lookup via {:?}",
-            x0,
-            resolver_config.name_servers()
-        );
+/// Which links a network resolver forwards to, all of them when nothing is set
+#[derive(Debug, Default)]
+struct LinkFilter {
+    interface: Option<String>,
+    connection: Option<String>,
+    vpn: bool,
+}
 
+impl LinkFilter {
+    fn matches(&self, link: &Link) -> bool {
+        let interface = self.interface.as_ref();
+        let connection = self.connection.as_ref();
+        interface.is_none_or(|interface| *interface == link.interface)
+            && connection.is_none_or(|connection| *connection == link.connection)
+            && (!self.vpn || link.vpn)
+    }
+}
+
+/// Forwarder to the nameservers of the network, rebuilt when the network hands out different ones
+pub struct NetworkResolver {
+    filter: LinkFilter,
+    fallback: NameServerConfigGroup,
+    current: RwLock<Arc<Upstream>>,
+}
+
+impl NetworkResolver {
+    fn new(filter: LinkFilter, fallback: NameServerConfigGroup) -> Self {
+        let upstream = Self::build_upstream(&NameServerConfigGroup::new());
+        Self {
+            filter,
+            fallback,
+            current: RwLock::new(Arc::new(upstream)),
+        }
+    }
+
+    /// A forwarder to nameservers of the network, over plain DNS
+    pub(crate) fn build_upstream(name_servers: &NameServerConfigGroup) -> Upstream {
         let mut resolver_opts = ResolverOpts::default();
         resolver_opts.shuffle_dns_servers = true;
         resolver_opts.try_tcp_on_error = false;
-        &TokioResolver::tokio(resolver_config, resolver_opts)
-    } else {
-        if ipv6_support {
-            info!("Looking up {} via all nameservers", x0);
-            &mushroom.resolver
-        } else {
-            info!("Looking up {} via ipv4 nameservers", x0);
-            &mushroom.ipv4_resolver
-        }
-    };
-    (final_resolver.lookup(x0, record_type).await, ipv6_support)
-}
+        Upstream::new(name_servers, resolver_opts)
+    }
+
+    /// The forwarder to the nameservers the network handed out when last refreshed
+    fn upstream(&self) -> Arc<Upstream> {
+        self.current.read().expect("network resolver lock poisoned").clone()
+    }
 
-fn is_ipv6_enabled() -> bool {
-    return false; // fuck nordvpn,
-    let disabled: CtlValue = sysctl::Ctl::new("net.ipv6.conf.all.disable_ipv6")
-        .map(|v|
-            {
-                let err = v.value();
-                err.unwrap_or(CtlValue::String("1".to_string()))
+    /// Forwards to the nameservers of the matching links, rebuilding the forwarder if they changed
+    pub fn refresh(&self, links: &Links, ipv6_support: bool) {
+        let mut name_servers: Vec<NameServerConfig> = vec![];
+        let matching = links.iter().filter(|link| self.filter.matches(link));
+        for ip in matching.flat_map(|link| &link.name_servers) {
+            let name_server = NameServerConfig::new(SocketAddr::new(*ip, 53), Protocol::Udp);
+            if (ipv6_support || ip.is_ipv4()) && !name_servers.contains(&name_server) {
+                name_servers.push(name_server);
             }
-        )
-        .unwrap_or(CtlValue::String("1".to_string()));
-    disabled == CtlValue::String("0".to_string())
+        }
+        if name_servers.is_empty() {
+            name_servers.extend(
+                self.fallback
+                    .iter()
+                    .filter(|ns| ipv6_support || ns.socket_addr.is_ipv4())
+                    .cloned(),
+            );
+        }
 
+        let mut current = self.current.write().expect("network resolver lock poisoned");
+        if !current.name_servers().eq(name_servers.iter()) {
+            info!("Network nameservers are now {:?}", name_servers);
+            let upstream = Self::build_upstream(&NameServerConfigGroup::from(name_servers));
+            *current = Arc::new(upstream);
+        }
+    }
 }
 
-// #[test]
-// fn test_ipv6() {
-//     assert!(!is_ipv6_enabled());
-// }
+/// Answers from the zone like an authoritative nameserver would
+async fn zone_lookup(
+    zone: &InMemoryAuthority,
+    query: Query,
+    dnssec_ok: bool,
+) -> Result<Message, ResolveError> {
+    let name = LowerName::from(query.name());
+    let record_type = query.query_type();
 
-fn try_adding_ns_from_dhcp(resolver_config: &mut ResolverConfig, ipv6_support: bool) {
-    let dbus_connection = Connection::new_system();
+    let mut message = Message::new();
+    message
+        .set_message_type(MessageType::Response)
+        .set_authoritative(true)
+        .set_recursion_available(true)
+        .add_query(query);
 
-    if let Ok(dbus_connection) = dbus_connection {
-        let nm = NetworkManager::new(&dbus_connection);
+    let err = match zone
+        .lookup(
+            &name,
+            record_type,
+            LookupOptions::default().set_dnssec_ok(dnssec_ok),
+        )
+        .await
+        .map_result()
+    {
+        Some(Ok(lookup)) => {
+            message.add_answers(lookup.iter().cloned());
+            return Ok(message);
+        }
+        Some(Err(err)) => err,
+        None => return Err(ResolveError::from("zone skipped the lookup")),
+    };
 
-        for dev in nm.get_devices().unwrap_or_default() {
-            match dev {
-                Device::Ethernet(x) => {
-                    let dhcp4_map = x.dhcp4_config().map(|it| it.options());
-                    try_adding_dhcp4_ns(resolver_config, dhcp4_map);
+    if err.is_nx_domain() {
+        message.set_response_code(ResponseCode::NXDomain);
+    } else if !matches!(err, LookupError::NameExists) {
+        return Err(ResolveError::from(format!("zone lookup failed: {err}")));
+    }
 
-                    if ipv6_support {
-                        let dhcp6_map = x.dhcp6_config().map(|it| it.options());
-                        try_adding_dhcp6_ns(resolver_config, dhcp6_map);
-                    }
+    if let Some(Ok(soa)) = zone.soa().await.map_result() {
+        message.add_name_servers(soa.iter().cloned());
+    }
+    Ok(message)
+}
+
+pub(crate) async fn hickory_lookup(
+    mushroom: &Mushroom,
+    name: &LowerName,
+    record_type: RecordType,
+    dnssec_ok: bool,
+) -> (Result<Message, ResolveError>, bool) {
+    let ipv6_support = mushroom.ipv6.is_enabled();
+    let query = Query::query(Name::from(name), record_type);
+
+    let chain = mushroom.router.route(name, record_type);
+    let mut result = Err(ResolveError::from("route has no strategies"));
+    for (link, strategy_name) in chain.strategies.iter().enumerate() {
+        result = match mushroom.strategies.get(strategy_name) {
+            Some(strategy) if strategy_name != DEFAULT_STRATEGY => {
+                strategy.lookup(query.clone(), dnssec_ok).await
+            }
+            _ => match mushroom.split_dns.route(name) {
+                Some((interface, upstream)) => {
+                    debug!("{} {} routed to the nameservers of {}", name, record_type, interface);
+                    upstream.lookup(query.clone(), dnssec_ok).await
                 }
-                Device::WiFi(x) => {
-                    let dhcp4_map = x.dhcp4_config().map(|it| it.options());
-                    try_adding_dhcp4_ns(resolver_config, dhcp4_map);
-
-                    if ipv6_support {
-                        let dhcp6_map = x.dhcp6_config().map(|it| it.options());
-                        try_adding_dhcp6_ns(resolver_config, dhcp6_map);
-                    }
+                None => {
+                    let upstream = if ipv6_support {
+                        &mushroom.resolver
+                    } else {
+                        &mushroom.ipv4_resolver
+                    };
+                    upstream.lookup(query.clone(), dnssec_ok).await
                 }
-                _ => {}
-            }
+            },
+        };
+
+        let falls_through = match &result {
+            Ok(message) => chain.falls_through(message.response_code()),
+            Err(err) => chain.falls_through_error(err),
+        };
+        if !falls_through || link + 1 == chain.strategies.len() {
+            info!(
+                "{} {} answered by {} (link {}/{})",
+                name,
+                record_type,
+                strategy_name,
+                link + 1,
+                chain.strategies.len()
+            );
+            break;
+        }
+
+        match &result {
+            Ok(message) => info!(
+                "{} {} via {} answered {}, falling through",
+                name,
+                record_type,
+                strategy_name,
+                message.response_code()
+            ),
+            Err(err) => info!(
+                "{} {} via {} failed: {}, falling through",
+                name, record_type, strategy_name, err
+            ),
         }
     }
+    (result, ipv6_support)
 }
 
-fn try_adding_dhcp6_ns(
-    resolver_config: &mut ResolverConfig,
-    dhcp6_map: Result<Result<HashMap<String, Variant<Box<dyn RefArg>>>, Error>, Error>,
-) {
-    if let Ok(Ok(dhcp6_map)) = dhcp6_map {
-        let ipv6_ns_opt = dhcp6_map.get("dhcp6_name_servers").map(|it| it.as_str());
-        if let Some(Some(dhcp_ipv6s)) = ipv6_ns_opt {
-            dhcp_ipv6s.split(" ")
-                .filter_map(|ipv6_str| {
-                    if let Ok(ipv6_ns) = Ipv6Addr::from_str(ipv6_str) {
-                        Some(ipv6_ns)
-                    } else {
-                        warn!("Your dhcp server is cooked and supplied a garbage ipv6 address {ipv6_str}");
-                        None
-                    }
-                }).for_each(|ipv6_ns| {
-                resolver_config.add_name_server(NameServerConfig::new(
-                    SocketAddr::V6(SocketAddrV6::new(ipv6_ns, 53, 0, 0)),
-                    Protocol::Udp,
-                ))
-            });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    fn link(interface: &str, connection: &str, vpn: bool, name_servers: &[&str]) -> Link {
+        Link {
+            interface: interface.to_string(),
+            connection: connection.to_string(),
+            vpn,
+            name_servers: name_servers.iter().map(|ip| ip.parse().unwrap()).collect(),
+            domains: vec![],
         }
     }
-}
 
-fn try_adding_dhcp4_ns(
-    resolver_config: &mut ResolverConfig,
-    dhcp4_map: Result<Result<HashMap<String, Variant<Box<dyn RefArg>>>, Error>, Error>,
-) {
-    if let Ok(Ok(dhcp4_map)) = dhcp4_map {
-        let ipv4_ns_opt = dhcp4_map.get("domain_name_servers").map(|it| it.as_str());
-        if let Some(Some(dhcp_ipv4s)) = ipv4_ns_opt {
-            dhcp_ipv4s.split(" ")
-                .filter_map(|ipv4_str| {
-                    if let Ok(ipv4_ns) = Ipv4Addr::from_str(ipv4_str) {
-                        Some(ipv4_ns)
-                    } else {
-                        warn!("Your dhcp server is cooked and supplied a garbage ipv4 address {ipv4_str}");
-                        None
-                    }
-                }).for_each(|ipv4_ns| {
-                resolver_config.add_name_server(NameServerConfig::new(
-                    SocketAddr::V4(SocketAddrV4::new(ipv4_ns, 53)),
-                    Protocol::Udp,
-                ))
-            });
+    fn name_servers(network: &NetworkResolver) -> Vec<IpAddr> {
+        network
+            .upstream()
+            .name_servers()
+            .map(|ns| ns.socket_addr.ip())
+            .collect()
+    }
+
+    #[test]
+    fn test_network_refresh() {
+        let links = vec![
+            link("wlan0", "Home WiFi", false, &["192.168.1.1", "fd00::1"]),
+            link("wg0", "Work VPN", true, &["10.8.0.1"]),
+            link("br0", "Bridge", false, &["192.168.1.1"]),
+        ];
+        let fallback = NameServerConfigGroup::from(vec![NameServerConfig::new(
+            "8.8.8.8:53".parse().unwrap(),
+            Protocol::Udp,
+        )]);
+
+        let network = NetworkResolver::new(LinkFilter::default(), fallback.clone());
+        network.refresh(&links, true);
+        assert_eq!(
+            name_servers(&network),
+            ["192.168.1.1", "fd00::1", "10.8.0.1"].map(|ip| ip.parse::<IpAddr>().unwrap())
+        );
+        network.refresh(&links, false);
+        assert_eq!(
+            name_servers(&network),
+            ["192.168.1.1", "10.8.0.1"].map(|ip| ip.parse::<IpAddr>().unwrap())
+        );
+
+        let filters = [
+            (Some("wlan0"), None, false, "192.168.1.1"),
+            (None, Some("Work VPN"), false, "10.8.0.1"),
+            (None, None, true, "10.8.0.1"),
+            (Some("wlan0"), None, true, "8.8.8.8"),
+            (None, Some("Gone"), false, "8.8.8.8"),
+        ];
+        for (interface, connection, vpn, expected) in filters {
+            let filter = LinkFilter {
+                interface: interface.map(str::to_string),
+                connection: connection.map(str::to_string),
+                vpn,
+            };
+            let network = NetworkResolver::new(filter, fallback.clone());
+            network.refresh(&links, false);
+            assert_eq!(
+                name_servers(&network),
+                [expected.parse::<IpAddr>().unwrap()]
+            );
         }
     }
 }
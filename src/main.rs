@@ -1,21 +1,36 @@
 pub mod access;
+pub mod activation;
 pub mod authority;
+pub mod config;
 pub mod error;
+pub mod ipv6;
 pub mod lookup;
+pub mod network;
+#[cfg(test)]
+mod private_bus;
+pub mod privileges;
+pub mod reload;
+pub mod resolve1;
+pub mod routing;
 pub mod server;
+pub mod split_dns;
 pub mod store;
+#[cfg(feature = "dns-over-rustls")]
+pub mod tls;
+pub mod upstream;
 
+use crate::activation::ActivatedSockets;
 use crate::authority::mushroom::Mushroom;
+use crate::config::{Config, CONFIG_PATH_ENV, DEFAULT_CONFIG_PATH};
 use crate::server::ServerFuture;
-use hickory_resolver::config::*;
-use hickory_resolver::TokioResolver;
+use clap::Parser;
 use sd_notify::NotifyState;
 use socket2::{Domain, Socket, Type};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::time::Duration;
-use tokio::net::UdpSocket;
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::runtime;
-use tracing::{error, info};
+use tracing::{error, info, Level};
 use tracing_subscriber::fmt;
 
 /// Low-level types for DNSSEC operations
@@ -47,76 +62,248 @@ pub mod dnssec {
     }
 }
 
+/// Local DNS resolver forwarding queries to (encrypted) upstream nameservers
+#[derive(Debug, Parser)]
+#[clap(name = "MushroomDNResolver", version)]
+struct Cli {
+    /// Path to the config file, defaults to `$MUSHROOM_DNRESOLVER_CONFIG` or
+    /// /etc/mushroom-dnresolver/config.toml
+    #[clap(short = 'c', long = "config", value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Run in the foreground: log with timestamps and don't talk to systemd
+    #[clap(long, conflicts_with = "systemd")]
+    foreground: bool,
+
+    /// Run as a systemd notify service, the default when `$LAUNCHED_BY_SYSTEMD` is set
+    #[clap(long)]
+    systemd: bool,
+
+    /// Listen on this address instead of the ones in the config, may be repeated
+    #[clap(long = "listen", value_name = "ADDR:PORT")]
+    listen: Vec<SocketAddr>,
+
+    /// Validate the config file and exit
+    #[clap(long)]
+    check_config: bool,
+
+    /// Most verbose level that gets logged: error, warn, info, debug or trace
+    #[clap(long, value_name = "LEVEL", default_value_t = Level::INFO)]
+    log_level: Level,
+}
+
+impl Cli {
+    fn in_systemd(&self) -> bool {
+        if self.systemd || self.foreground {
+            return self.systemd;
+        }
+        std::env::var_os("LAUNCHED_BY_SYSTEMD").is_some()
+    }
+}
+
 fn main() -> Result<(), String> {
-    // Construct a new Resolver with default configuration options
-    let in_systemd = true;
-    setup_logging(in_systemd);
+    let args = Cli::parse();
+    let in_systemd = args.in_systemd();
+    setup_logging(in_systemd, args.log_level);
+
+    let mut config = load_config(args.config)?;
+    if !args.listen.is_empty() {
+        config.listen = args.listen;
+    }
+    config.validate().map_err(|err| err.to_string())?;
+
+    if args.check_config {
+        info!("config is valid");
+        return Ok(());
+    }
+
+    // `systemctl reload` must not stop the daemon, whether or not there's a certificate to reload
+    if let Err(err) = reload::handle_sighup() {
+        error!("Can't handle SIGHUP, a reload would stop the daemon: {err}");
+    }
+
+    // sockets passed by systemd replace the configured addresses of their kind
+    let activated = ActivatedSockets::from_env()?;
+    activated.check(&config)?;
+    let binds = udp_sockets(activated.udp, &config.listen);
+    let tcp_binds = tcp_listeners(activated.tcp, &config.listen);
+    // shared by the encrypted listeners, which pick up a renewed certificate without a restart
+    #[cfg(feature = "dns-over-rustls")]
+    let certificate = match &config.tls {
+        Some(tls)
+            if config.dot.is_some()
+                || config.doh.is_some()
+                || config.doq.is_some()
+                || config.doh3.is_some() =>
+        {
+            Some(tls::CertificateResolver::new(tls)?)
+        }
+        _ => None,
+    };
+    #[cfg(feature = "dns-over-rustls")]
+    let dot_binds = match (&config.dot, &config.tls, &certificate) {
+        (Some(dot), Some(tls), Some(certificate)) => {
+            let tls_config = tls::dot_server_config(tls, certificate.clone())?;
+            tcp_listeners(activated.dot, &dot.listen)
+                .into_iter()
+                .map(|bind| (bind, tls_config.clone()))
+                .collect::<Vec<_>>()
+        }
+        _ => vec![],
+    };
+    #[cfg(feature = "dns-over-https-rustls")]
+    let doh_binds = match (&config.doh, &config.tls, &certificate) {
+        (Some(doh), Some(tls), Some(certificate)) => {
+            let tls_config = tls::doh_server_config(tls, certificate.clone())?;
+            tcp_listeners(activated.doh, &doh.listen)
+                .into_iter()
+                .map(|bind| (bind, tls_config.clone()))
+                .collect::<Vec<_>>()
+        }
+        _ => vec![],
+    };
+    #[cfg(feature = "dns-over-quic")]
+    let doq_binds = match (&config.doq, &certificate) {
+        (Some(doq), Some(certificate)) => {
+            let tls_config = tls::doq_server_config(certificate.clone())?;
+            udp_sockets(activated.doq, &doq.listen)
+                .into_iter()
+                .map(|bind| (bind, tls_config.clone()))
+                .collect::<Vec<_>>()
+        }
+        _ => vec![],
+    };
+    #[cfg(feature = "dns-over-h3")]
+    let doh3_binds = match (&config.doh3, &certificate) {
+        (Some(doh3), Some(certificate)) => {
+            let tls_config = tls::doh3_server_config(certificate.clone())?;
+            udp_sockets(activated.doh3, &doh3.listen)
+                .into_iter()
+                .map(|bind| (bind, tls_config.clone()))
+                .collect::<Vec<_>>()
+        }
+        _ => vec![],
+    };
+    let bus = config.dbus.as_ref().and_then(|dbus| {
+        resolve1::connect(dbus)
+            .map_err(|err| error!("Can't claim {} on the system bus: {}", dbus.name, err))
+            .ok()
+    });
+    // everything needing root is done by now, and the privileges must be dropped before the
+    // runtime starts its threads
+    if let Some(privileges) = &config.privileges {
+        privileges::drop_privileges(privileges)?;
+    }
 
     let mut runtime = runtime::Builder::new_multi_thread();
     runtime.enable_all().thread_name("hickory-server-runtime");
-    runtime.worker_threads(8);
+    runtime.worker_threads(config.worker_threads);
     let runtime = runtime
         .build()
         .map_err(|err| format!("failed to initialize Tokio runtime: {err:?}"))?;
 
-    let mut binds = vec![];
     let _guard = runtime.enter();
-    binds.push(build_udp_socket(
-        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-        53,
-    ));
-    let ipv6_sock = build_udp_socket(
-        IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
-        53,
+    let mushroom = Arc::new(Mushroom::from_config(&config)?);
+    mushroom.watch_networks();
+    if let Some(bus) = bus {
+        resolve1::serve(bus, mushroom.clone(), runtime.handle().clone());
+    }
+    let mut server = ServerFuture::with_access(
+        mushroom,
+        &config.deny_networks,
+        &config.allow_networks,
     );
-    binds.push(ipv6_sock);
-
-    let mut opts = ResolverOpts::default();
-    opts.cache_size = 256;
-    opts.timeout = Duration::from_secs(5);
-    opts.try_tcp_on_error = false;
-    opts.attempts = 1;
-    opts.server_ordering_strategy = ServerOrderingStrategy::QueryStatistics;
-    opts.num_concurrent_reqs = 2;
-    opts.use_hosts_file = ResolveHosts::Always;
-    let mut resolver_config = ResolverConfig::new();
-    let mut ipv4_resolver_config = ResolverConfig::new();
-    let config_groups = [
-        NameServerConfigGroup::cloudflare_tls(),
-        NameServerConfigGroup::cloudflare_https(),
-        NameServerConfigGroup::quad9_tls(),
-        NameServerConfigGroup::quad9_https(),
-        NameServerConfigGroup::google_h3(),
-    ];
-    for config_group in config_groups {
-        for name_server_cfg in config_group.iter() {
-            if name_server_cfg.socket_addr.is_ipv4() {
-                ipv4_resolver_config.add_name_server(name_server_cfg.clone());
-                continue
+
+    for bind in binds {
+        let registered = bind.and_then(|bind| {
+            info!("Bound UDP {:?}", bind.local_addr().unwrap());
+            server.register_socket_std(bind)
+        });
+        if let Err(err) = registered {
+            error!("{}", err);
+        }
+    }
+    for bind in tcp_binds {
+        let registered = bind.and_then(|bind| {
+            info!("Bound TCP {:?}", bind.local_addr().unwrap());
+            server.register_listener_std(bind, config.tcp_idle_timeout())
+        });
+        if let Err(err) = registered {
+            error!("{}", err);
+        }
+    }
+    #[cfg(feature = "dns-over-rustls")]
+    if let Some(dot) = &config.dot {
+        for (bind, tls_config) in dot_binds {
+            let registered = bind.and_then(|bind| {
+                info!("Bound DNS-over-TLS {:?}", bind.local_addr().unwrap());
+                let bind = tokio::net::TcpListener::from_std(bind)?;
+                server.register_tls_listener_with_tls_config(bind, dot.idle_timeout(), tls_config)
+            });
+            if let Err(err) = registered {
+                error!("{}", err);
             }
-            resolver_config.add_name_server(name_server_cfg.clone());
         }
     }
-
-    // Fuck nordvpn 🖕
-    let resolver = TokioResolver::tokio(ipv4_resolver_config.clone(), opts.clone());
-    let ipv4_resolver = TokioResolver::tokio(ipv4_resolver_config, opts);
-    let mushroom = Mushroom { resolver, ipv4_resolver };
-    let deny_networks = &[];
-    let allow_networks = &[];
-    let mut server = ServerFuture::with_access(mushroom, deny_networks, allow_networks);
-
-    for bind in binds {
-        match bind {
-            Ok(bind) => {
-                info!("Bound {:?}", bind.local_addr().unwrap());
-                server.register_socket(bind);
-            },
-            Err(err) => {
+    #[cfg(feature = "dns-over-https-rustls")]
+    if let Some(doh) = &config.doh {
+        let limits = server::Http2Limits {
+            max_concurrent_streams: doh.max_concurrent_streams,
+            idle_timeout: doh.idle_timeout(),
+        };
+        for (bind, tls_config) in doh_binds {
+            let registered = bind.and_then(|bind| {
+                info!("Bound DNS-over-HTTPS {:?}", bind.local_addr().unwrap());
+                let bind = tokio::net::TcpListener::from_std(bind)?;
+                server.register_https_listener_with_tls_config(
+                    bind,
+                    doh.handshake_timeout(),
+                    tls_config,
+                    doh.hostname.clone(),
+                    doh.path.clone(),
+                    server::DohClients::new(doh.clients.clone()),
+                    limits,
+                )
+            });
+            if let Err(err) = registered {
+                error!("{}", err);
+            }
+        }
+    }
+    #[cfg(feature = "dns-over-quic")]
+    for (bind, tls_config) in doq_binds {
+        let registered = bind.and_then(|bind| {
+            info!("Bound DNS-over-QUIC {:?}", bind.local_addr().unwrap());
+            let bind = tokio::net::UdpSocket::from_std(bind)?;
+            server.register_quic_listener_with_tls_config(bind, tls_config, None)
+        });
+        if let Err(err) = registered {
+            error!("{}", err);
+        }
+    }
+    #[cfg(feature = "dns-over-h3")]
+    if let Some(doh3) = &config.doh3 {
+        for (bind, tls_config) in doh3_binds {
+            let registered = bind.and_then(|bind| {
+                info!("Bound DNS-over-HTTP/3 {:?}", bind.local_addr().unwrap());
+                let bind = tokio::net::UdpSocket::from_std(bind)?;
+                server.register_h3_listener_with_tls_config(
+                    bind,
+                    tls_config,
+                    doh3.hostname.clone(),
+                    doh3.path.clone(),
+                    server::DohClients::new(doh3.clients.clone()),
+                )
+            });
+            if let Err(err) = registered {
                 error!("{}", err);
             }
         }
     }
+    #[cfg(feature = "dns-over-rustls")]
+    if let Some(certificate) = certificate {
+        tls::spawn_certificate_reload(certificate);
+    }
 
     info!("server starting up, awaiting connections...");
 
@@ -144,18 +331,67 @@ fn main() -> Result<(), String> {
     Ok(())
 }
 
-fn setup_logging(in_systemd: bool) {
+/// Reads the config file from `path`, `$MUSHROOM_DNRESOLVER_CONFIG` or [`DEFAULT_CONFIG_PATH`],
+/// whichever is set first.
+///
+/// A missing file at the default location is not an error, the built-in defaults are used instead.
+fn load_config(path: Option<PathBuf>) -> Result<Config, String> {
+    let path = path.or_else(|| std::env::var_os(CONFIG_PATH_ENV).map(PathBuf::from));
+    let (path, explicit) = match path {
+        Some(path) => (path, true),
+        None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+    };
+
+    if !explicit && !path.exists() {
+        info!("no config file at {}, using defaults", path.display());
+        return Ok(Config::default());
+    }
+
+    info!("loading config from {}", path.display());
+    Config::read_config(&path)
+        .map_err(|err| format!("failed to read config {}: {err}", path.display()))
+}
+
+fn setup_logging(in_systemd: bool, level: Level) {
     let systemd_format = fmt::format()
         .without_time();
 
     let fmt = tracing_subscriber::fmt()
-        .event_format(systemd_format);
+        .with_max_level(level);
 
     if in_systemd {
-        fmt.init();
+        fmt.event_format(systemd_format).init();
     } else {
-        tracing_subscriber::fmt().init()
+        fmt.init()
+    }
+}
+
+/// The UDP sockets passed by systemd, or else sockets bound to `addrs`
+fn udp_sockets(
+    activated: Vec<UdpSocket>,
+    addrs: &[SocketAddr],
+) -> Vec<Result<UdpSocket, std::io::Error>> {
+    if activated.is_empty() {
+        return addrs
+            .iter()
+            .map(|addr| build_udp_socket(addr.ip(), addr.port()))
+            .collect();
+    }
+    activated.into_iter().map(Ok).collect()
+}
+
+/// The TCP listeners passed by systemd, or else listeners bound to `addrs`
+fn tcp_listeners(
+    activated: Vec<TcpListener>,
+    addrs: &[SocketAddr],
+) -> Vec<Result<TcpListener, std::io::Error>> {
+    if activated.is_empty() {
+        return addrs
+            .iter()
+            .map(|addr| build_tcp_listener(addr.ip(), addr.port()))
+            .collect();
     }
+    activated.into_iter().map(Ok).collect()
 }
 
 /// Build a UdpSocket for a given IP, port pair; IPv6 sockets will not accept v4 connections
@@ -173,5 +409,25 @@ fn build_udp_socket(ip: IpAddr, port: u16) -> Result<UdpSocket, std::io::Error>
     let s_addr = SocketAddr::new(ip, port);
     sock.bind(&s_addr.into())?;
 
-    UdpSocket::from_std(sock.into())
+    Ok(sock.into())
+}
+
+/// Build a TcpListener for a given IP, port pair; IPv6 sockets will not accept v4 connections
+fn build_tcp_listener(ip: IpAddr, port: u16) -> Result<TcpListener, std::io::Error> {
+    let sock = if ip.is_ipv4() {
+        Socket::new(Domain::IPV4, Type::STREAM, None)?
+    } else {
+        let s = Socket::new(Domain::IPV6, Type::STREAM, None)?;
+        s.set_only_v6(true)?;
+        s
+    };
+
+    sock.set_nonblocking(true)?;
+    sock.set_reuse_address(true)?;
+
+    let s_addr = SocketAddr::new(ip, port);
+    sock.bind(&s_addr.into())?;
+    sock.listen(128)?;
+
+    Ok(sock.into())
 }
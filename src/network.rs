@@ -0,0 +1,501 @@
+//! The connections NetworkManager has up, with the nameservers and search domains they were handed
+//!
+//! A thread follows NetworkManager's signals on the system bus, so the links are read again as soon
+//! as a device comes up or goes down, gets a new DHCP lease or a VPN connects.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dbus::arg::PropMap;
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::{Connection, Proxy};
+use dbus::channel::Channel;
+use dbus::message::MatchRule;
+use dbus::Path;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+const NM_BUS: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_ACTIVE_CONNECTION: &str = "org.freedesktop.NetworkManager.Connection.Active";
+const NM_DEVICE: &str = "org.freedesktop.NetworkManager.Device";
+const NM_IP4_CONFIG: &str = "org.freedesktop.NetworkManager.IP4Config";
+const NM_IP6_CONFIG: &str = "org.freedesktop.NetworkManager.IP6Config";
+
+/// How long a call to NetworkManager may take
+const DBUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the links are read again without any signal, in case one was missed
+const RESYNC_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long to wait for a burst of signals to settle before reading the links, a stream of them
+/// that doesn't end only holds the links off this long
+const SETTLE_DELAY: Duration = Duration::from_millis(200);
+
+/// How long to wait before connecting to the bus again after losing it
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// A connection that is up, with what it was handed to resolve names with
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Link {
+    /// Interface the connection is on, e.g. `wlan0` or `wg0`
+    pub interface: String,
+    /// ID of the connection, e.g. `Home WiFi` or `Work VPN`
+    pub connection: String,
+    /// Whether the connection is a VPN
+    pub vpn: bool,
+    /// Nameservers, IPv4 before IPv6, each in the order the network handed them out
+    pub name_servers: Vec<IpAddr>,
+    /// Search and routing domains
+    pub domains: Vec<String>,
+}
+
+/// The links in the order NetworkManager lists their connections
+pub type Links = Vec<Link>;
+
+/// The current links, with a channel announcing their changes
+pub struct NetworkLinks {
+    state: watch::Sender<Arc<Links>>,
+    /// Address of the bus NetworkManager is on, the system bus when `None`
+    bus_address: Option<String>,
+}
+
+impl NetworkLinks {
+    /// Reads the links from NetworkManager on the system bus once, call [`Self::watch`] to keep
+    /// them up to date
+    pub fn new() -> Arc<Self> {
+        Self::on_bus(None)
+    }
+
+    /// Like [`Self::new`], with NetworkManager on the bus at `bus_address`
+    pub(crate) fn on_bus(bus_address: Option<String>) -> Arc<Self> {
+        let links = Self {
+            state: watch::Sender::new(Arc::default()),
+            bus_address,
+        };
+        // the watch thread warns when the bus can't be reached
+        match links.connect() {
+            Ok(connection) => links.refresh(&connection),
+            Err(err) => debug!("Can't reach NetworkManager: {err}"),
+        }
+        Arc::new(links)
+    }
+
+    /// The links as last read
+    pub fn current(&self) -> Arc<Links> {
+        self.state.borrow().clone()
+    }
+
+    /// A receiver that is notified whenever the links change
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Links>> {
+        self.state.subscribe()
+    }
+
+    /// Follows NetworkManager's signals on a thread of its own, the D-Bus connection blocks
+    pub fn watch(self: &Arc<Self>) {
+        let links = self.clone();
+        let spawned = std::thread::Builder::new()
+            .name("network-watch".to_string())
+            .spawn(move || links.follow_forever());
+        if let Err(err) = spawned {
+            warn!("Can't follow NetworkManager: {err}");
+        }
+    }
+
+    fn connect(&self) -> Result<Connection, dbus::Error> {
+        match &self.bus_address {
+            None => Connection::new_system(),
+            Some(address) => {
+                let mut channel = Channel::open_private(address)?;
+                channel.register()?;
+                Ok(Connection::from(channel))
+            }
+        }
+    }
+
+    fn follow_forever(&self) {
+        let mut warned = false;
+        loop {
+            let Err(err) = self.follow() else {
+                return;
+            };
+            if warned {
+                debug!("Can't reach NetworkManager, trying again in {RECONNECT_DELAY:?}: {err}");
+            } else {
+                warn!("Can't reach NetworkManager, trying again in {RECONNECT_DELAY:?}: {err}");
+                warned = true;
+            }
+            std::thread::sleep(RECONNECT_DELAY);
+        }
+    }
+
+    /// Reads the links whenever NetworkManager signals a change, until the connection fails
+    fn follow(&self) -> Result<(), dbus::Error> {
+        let connection = self.connect()?;
+        let changed = Arc::new(AtomicBool::new(false));
+        let rules = [
+            MatchRule::new_signal(NM_BUS, "StateChanged").with_sender(NM_BUS),
+            // devices, their states, DHCP leases and active connections of all NetworkManager's
+            // objects
+            MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+                .with_sender(NM_BUS)
+                .with_namespaced_path(NM_PATH),
+        ];
+        for rule in rules {
+            let changed = changed.clone();
+            connection.add_match(rule, move |_: (), _, _| {
+                changed.store(true, Ordering::Relaxed);
+                true
+            })?;
+        }
+
+        // anything that changed before the signals were subscribed to
+        self.refresh(&connection);
+        let mut last_read = Instant::now();
+        loop {
+            connection.process(RESYNC_INTERVAL.saturating_sub(last_read.elapsed()))?;
+            if changed.load(Ordering::Relaxed) {
+                let settled = Instant::now() + SETTLE_DELAY;
+                while let Some(left) = settled.checked_duration_since(Instant::now()) {
+                    if !connection.process(left)? {
+                        break;
+                    }
+                }
+            } else if last_read.elapsed() < RESYNC_INTERVAL {
+                continue;
+            }
+            changed.store(false, Ordering::Relaxed);
+            self.refresh(&connection);
+            last_read = Instant::now();
+        }
+    }
+
+    /// Reads the links, keeping the last ones if NetworkManager can't be asked
+    fn refresh(&self, connection: &Connection) {
+        let links = match read_links(connection) {
+            Ok(links) => links,
+            Err(err) => {
+                warn!("Can't read the network links from NetworkManager: {err}");
+                return;
+            }
+        };
+        self.state.send_if_modified(|current| {
+            if **current == links {
+                return false;
+            }
+            info!("Network links are now {:?}", links);
+            *current = Arc::new(links);
+            true
+        });
+    }
+}
+
+/// The active connections of every link type with the nameservers and domains of their IP configs,
+/// however they got them: DHCP, router advertisements, VPN plugins or static settings
+fn read_links(connection: &Connection) -> Result<Links, dbus::Error> {
+    let nm = connection.with_proxy(NM_BUS, NM_PATH, DBUS_TIMEOUT);
+    let active_connections: Vec<Path> = nm.get(NM_BUS, "ActiveConnections")?;
+
+    let mut links = Links::new();
+    for active in active_connections {
+        let active = connection.with_proxy(NM_BUS, active, DBUS_TIMEOUT);
+        let devices: Vec<Path> = active.get(NM_ACTIVE_CONNECTION, "Devices")?;
+        let interface = match devices.into_iter().next() {
+            Some(device) => connection
+                .with_proxy(NM_BUS, device, DBUS_TIMEOUT)
+                .get(NM_DEVICE, "IpInterface")?,
+            None => String::new(),
+        };
+        let mut link = Link {
+            interface,
+            connection: active.get(NM_ACTIVE_CONNECTION, "Id")?,
+            vpn: active.get(NM_ACTIVE_CONNECTION, "Vpn")?,
+            name_servers: vec![],
+            domains: vec![],
+        };
+
+        for (property, config_interface) in
+            [("Ip4Config", NM_IP4_CONFIG), ("Ip6Config", NM_IP6_CONFIG)]
+        {
+            let config: Path = active.get(NM_ACTIVE_CONNECTION, property)?;
+            if &*config == "/" {
+                continue;
+            }
+            let config = connection.with_proxy(NM_BUS, config, DBUS_TIMEOUT);
+            link.name_servers
+                .extend(name_servers(&config, config_interface)?);
+            for property in ["Searches", "Domains"] {
+                let domains: Vec<String> = config.get(config_interface, property)?;
+                for domain in domains {
+                    if !link.domains.contains(&domain) {
+                        link.domains.push(domain);
+                    }
+                }
+            }
+        }
+        if !link.name_servers.is_empty() || !link.domains.is_empty() {
+            links.push(link);
+        }
+    }
+    Ok(links)
+}
+
+/// The nameservers of an IP config. IP6Config may lack `NameserverData`, it always lists the
+/// addresses as byte arrays in `Nameservers`.
+fn name_servers(config: &Proxy<&Connection>, interface: &str) -> Result<Vec<IpAddr>, dbus::Error> {
+    let data: Vec<PropMap> = match config.get(interface, "NameserverData") {
+        Ok(data) => data,
+        Err(_) if interface == NM_IP6_CONFIG => {
+            let addresses: Vec<Vec<u8>> = config.get(interface, "Nameservers")?;
+            return Ok(addresses
+                .into_iter()
+                .filter_map(|address| <[u8; 16]>::try_from(address).ok())
+                .map(IpAddr::from)
+                .collect());
+        }
+        Err(err) => return Err(err),
+    };
+    Ok(data
+        .iter()
+        .filter_map(|data| {
+            let address = data.get("address")?.0.as_str()?;
+            match address.parse() {
+                Ok(address) => Some(address),
+                Err(_) => {
+                    warn!("NetworkManager handed out a garbage nameserver {address}");
+                    None
+                }
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::private_bus::PrivateBus;
+    use dbus::arg::{RefArg, Variant};
+    use dbus::channel::{MatchingReceiver, Sender};
+    use dbus::Message;
+    use std::net::Ipv6Addr;
+    use std::sync::{mpsc, Mutex};
+
+    /// An active connection of the mock NetworkManager
+    #[derive(Clone)]
+    struct MockConnection {
+        id: &'static str,
+        interface: &'static str,
+        vpn: bool,
+        ip4: Vec<&'static str>,
+        ip6: Vec<&'static str>,
+        searches: Vec<&'static str>,
+    }
+
+    /// Answers the properties of active connections, their devices and IP configs like
+    /// NetworkManager would, and sends the signals it is given
+    struct MockNetworkManager {
+        signals: mpsc::Sender<Message>,
+        /// Sends PropertiesChanged over and over while set
+        flood: Arc<AtomicBool>,
+        stop: Arc<AtomicBool>,
+    }
+
+    impl MockNetworkManager {
+        fn start(bus: &PrivateBus, connections: Arc<Mutex<Vec<MockConnection>>>) -> Self {
+            let connection = bus.connect();
+            connection.request_name(NM_BUS, false, true, true).unwrap();
+            let (signals, queued) = mpsc::channel::<Message>();
+            let flood = Arc::new(AtomicBool::new(false));
+            let flooding = flood.clone();
+            let stop = Arc::new(AtomicBool::new(false));
+            let stopped = stop.clone();
+            std::thread::spawn(move || {
+                connection.start_receive(
+                    MatchRule::new_method_call(),
+                    Box::new(move |call: Message, connection: &Connection| {
+                        let _ = connection.send(answer(&call, &connections.lock().unwrap()));
+                        true
+                    }),
+                );
+                while !stopped.load(Ordering::Relaxed) {
+                    connection.process(Duration::from_millis(20)).unwrap();
+                    for signal in queued.try_iter() {
+                        let _ = connection.send(signal);
+                    }
+                    if flooding.load(Ordering::Relaxed) {
+                        let _ = connection.send(properties_changed());
+                    }
+                }
+            });
+            Self {
+                signals,
+                flood,
+                stop,
+            }
+        }
+
+        fn send(&self, signal: Message) {
+            self.signals.send(signal).unwrap();
+        }
+    }
+
+    impl Drop for MockNetworkManager {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn properties_changed() -> Message {
+        Message::new_signal(
+            NM_PATH,
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+        )
+        .unwrap()
+        .append3(NM_BUS, PropMap::new(), Vec::<String>::new())
+    }
+
+    fn answer(call: &Message, connections: &[MockConnection]) -> Message {
+        let path = call.path().unwrap().to_string();
+        let (_, property): (String, String) = call.read2().unwrap();
+        let object = |kind: &str, index: usize| Path::from(format!("{NM_PATH}/{kind}/{index}"));
+        let value: Option<Box<dyn RefArg>> = if path == NM_PATH {
+            let active = (0..connections.len()).map(|index| object("ActiveConnection", index));
+            (property == "ActiveConnections").then(|| Box::new(active.collect::<Vec<_>>()) as _)
+        } else {
+            let mut segments = path.rsplit('/');
+            let index: usize = segments.next().unwrap().parse().unwrap();
+            let connection = &connections[index];
+            match (segments.next().unwrap(), property.as_str()) {
+                ("ActiveConnection", "Id") => Some(Box::new(connection.id.to_string())),
+                ("ActiveConnection", "Vpn") => Some(Box::new(connection.vpn)),
+                ("ActiveConnection", "Devices") => Some(Box::new(vec![object("Devices", index)])),
+                ("ActiveConnection", "Ip4Config") => Some(Box::new(object("IP4Config", index))),
+                ("ActiveConnection", "Ip6Config") => Some(Box::new(object("IP6Config", index))),
+                ("Devices", "IpInterface") => Some(Box::new(connection.interface.to_string())),
+                ("IP4Config", "NameserverData") => {
+                    let data: Vec<PropMap> = connection
+                        .ip4
+                        .iter()
+                        .map(|address| {
+                            let address = Variant(Box::new(address.to_string()) as Box<dyn RefArg>);
+                            PropMap::from([("address".to_string(), address)])
+                        })
+                        .collect();
+                    Some(Box::new(data))
+                }
+                // like older NetworkManagers, there is only `Nameservers` for IPv6
+                ("IP6Config", "Nameservers") => {
+                    let addresses: Vec<Vec<u8>> = connection
+                        .ip6
+                        .iter()
+                        .map(|address| address.parse::<Ipv6Addr>().unwrap().octets().to_vec())
+                        .collect();
+                    Some(Box::new(addresses))
+                }
+                ("IP4Config", "Searches") => {
+                    let searches = connection.searches.iter().map(|domain| domain.to_string());
+                    Some(Box::new(searches.collect::<Vec<_>>()))
+                }
+                ("IP4Config" | "IP6Config", "Searches" | "Domains") => {
+                    Some(Box::new(Vec::<String>::new()))
+                }
+                _ => None,
+            }
+        };
+        match value {
+            Some(value) => call.method_return().append1(Variant(value)),
+            None => {
+                let name = "org.freedesktop.DBus.Error.UnknownProperty".into();
+                call.error(&name, c"unknown property")
+            }
+        }
+    }
+
+    fn wait_for(links: &NetworkLinks, expected: &Links) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while *links.current() != *expected {
+            assert!(
+                Instant::now() < deadline,
+                "links stayed {:?}",
+                links.current()
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn link(connection: &MockConnection) -> Link {
+        let ip4 = connection
+            .ip4
+            .iter()
+            .filter(|ns| ns.parse::<IpAddr>().is_ok());
+        Link {
+            interface: connection.interface.to_string(),
+            connection: connection.id.to_string(),
+            vpn: connection.vpn,
+            name_servers: ip4
+                .chain(&connection.ip6)
+                .map(|ns| ns.parse().unwrap())
+                .collect(),
+            domains: connection.searches.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_follow_network_manager() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("no dbus-daemon, skipping");
+            return;
+        };
+        let wired = MockConnection {
+            id: "Wired connection 1",
+            interface: "eth0",
+            vpn: false,
+            ip4: vec!["192.0.2.1", "192.0.2.2"],
+            ip6: vec![],
+            searches: vec!["corp.example"],
+        };
+        let vpn = MockConnection {
+            id: "Work VPN",
+            interface: "wg0",
+            vpn: true,
+            ip4: vec!["10.8.0.1", "garbage"],
+            ip6: vec!["fd00::53"],
+            searches: vec!["~work.example"],
+        };
+        let connections = Arc::new(Mutex::new(vec![wired.clone(), vpn.clone()]));
+        let network_manager = MockNetworkManager::start(&bus, connections.clone());
+
+        let links = NetworkLinks::on_bus(Some(bus.address.clone()));
+        assert_eq!(*links.current(), vec![link(&wired), link(&vpn)]);
+        links.watch();
+
+        // the VPN disconnects and a bridge without IPv4 comes up
+        let bridge = MockConnection {
+            id: "Bridge",
+            interface: "br0",
+            vpn: false,
+            ip4: vec![],
+            ip6: vec!["2001:db8::53"],
+            searches: vec![],
+        };
+        *connections.lock().unwrap() = vec![wired.clone(), bridge.clone()];
+        network_manager.send(properties_changed());
+        wait_for(&links, &vec![link(&wired), link(&bridge)]);
+
+        // everything goes down
+        connections.lock().unwrap().clear();
+        let signal = Message::new_signal(NM_PATH, NM_BUS, "StateChanged")
+            .unwrap()
+            .append1(20u32);
+        network_manager.send(signal);
+        wait_for(&links, &vec![]);
+
+        // signals that never stop don't keep the links from being read
+        *connections.lock().unwrap() = vec![wired.clone()];
+        network_manager.flood.store(true, Ordering::Relaxed);
+        wait_for(&links, &vec![link(&wired)]);
+    }
+}
@@ -0,0 +1,75 @@
+//! A D-Bus daemon of our own for the tests, so they don't need (or disturb) the system bus
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dbus::blocking::Connection;
+use dbus::channel::Channel;
+
+/// Tells the config files of buses started at the same time apart
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+/// A `dbus-daemon` allowing everything, stopped when dropped
+pub struct PrivateBus {
+    daemon: Child,
+    pub address: String,
+}
+
+impl PrivateBus {
+    /// Starts a `dbus-daemon`, `None` if there is none installed
+    pub fn start() -> Option<Self> {
+        let n = STARTED.fetch_add(1, Ordering::Relaxed);
+        let config =
+            std::env::temp_dir().join(format!("mushroom-bus-{}-{n}.conf", std::process::id()));
+        // the policy we ship is included, so a broken one keeps the daemon from starting
+        let policy = concat!(env!("CARGO_MANIFEST_DIR"), "/org.freedesktop.resolve1.conf");
+        std::fs::write(
+            &config,
+            format!(
+                r#"<busconfig>
+                    <type>session</type>
+                    <listen>unix:tmpdir=/tmp</listen>
+                    <auth>EXTERNAL</auth>
+                    <policy context="default">
+                        <allow user="*"/>
+                        <allow own="*"/>
+                        <allow send_destination="*" eavesdrop="true"/>
+                        <allow eavesdrop="true"/>
+                    </policy>
+                    <include>{policy}</include>
+                </busconfig>"#
+            ),
+        )
+        .unwrap();
+        let mut daemon = Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .args(["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let _ = std::fs::remove_file(config);
+        Some(Self {
+            daemon,
+            address: address.trim().to_string(),
+        })
+    }
+
+    pub fn connect(&self) -> Connection {
+        let mut channel = Channel::open_private(&self.address).unwrap();
+        channel.register().unwrap();
+        Connection::from(channel)
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
@@ -0,0 +1,442 @@
+//! Giving up root once the listeners are bound, and sandboxing what is left
+//!
+//! Everything here must run before the Tokio runtime starts its threads: the user switch and
+//! seccomp cover every thread, but Landlock only restricts the thread that asks for it and the
+//! threads it starts afterwards.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+
+use tracing::info;
+
+use crate::config::PrivilegesConfig;
+
+/// Switches to the configured user and group, then applies the configured sandboxes
+pub fn drop_privileges(config: &PrivilegesConfig) -> Result<(), String> {
+    if let Some(user) = &config.user {
+        let (uid, primary_gid) = lookup_user(user)?;
+        let gid = match &config.group {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid,
+        };
+        switch_user(uid, gid).map_err(|err| format!("failed to switch to user {user}: {err}"))?;
+        info!("Switched to user {user} ({uid}:{gid})");
+    }
+
+    if config.landlock || config.seccomp {
+        // required for unprivileged sandboxes, and keeps them from being undone by executing setuid
+        // binaries
+        // SAFETY: prctl with PR_SET_NO_NEW_PRIVS only takes integer arguments
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(format!(
+                "failed to set no_new_privs: {}",
+                io::Error::last_os_error()
+            ));
+        }
+    }
+    if config.landlock {
+        landlock::restrict_filesystem().map_err(|err| format!("failed to apply landlock: {err}"))?;
+        info!("Landlock applied, the file system is read-only");
+    }
+    if config.seccomp {
+        seccomp::deny_syscalls().map_err(|err| format!("failed to apply seccomp: {err}"))?;
+        info!("Seccomp filter applied");
+    }
+    Ok(())
+}
+
+/// User ID and primary group ID of the user named `name`
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let c_name = CString::new(name).map_err(|_| format!("invalid user name {name}"))?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the duration of the call, and the buffer's length is
+    // passed along with it
+    let err = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if err != 0 {
+        return Err(format!(
+            "failed to look up user {name}: {}",
+            io::Error::from_raw_os_error(err)
+        ));
+    }
+    if result.is_null() {
+        return Err(format!("unknown user {name}"));
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Group ID of the group named `name`
+fn lookup_group(name: &str) -> Result<libc::gid_t, String> {
+    let c_name = CString::new(name).map_err(|_| format!("invalid group name {name}"))?;
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: as in lookup_user
+    let err = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if err != 0 {
+        return Err(format!(
+            "failed to look up group {name}: {}",
+            io::Error::from_raw_os_error(err)
+        ));
+    }
+    if result.is_null() {
+        return Err(format!("unknown group {name}"));
+    }
+    Ok(group.gr_gid)
+}
+
+/// Drops the supplementary groups, then sets the group and user. Switching away from root clears
+/// all capabilities, including `CAP_NET_BIND_SERVICE`.
+fn switch_user(uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    // SAFETY: these only take integers, and a pointer to one gid for setgroups
+    unsafe {
+        if libc::setgroups(1, &gid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::setgid(gid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::setuid(uid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // make sure root can't be regained
+        if uid != 0 && libc::setuid(0) == 0 {
+            return Err(io::Error::other("root could be regained"));
+        }
+    }
+    Ok(())
+}
+
+/// Landlock, see [landlock(7)](https://man7.org/linux/man-pages/man7/landlock.7.html)
+mod landlock {
+    use super::*;
+
+    const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    /// Everything up to `MAKE_SYM`, the rights of the first ABI version
+    const ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Leaves only reading files and directories allowed: nothing can be written, created,
+    /// removed or executed anymore
+    pub(super) fn restrict_filesystem() -> io::Result<()> {
+        // SAFETY: a null attribute with the version flag only queries the ABI version
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut handled_access_fs = ACCESS_FS_ABI_1;
+        if abi >= 2 {
+            handled_access_fs |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled_access_fs |= ACCESS_FS_TRUNCATE;
+        }
+
+        let attr = RulesetAttr { handled_access_fs };
+        // SAFETY: the attribute is valid and its size is passed along
+        let ruleset = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the ruleset was just created and isn't owned by anything else
+        let ruleset = unsafe { <OwnedFd as std::os::fd::FromRawFd>::from_raw_fd(ruleset as i32) };
+
+        // SAFETY: the path is nul terminated
+        let root = unsafe { libc::open(c"/".as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if root < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the file descriptor was just opened
+        let root = unsafe { <OwnedFd as std::os::fd::FromRawFd>::from_raw_fd(root) };
+        let path_beneath = PathBeneathAttr {
+            allowed_access: ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR,
+            parent_fd: root.as_raw_fd(),
+        };
+        // SAFETY: both file descriptors are open and the attribute is valid
+        let added = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &path_beneath,
+                0,
+            )
+        };
+        if added != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: the ruleset is open, no_new_privs is set by the caller
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Seccomp, see [seccomp(2)](https://man7.org/linux/man-pages/man2/seccomp.2.html)
+mod seccomp {
+    use super::*;
+
+    #[cfg(target_arch = "x86_64")]
+    pub(super) const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    pub(super) const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Syscalls of the x32 ABI, numbered from here, would get around the filter
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// Offsets in `struct seccomp_data`
+    const SYSCALL_NR: u32 = 0;
+    const SYSCALL_ARCH: u32 = 4;
+
+    /// Syscalls a resolver never needs, but an attacker would: running programs, debugging or
+    /// reading other processes, changing users, mounts, namespaces or the kernel
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_setuid,
+        libc::SYS_setgid,
+        libc::SYS_setreuid,
+        libc::SYS_setregid,
+        libc::SYS_setresuid,
+        libc::SYS_setresgid,
+        libc::SYS_setfsuid,
+        libc::SYS_setfsgid,
+        libc::SYS_setgroups,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_kexec_load,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_acct,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_personality,
+    ];
+
+    fn statement(code: u32, k: u32) -> libc::sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// The filter program: anything denied fails with EPERM, the rest is allowed
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub(super) fn filter() -> Vec<libc::sock_filter> {
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let mut program = vec![
+            statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SYSCALL_ARCH),
+            jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0),
+            statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SYSCALL_NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, X32_SYSCALL_BIT, 0, 1),
+            statement(libc::BPF_RET | libc::BPF_K, deny),
+        ]);
+        for &syscall in DENIED {
+            program.extend([
+                jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, syscall as u32, 0, 1),
+                statement(libc::BPF_RET | libc::BPF_K, deny),
+            ]);
+        }
+        program.push(statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+        program
+    }
+
+    /// Installs the filter on every thread of the process
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub(super) fn deny_syscalls() -> io::Result<()> {
+        let mut program = filter();
+        let fprog = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_mut_ptr(),
+        };
+        // SAFETY: the program outlives the call, which copies it into the kernel
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &fprog,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub(super) fn deny_syscalls() -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no filter for this architecture",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root"), Ok((0, 0)));
+        assert_eq!(lookup_group("root"), Ok(0));
+        assert!(lookup_user("no-such-user-mushroom").is_err());
+        assert!(lookup_group("no-such-group-mushroom").is_err());
+    }
+
+    /// Runs the filter like the kernel would on a syscall of `arch`, returning its action
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn run_filter(program: &[libc::sock_filter], arch: u32, nr: u32) -> u32 {
+        let mut accumulator = 0;
+        let mut pc = 0;
+        loop {
+            let instruction = program[pc];
+            pc += 1;
+            match instruction.code as u32 {
+                code if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => {
+                    accumulator = match instruction.k {
+                        0 => nr,
+                        4 => arch,
+                        k => panic!("loads unexpected offset {k}"),
+                    }
+                }
+                code if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K => {
+                    let taken = accumulator == instruction.k;
+                    pc += usize::from(if taken { instruction.jt } else { instruction.jf });
+                }
+                code if code == libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K => {
+                    let taken = accumulator >= instruction.k;
+                    pc += usize::from(if taken { instruction.jt } else { instruction.jf });
+                }
+                code if code == libc::BPF_RET | libc::BPF_K => return instruction.k,
+                code => panic!("unexpected instruction {code:#x}"),
+            }
+        }
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn test_seccomp_filter() {
+        let program = seccomp::filter();
+        let run = |arch, nr: libc::c_long| run_filter(&program, arch, nr as u32);
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+        for nr in [
+            libc::SYS_execve,
+            libc::SYS_ptrace,
+            libc::SYS_setuid,
+            libc::SYS_mount,
+            libc::SYS_unshare,
+            libc::SYS_bpf,
+            libc::SYS_personality,
+        ] {
+            assert_eq!(run(seccomp::AUDIT_ARCH, nr), deny, "syscall {nr}");
+        }
+        for nr in [
+            libc::SYS_read,
+            libc::SYS_sendto,
+            libc::SYS_recvfrom,
+            libc::SYS_futex,
+            libc::SYS_openat,
+        ] {
+            assert_eq!(
+                run(seccomp::AUDIT_ARCH, nr),
+                libc::SECCOMP_RET_ALLOW,
+                "syscall {nr}"
+            );
+        }
+
+        // syscalls of another architecture are numbered differently, the process is killed
+        const AUDIT_ARCH_I386: u32 = 0x4000_0003;
+        const AUDIT_ARCH_ARM: u32 = 0x4000_0028;
+        for arch in [AUDIT_ARCH_I386, AUDIT_ARCH_ARM] {
+            assert_eq!(
+                run(arch, libc::SYS_read),
+                libc::SECCOMP_RET_KILL_PROCESS
+            );
+        }
+        // the x32 ABI shares the arch of x86_64
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(run(seccomp::AUDIT_ARCH, 0x4000_0000 | libc::SYS_read), deny);
+    }
+}
@@ -0,0 +1,51 @@
+//! SIGHUP, which `systemctl reload` sends, asks for the certificate to be loaded again
+//!
+//! The handler is installed whatever the config, so a reload never terminates the daemon. Without
+//! a certificate there is nothing to reload, and the signal is ignored.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by SIGHUP, asks for a reload
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_signal: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Sets [`RELOAD_REQUESTED`] on SIGHUP, instead of terminating
+pub fn handle_sighup() -> io::Result<()> {
+    // SAFETY: the action is fully initialized before it is installed, and the handler only
+    // stores to an atomic, which is async-signal-safe
+    let result = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut())
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether SIGHUP came since the last call
+pub fn take_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sighup() {
+        handle_sighup().unwrap();
+        take_request();
+        // SAFETY: raise only delivers the signal to this thread, whose handler was just installed
+        assert_eq!(unsafe { libc::raise(libc::SIGHUP) }, 0);
+        assert!(take_request());
+        assert!(!take_request());
+    }
+}
@@ -0,0 +1,902 @@
+//! The `org.freedesktop.resolve1.Manager` D-Bus API of systemd-resolved
+//!
+//! resolvectl, NetworkManager's resolved plugin and GNOME's tools talk to it. Lookups take the same
+//! routes, split DNS and caches as queries to the listeners, and the links set over it are those of
+//! the split DNS table.
+
+use std::ffi::{CStr, CString};
+use std::net::IpAddr;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
+use dbus::blocking::Connection;
+use dbus::channel::{BusType, Channel, MatchingReceiver, Sender};
+use dbus::message::{MatchRule, MessageType};
+use dbus::Message;
+use hickory_proto::op::{Query, ResponseCode};
+use hickory_proto::rr::{DNSClass, LowerName, Name, RData, RecordType};
+use hickory_proto::serialize::binary::{BinEncodable, BinEncoder};
+use hickory_resolver::ResolveError;
+use tokio::runtime::Handle;
+use tracing::{debug, error, info};
+
+use crate::authority::mushroom::Mushroom;
+use crate::config::DbusConfig;
+use crate::lookup::hickory_lookup;
+use crate::split_dns::routing_domain;
+
+/// Bus name of systemd-resolved, which its clients look for
+pub const DEFAULT_BUS_NAME: &str = "org.freedesktop.resolve1";
+
+const PATH: &str = "/org/freedesktop/resolve1";
+const MANAGER: &str = "org.freedesktop.resolve1.Manager";
+
+/// Set in the returned flags when the upstream validated the answer with DNSSEC
+const SD_RESOLVED_AUTHENTICATED: u64 = 1 << 9;
+
+/// How long a call to the bus daemon may take
+const DBUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A D-Bus error reply
+#[derive(Debug, PartialEq, Eq)]
+struct CallError {
+    name: String,
+    message: String,
+}
+
+impl CallError {
+    fn new(name: &str, message: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            message: message.into(),
+        }
+    }
+
+    fn invalid_args(message: impl Into<String>) -> Self {
+        Self::new("org.freedesktop.DBus.Error.InvalidArgs", message)
+    }
+
+    /// The error of an answer with a failure response code, named after the code like
+    /// `org.freedesktop.resolve1.DnsError.NXDOMAIN`
+    fn dns(name: &Name, response_code: ResponseCode) -> Self {
+        let code: String = format!("{response_code:?}")
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .collect();
+        let message = match response_code {
+            ResponseCode::NXDomain => format!("'{}' not found", display(name)),
+            code => format!("Could not resolve '{}': {code}", display(name)),
+        };
+        Self::new(
+            &format!("org.freedesktop.resolve1.DnsError.{}", code.to_uppercase()),
+            message,
+        )
+    }
+}
+
+/// A method call, with its arguments read
+#[derive(Debug, PartialEq, Eq)]
+enum Call {
+    ResolveHostname {
+        ifindex: i32,
+        name: Name,
+        family: i32,
+    },
+    ResolveAddress {
+        ifindex: i32,
+        address: IpAddr,
+    },
+    ResolveRecord {
+        ifindex: i32,
+        name: Name,
+        record_type: RecordType,
+    },
+    SetLinkDns {
+        interface: String,
+        name_servers: Vec<IpAddr>,
+    },
+    SetLinkDomains {
+        interface: String,
+        domains: Vec<LowerName>,
+    },
+    RevertLink {
+        interface: String,
+    },
+    FlushCaches,
+    ResetStatistics,
+}
+
+/// Addresses as ResolveHostname returns them: interface index, address family and bytes
+type Addresses = Vec<(i32, i32, Vec<u8>)>;
+
+/// Records as ResolveRecord returns them: interface index, class, type and wire format
+type Records = Vec<(i32, u16, u16, Vec<u8>)>;
+
+/// What a method call returns
+enum Reply {
+    Hostname(Addresses, String, u64),
+    Address(Vec<(i32, String)>, u64),
+    Record(Records, u64),
+    Empty,
+}
+
+/// Connects to the system bus and claims the configured name. Done before the privileges are
+/// dropped, the bus policy usually only lets root claim it.
+pub fn connect(config: &DbusConfig) -> Result<Connection, dbus::Error> {
+    claim(Channel::get_private(BusType::System)?, &config.name)
+}
+
+fn claim(mut channel: Channel, name: &str) -> Result<Connection, dbus::Error> {
+    // the thread serving the API polls the connection itself
+    channel.set_watch_enabled(true);
+    let connection = Connection::from(channel);
+    match connection.request_name(name, false, true, true)? {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => Ok(connection),
+        _ => Err(dbus::Error::new_failed(&format!("{name} is taken"))),
+    }
+}
+
+/// Answers the method calls on a thread of its own, the lookups run on the runtime
+pub fn serve(connection: Connection, mushroom: Arc<Mushroom>, runtime: Handle) {
+    let spawned = std::thread::Builder::new()
+        .name("resolve1".to_string())
+        .spawn(move || {
+            if let Err(err) = run(connection, mushroom, runtime) {
+                error!("The resolve1 D-Bus API stopped: {err}");
+            }
+        });
+    match spawned {
+        Ok(_) => info!("Serving the resolve1 D-Bus API"),
+        Err(err) => error!("Can't serve the resolve1 D-Bus API: {err}"),
+    }
+}
+
+/// Dispatches the calls as they come in and sends the replies of the lookups as they finish.
+///
+/// Only this thread touches the connection: libdbus holds a lock while waiting for messages, which
+/// would keep other threads from sending until one came in.
+fn run(connection: Connection, mushroom: Arc<Mushroom>, runtime: Handle) -> Result<(), String> {
+    let (replies, finished) = mpsc::channel();
+    let (waker, woken) = UnixDatagram::pair().map_err(|err| err.to_string())?;
+    for socket in [&waker, &woken] {
+        socket
+            .set_nonblocking(true)
+            .map_err(|err| err.to_string())?;
+    }
+    let pending = Pending {
+        replies,
+        waker: Arc::new(waker),
+    };
+    connection.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |call, connection| {
+            receive(connection, call, &mushroom, &runtime, &pending);
+            true
+        }),
+    );
+
+    let bus = connection.channel().watch().fd;
+    loop {
+        let mut fds = [bus, woken.as_raw_fd()].map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        // SAFETY: the array holds as many pollfds as are passed along
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err.to_string());
+            }
+        }
+
+        while connection
+            .process(Duration::ZERO)
+            .map_err(|err| err.to_string())?
+        {}
+        while woken.recv(&mut [0; 16]).is_ok() {}
+        for reply in finished.try_iter() {
+            send(&connection, reply);
+        }
+        connection.channel().flush();
+    }
+}
+
+/// Where the lookups running on the runtime leave their replies
+struct Pending {
+    replies: mpsc::Sender<Message>,
+    /// Wakes the thread up to send the replies
+    waker: Arc<UnixDatagram>,
+}
+
+fn receive(
+    connection: &Connection,
+    call: Message,
+    mushroom: &Arc<Mushroom>,
+    runtime: &Handle,
+    pending: &Pending,
+) {
+    let parsed = read_call(&call).and_then(|parsed| {
+        if parsed.changes_state() {
+            authorize(connection, &call)?;
+        }
+        Ok(parsed)
+    });
+    let parsed = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            send(connection, error_reply(&call, err));
+            return;
+        }
+    };
+    debug!("D-Bus call {:?}", parsed);
+
+    let mushroom = mushroom.clone();
+    let replies = pending.replies.clone();
+    let waker = pending.waker.clone();
+    runtime.spawn(async move {
+        let reply = match answer(&mushroom, parsed).await {
+            Ok(Reply::Hostname(addresses, canonical, flags)) => {
+                call.method_return().append3(addresses, canonical, flags)
+            }
+            Ok(Reply::Address(names, flags)) => call.method_return().append2(names, flags),
+            Ok(Reply::Record(records, flags)) => call.method_return().append2(records, flags),
+            Ok(Reply::Empty) => call.method_return(),
+            Err(err) => error_reply(&call, err),
+        };
+        if replies.send(reply).is_ok() {
+            let _ = waker.send(&[0]);
+        }
+    });
+}
+
+fn send(connection: &Connection, reply: Message) {
+    if connection.send(reply).is_err() {
+        debug!("Can't send a D-Bus reply");
+    }
+}
+
+fn error_reply(call: &Message, err: CallError) -> Message {
+    let message = CString::new(err.message).unwrap_or_default();
+    call.error(&err.name.into(), &message)
+}
+
+/// Reads the method and its arguments off the call
+fn read_call(call: &Message) -> Result<Call, CallError> {
+    if call.msg_type() != MessageType::MethodCall || call.path().as_deref() != Some(PATH) {
+        return Err(CallError::new(
+            "org.freedesktop.DBus.Error.UnknownObject",
+            "Unknown object",
+        ));
+    }
+    let interface = call.interface();
+    let member = call.member();
+    if interface
+        .as_deref()
+        .is_some_and(|interface| interface != MANAGER)
+    {
+        return Err(unknown_method(call));
+    }
+    let invalid = |err: dbus::arg::TypeMismatchError| CallError::invalid_args(err.to_string());
+
+    Ok(match member.as_deref() {
+        Some("ResolveHostname") => {
+            let (ifindex, name, family, _flags): (i32, &str, i32, u64) =
+                call.read4().map_err(invalid)?;
+            if ![libc::AF_UNSPEC, libc::AF_INET, libc::AF_INET6].contains(&family) {
+                return Err(CallError::invalid_args(format!(
+                    "Unknown address family {family}"
+                )));
+            }
+            Call::ResolveHostname {
+                ifindex,
+                name: parse_name(name)?,
+                family,
+            }
+        }
+        Some("ResolveAddress") => {
+            let (ifindex, family, address, _flags): (i32, i32, Vec<u8>, u64) =
+                call.read4().map_err(invalid)?;
+            Call::ResolveAddress {
+                ifindex,
+                address: parse_address(family, address)?,
+            }
+        }
+        Some("ResolveRecord") => {
+            let (ifindex, name, class, record_type, _flags): (i32, &str, u16, u16, u64) =
+                call.read5().map_err(invalid)?;
+            if DNSClass::from(class) != DNSClass::IN {
+                return Err(CallError::invalid_args("Only class IN is supported"));
+            }
+            Call::ResolveRecord {
+                ifindex,
+                name: parse_name(name)?,
+                record_type: RecordType::from(record_type),
+            }
+        }
+        Some("SetLinkDNS") => {
+            let (ifindex, addresses): (i32, Vec<(i32, Vec<u8>)>) = call.read2().map_err(invalid)?;
+            Call::SetLinkDns {
+                interface: interface_name(ifindex)?,
+                name_servers: addresses
+                    .into_iter()
+                    .map(|(family, address)| parse_address(family, address))
+                    .collect::<Result<_, _>>()?,
+            }
+        }
+        Some("SetLinkDomains") => {
+            let (ifindex, domains): (i32, Vec<(&str, bool)>) = call.read2().map_err(invalid)?;
+            let mut routing_domains = vec![];
+            for (domain, _routing_only) in domains {
+                let parsed = routing_domain(domain).map_err(|err| {
+                    CallError::invalid_args(format!("Invalid domain {domain}: {err}"))
+                })?;
+                routing_domains.extend(parsed);
+            }
+            Call::SetLinkDomains {
+                interface: interface_name(ifindex)?,
+                domains: routing_domains,
+            }
+        }
+        Some("RevertLink") => {
+            let ifindex: i32 = call.read1().map_err(invalid)?;
+            Call::RevertLink {
+                interface: interface_name(ifindex)?,
+            }
+        }
+        Some("FlushCaches") => Call::FlushCaches,
+        Some("ResetStatistics") => Call::ResetStatistics,
+        _ => return Err(unknown_method(call)),
+    })
+}
+
+impl Call {
+    /// Whether the call changes what others get answered, which only privileged callers may do
+    fn changes_state(&self) -> bool {
+        matches!(
+            self,
+            Self::SetLinkDns { .. }
+                | Self::SetLinkDomains { .. }
+                | Self::RevertLink { .. }
+                | Self::FlushCaches
+                | Self::ResetStatistics
+        )
+    }
+}
+
+fn unknown_method(call: &Message) -> CallError {
+    CallError::new(
+        "org.freedesktop.DBus.Error.UnknownMethod",
+        format!(
+            "Unknown method {} of interface {}",
+            call.member().as_deref().unwrap_or_default(),
+            call.interface().as_deref().unwrap_or_default()
+        ),
+    )
+}
+
+/// Lets root, and the user we run as, change state
+fn authorize(connection: &Connection, call: &Message) -> Result<(), CallError> {
+    let denied = || CallError::new("org.freedesktop.DBus.Error.AccessDenied", "Access denied");
+    let sender = call.sender().ok_or_else(denied)?;
+    let bus = connection.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        DBUS_TIMEOUT,
+    );
+    let (uid,): (u32,) = bus
+        .method_call("org.freedesktop.DBus", "GetConnectionUnixUser", (&*sender,))
+        .map_err(|err| CallError::new("org.freedesktop.DBus.Error.Failed", err.to_string()))?;
+    // SAFETY: geteuid can't fail
+    if uid == 0 || uid == unsafe { libc::geteuid() } {
+        Ok(())
+    } else {
+        Err(denied())
+    }
+}
+
+fn parse_name(name: &str) -> Result<Name, CallError> {
+    let mut parsed = Name::from_str(name)
+        .map_err(|err| CallError::invalid_args(format!("Invalid name {name}: {err}")))?;
+    parsed.set_fqdn(true);
+    Ok(parsed)
+}
+
+fn parse_address(family: i32, address: Vec<u8>) -> Result<IpAddr, CallError> {
+    match family {
+        libc::AF_INET => <[u8; 4]>::try_from(address).ok().map(IpAddr::from),
+        libc::AF_INET6 => <[u8; 16]>::try_from(address).ok().map(IpAddr::from),
+        _ => None,
+    }
+    .ok_or_else(|| CallError::invalid_args(format!("Invalid address of family {family}")))
+}
+
+/// Name of the interface with the index
+fn interface_name(ifindex: i32) -> Result<String, CallError> {
+    let no_such_link = || {
+        CallError::new(
+            "org.freedesktop.resolve1.NoSuchLink",
+            format!("Link {ifindex} not known"),
+        )
+    };
+    let index = u32::try_from(ifindex)
+        .ok()
+        .filter(|index| *index > 0)
+        .ok_or_else(no_such_link)?;
+    let mut buffer = [0 as libc::c_char; libc::IF_NAMESIZE];
+    // SAFETY: the buffer holds IF_NAMESIZE bytes, as if_indextoname needs
+    let name = unsafe { libc::if_indextoname(index, buffer.as_mut_ptr()) };
+    if name.is_null() {
+        return Err(no_such_link());
+    }
+    // SAFETY: if_indextoname wrote a nul terminated name into the buffer
+    let name = unsafe { CStr::from_ptr(buffer.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// The name the way resolved shows it, without the trailing dot
+fn display(name: &Name) -> String {
+    let name = name.to_string();
+    match name.strip_suffix('.') {
+        Some(stripped) if !stripped.is_empty() => stripped.to_string(),
+        _ => name,
+    }
+}
+
+async fn answer(mushroom: &Mushroom, call: Call) -> Result<Reply, CallError> {
+    match call {
+        Call::ResolveHostname {
+            ifindex,
+            name,
+            family,
+        } => resolve_hostname(mushroom, ifindex, name, family).await,
+        Call::ResolveAddress { ifindex, address } => {
+            let name = Name::from(address);
+            let message = lookup(mushroom, ifindex, &name, RecordType::PTR).await?;
+            let names: Vec<(i32, String)> = message
+                .answers()
+                .iter()
+                .filter_map(|record| match record.data() {
+                    RData::PTR(ptr) => Some((ifindex, display(&ptr.0))),
+                    _ => None,
+                })
+                .collect();
+            if names.is_empty() {
+                return Err(no_such_rr(&name));
+            }
+            Ok(Reply::Address(names, flags(&message)))
+        }
+        Call::ResolveRecord {
+            ifindex,
+            name,
+            record_type,
+        } => {
+            let message = lookup(mushroom, ifindex, &name, record_type).await?;
+            let mut records = vec![];
+            for record in message.answers() {
+                if record_type != RecordType::ANY && record.record_type() != record_type {
+                    continue;
+                }
+                let mut wire = vec![];
+                let mut encoder = BinEncoder::new(&mut wire);
+                encoder.set_canonical_names(true);
+                record.emit(&mut encoder).map_err(|err| {
+                    CallError::new("org.freedesktop.resolve1.InvalidReply", err.to_string())
+                })?;
+                records.push((
+                    ifindex,
+                    u16::from(record.dns_class()),
+                    u16::from(record.record_type()),
+                    wire,
+                ));
+            }
+            if records.is_empty() {
+                return Err(no_such_rr(&name));
+            }
+            Ok(Reply::Record(records, flags(&message)))
+        }
+        Call::SetLinkDns {
+            interface,
+            name_servers,
+        } => {
+            info!(
+                "Nameservers of {interface} set to {:?} over D-Bus",
+                name_servers
+            );
+            mushroom
+                .split_dns
+                .set_name_servers(&interface, name_servers);
+            Ok(Reply::Empty)
+        }
+        Call::SetLinkDomains { interface, domains } => {
+            info!("Domains of {interface} set to {:?} over D-Bus", domains);
+            mushroom.split_dns.set_domains(&interface, domains);
+            Ok(Reply::Empty)
+        }
+        Call::RevertLink { interface } => {
+            info!("Reverting the DNS of {interface} over D-Bus");
+            mushroom.split_dns.revert(&interface);
+            Ok(Reply::Empty)
+        }
+        Call::FlushCaches => {
+            mushroom.flush_caches();
+            Ok(Reply::Empty)
+        }
+        Call::ResetStatistics => {
+            mushroom.reset_statistics();
+            Ok(Reply::Empty)
+        }
+    }
+}
+
+/// Looks up the addresses of the family, or of both families
+async fn resolve_hostname(
+    mushroom: &Mushroom,
+    ifindex: i32,
+    name: Name,
+    family: i32,
+) -> Result<Reply, CallError> {
+    let (a, aaaa) = match family {
+        libc::AF_INET => (
+            Some(lookup(mushroom, ifindex, &name, RecordType::A).await),
+            None,
+        ),
+        libc::AF_INET6 => (
+            None,
+            Some(lookup(mushroom, ifindex, &name, RecordType::AAAA).await),
+        ),
+        _ => {
+            let (a, aaaa) = tokio::join!(
+                lookup(mushroom, ifindex, &name, RecordType::A),
+                lookup(mushroom, ifindex, &name, RecordType::AAAA)
+            );
+            (Some(a), Some(aaaa))
+        }
+    };
+
+    let mut addresses = vec![];
+    let mut canonical = name.clone();
+    let mut flags = SD_RESOLVED_AUTHENTICATED;
+    let mut first_error = None;
+    for result in [a, aaaa].into_iter().flatten() {
+        let message = match result {
+            Ok(message) => message,
+            Err(err) => {
+                first_error.get_or_insert(err);
+                continue;
+            }
+        };
+        for record in message.answers() {
+            match record.data() {
+                RData::A(a) => addresses.push((ifindex, libc::AF_INET, a.0.octets().to_vec())),
+                RData::AAAA(aaaa) => {
+                    addresses.push((ifindex, libc::AF_INET6, aaaa.0.octets().to_vec()))
+                }
+                RData::CNAME(cname) if *record.name() == canonical => canonical = cname.0.clone(),
+                _ => {}
+            }
+        }
+        flags &= self::flags(&message);
+    }
+    if addresses.is_empty() {
+        return Err(first_error.unwrap_or_else(|| no_such_rr(&name)));
+    }
+    Ok(Reply::Hostname(addresses, display(&canonical), flags))
+}
+
+/// Looks the name up on the link with the index, or through the routes and split DNS like queries
+/// to the listeners when it is 0. Answers with a failure response code are errors.
+async fn lookup(
+    mushroom: &Mushroom,
+    ifindex: i32,
+    name: &Name,
+    record_type: RecordType,
+) -> Result<hickory_proto::op::Message, CallError> {
+    let result: Result<_, ResolveError> = if ifindex == 0 {
+        hickory_lookup(mushroom, &LowerName::from(name), record_type, false)
+            .await
+            .0
+    } else {
+        let interface = interface_name(ifindex)?;
+        let upstream = mushroom.split_dns.upstream(&interface).ok_or_else(|| {
+            CallError::new(
+                "org.freedesktop.resolve1.NoNameServers",
+                format!("No nameservers on {interface}"),
+            )
+        })?;
+        upstream
+            .lookup(Query::query(name.clone(), record_type), false)
+            .await
+    };
+    let message = result
+        .map_err(|err| CallError::new("org.freedesktop.DBus.Error.Failed", err.to_string()))?;
+    match message.response_code() {
+        ResponseCode::NoError => Ok(message),
+        code => Err(CallError::dns(name, code)),
+    }
+}
+
+fn no_such_rr(name: &Name) -> CallError {
+    CallError::new(
+        "org.freedesktop.resolve1.NoSuchRR",
+        format!(
+            "'{}' does not have any RR of the requested type",
+            display(name)
+        ),
+    )
+}
+
+fn flags(message: &hickory_proto::op::Message) -> u64 {
+    if message.authentic_data() {
+        SD_RESOLVED_AUTHENTICATED
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::network::NetworkLinks;
+    use crate::private_bus::PrivateBus;
+    use crate::store::forwarder::ForwardConfig;
+    use dbus::blocking::Proxy;
+    use hickory_proto::op::Message as DnsMessage;
+    use hickory_proto::rr::rdata::{CNAME, PTR, TXT};
+    use hickory_proto::rr::Record;
+    use hickory_resolver::config::{NameServerConfig, NameServerConfigGroup};
+    use hickory_resolver::proto::xfer::Protocol;
+    use std::collections::BTreeMap;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    /// The records of `host.example.`, which `alias.example.` is a CNAME of
+    fn host_records(record_type: RecordType) -> Vec<Record> {
+        let data = match record_type {
+            RecordType::A => RData::A(Ipv4Addr::new(10, 9, 9, 9).into()),
+            RecordType::AAAA => RData::AAAA(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 9).into()),
+            RecordType::TXT => RData::TXT(TXT::new(vec!["hello".to_string()])),
+            _ => return vec![],
+        };
+        vec![Record::from_rdata(name("host.example."), 60, data)]
+    }
+
+    /// An upstream answering for `host.example.`, `alias.example.` and `10.9.9.9`, counting the
+    /// queries it gets
+    fn fake_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counted = queries.clone();
+        std::thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            while let Ok((len, peer)) = socket.recv_from(&mut buffer) {
+                counted.fetch_add(1, Ordering::Relaxed);
+                let request = DnsMessage::from_vec(&buffer[..len]).unwrap();
+                let query = request.queries()[0].clone();
+                let mut response = DnsMessage::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(hickory_proto::op::MessageType::Response)
+                    .set_recursion_available(true)
+                    .add_query(query.clone());
+                match query.name().to_string().as_str() {
+                    "host.example." => {
+                        response.add_answers(host_records(query.query_type()));
+                    }
+                    "alias.example." => {
+                        let cname = RData::CNAME(CNAME(name("host.example.")));
+                        response.add_answer(Record::from_rdata(query.name().clone(), 60, cname));
+                        response.add_answers(host_records(query.query_type()));
+                    }
+                    "9.9.9.10.in-addr.arpa." => {
+                        let ptr = RData::PTR(PTR(name("host.example.")));
+                        response.add_answer(Record::from_rdata(query.name().clone(), 60, ptr));
+                    }
+                    _ => {
+                        response.set_response_code(ResponseCode::NXDomain);
+                    }
+                }
+                socket.send_to(&response.to_vec().unwrap(), peer).unwrap();
+            }
+        });
+        (address, queries)
+    }
+
+    fn method_call(method: &str) -> Message {
+        Message::new_method_call(DEFAULT_BUS_NAME, PATH, MANAGER, method).unwrap()
+    }
+
+    #[test]
+    fn test_read_call() {
+        let call = method_call("ResolveHostname").append3(2i32, "Host.Example", libc::AF_INET6);
+        assert_eq!(
+            read_call(&call.append1(0u64)),
+            Ok(Call::ResolveHostname {
+                ifindex: 2,
+                name: name("Host.Example."),
+                family: libc::AF_INET6,
+            })
+        );
+        let call = method_call("ResolveAddress").append2(0i32, libc::AF_INET);
+        assert_eq!(
+            read_call(&call.append2(vec![192u8, 168, 1, 1], 0u64)),
+            Ok(Call::ResolveAddress {
+                ifindex: 0,
+                address: IpAddr::from([192, 168, 1, 1]),
+            })
+        );
+        let call = method_call("ResolveAddress").append2(0i32, libc::AF_INET6);
+        let err = read_call(&call.append2(vec![192u8, 168, 1, 1], 0u64)).unwrap_err();
+        assert_eq!(err.name, "org.freedesktop.DBus.Error.InvalidArgs");
+        let call = method_call("ResolveRecord").append3(0i32, "host.example.", 3u16);
+        let err = read_call(&call.append2(16u16, 0u64)).unwrap_err();
+        assert_eq!(err.name, "org.freedesktop.DBus.Error.InvalidArgs");
+
+        // the domains of a link that doesn't exist
+        let call = method_call("SetLinkDomains").append2(i32::MAX, vec![("corp.example", true)]);
+        let err = read_call(&call).unwrap_err();
+        assert_eq!(err.name, "org.freedesktop.resolve1.NoSuchLink");
+        let call = method_call("SetLinkDomains").append2(1i32, vec![("bad..domain", true)]);
+        let err = read_call(&call).unwrap_err();
+        assert_eq!(err.name, "org.freedesktop.DBus.Error.InvalidArgs");
+
+        let err = read_call(&method_call("ResolveService")).unwrap_err();
+        assert_eq!(err.name, "org.freedesktop.DBus.Error.UnknownMethod");
+        let call = Message::new_method_call(DEFAULT_BUS_NAME, "/", MANAGER, "FlushCaches").unwrap();
+        let err = read_call(&call).unwrap_err();
+        assert_eq!(err.name, "org.freedesktop.DBus.Error.UnknownObject");
+    }
+
+    #[test]
+    fn test_error_names() {
+        let err = CallError::dns(&name("missing.example."), ResponseCode::NXDomain);
+        assert_eq!(err.name, "org.freedesktop.resolve1.DnsError.NXDOMAIN");
+        assert_eq!(err.message, "'missing.example' not found");
+        let err = CallError::dns(&name("host.example."), ResponseCode::ServFail);
+        assert_eq!(err.name, "org.freedesktop.resolve1.DnsError.SERVFAIL");
+        let err = CallError::dns(&name("host.example."), ResponseCode::Unknown(3841));
+        assert!(dbus::strings::ErrorName::new(err.name).is_ok());
+    }
+
+    #[test]
+    fn test_resolve1() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("no dbus-daemon, skipping");
+            return;
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+
+        let (upstream, queries) = fake_upstream();
+        let config = Config {
+            upstream: ForwardConfig {
+                presets: vec![],
+                name_servers: NameServerConfigGroup::from(vec![NameServerConfig::new(
+                    upstream,
+                    Protocol::Udp,
+                )]),
+                options: None,
+            },
+            strategies: BTreeMap::new(),
+            routes: vec![],
+            ..Config::default()
+        };
+        // there is no NetworkManager on the private bus, so no links either
+        let links = NetworkLinks::on_bus(Some(bus.address.clone()));
+        assert!(links.current().is_empty());
+        let mushroom = Arc::new(Mushroom::with_links(&config, links).unwrap());
+        let mut channel = Channel::open_private(&bus.address).unwrap();
+        channel.register().unwrap();
+        let service = claim(channel, DEFAULT_BUS_NAME).unwrap();
+        serve(service, mushroom.clone(), runtime.handle().clone());
+
+        let client = bus.connect();
+        let proxy = Proxy::new(DEFAULT_BUS_NAME, PATH, DBUS_TIMEOUT, &client);
+        let resolve = |host: &str| -> Result<(Addresses, String, u64), dbus::Error> {
+            proxy.method_call(
+                MANAGER,
+                "ResolveHostname",
+                (0i32, host, libc::AF_UNSPEC, 0u64),
+            )
+        };
+
+        let (addresses, canonical, _) = resolve("alias.example").unwrap();
+        assert_eq!(
+            addresses,
+            vec![
+                (0, libc::AF_INET, vec![10, 9, 9, 9]),
+                (
+                    0,
+                    libc::AF_INET6,
+                    Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 9).octets().to_vec()
+                ),
+            ]
+        );
+        assert_eq!(canonical, "host.example");
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+
+        // answered from the cache the listeners use, until it is flushed
+        assert!(resolve("alias.example").is_ok());
+        let alias = LowerName::from(name("alias.example."));
+        let (result, _) = runtime.block_on(hickory_lookup(&mushroom, &alias, RecordType::A, false));
+        assert_eq!(result.unwrap().answers().len(), 2);
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+        let () = proxy.method_call(MANAGER, "FlushCaches", ()).unwrap();
+        assert!(resolve("alias.example").is_ok());
+        assert_eq!(queries.load(Ordering::Relaxed), 4);
+
+        let err = resolve("missing.example").unwrap_err();
+        assert_eq!(
+            err.name(),
+            Some("org.freedesktop.resolve1.DnsError.NXDOMAIN")
+        );
+
+        let (names, _): (Vec<(i32, String)>, u64) = proxy
+            .method_call(
+                MANAGER,
+                "ResolveAddress",
+                (0i32, libc::AF_INET, vec![10u8, 9, 9, 9], 0u64),
+            )
+            .unwrap();
+        assert_eq!(names, vec![(0, "host.example".to_string())]);
+
+        let (records, _): (Records, u64) = proxy
+            .method_call(
+                MANAGER,
+                "ResolveRecord",
+                (0i32, "host.example", 1u16, 16u16, 0u64),
+            )
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        let record =
+            <Record as hickory_proto::serialize::binary::BinDecodable>::from_bytes(&records[0].3)
+                .unwrap();
+        assert_eq!(record, host_records(RecordType::TXT)[0]);
+        let err = proxy
+            .method_call::<(Records, u64), _, _, _>(
+                MANAGER,
+                "ResolveRecord",
+                (0i32, "host.example", 1u16, 15u16, 0u64),
+            )
+            .unwrap_err();
+        assert_eq!(err.name(), Some("org.freedesktop.resolve1.NoSuchRR"));
+
+        // the links set over D-Bus are those of split DNS
+        let loopback = interface_name(1).unwrap();
+        let git = LowerName::from(name("git.corp.example."));
+        let () = proxy
+            .method_call(
+                MANAGER,
+                "SetLinkDNS",
+                (1i32, vec![(libc::AF_INET, vec![127u8, 0, 0, 2])]),
+            )
+            .unwrap();
+        let () = proxy
+            .method_call(
+                MANAGER,
+                "SetLinkDomains",
+                (1i32, vec![("corp.example", true)]),
+            )
+            .unwrap();
+        let (interface, upstream) = mushroom.split_dns.route(&git).unwrap();
+        assert_eq!(interface, loopback);
+        assert_eq!(
+            upstream.name_servers().next().unwrap().socket_addr,
+            "127.0.0.2:53".parse().unwrap()
+        );
+        let () = proxy.method_call(MANAGER, "RevertLink", (1i32,)).unwrap();
+        assert!(mushroom.split_dns.route(&git).is_none());
+
+        let () = proxy.method_call(MANAGER, "ResetStatistics", ()).unwrap();
+    }
+}
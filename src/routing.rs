@@ -0,0 +1,363 @@
+//! Routing of queries to the chain of strategies that should answer them
+
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{LowerName, Name, RecordType};
+use hickory_proto::{ProtoError, ProtoErrorKind};
+use hickory_resolver::ResolveError;
+use serde::{Deserialize, Deserializer};
+
+use crate::store::forwarder::{ForwardConfig, NameServerPreset};
+
+/// Name of the strategy that answers everything no route matches, backed by `upstream`
+pub const DEFAULT_STRATEGY: &str = "default";
+
+/// A route sending queries below a domain suffix through a chain of strategies
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Domain suffix this route matches, e.g. `nordvpn.com.` matches `nordvpn.com.` and all names below it
+    pub suffix: Name,
+    /// Only match queries of these types, all types are matched when empty
+    #[serde(default)]
+    pub query_types: Vec<RecordType>,
+    /// Names of the strategies answering matching queries, tried in order
+    pub strategies: Vec<String>,
+    /// Response codes on which the next strategy is tried, on top of SERVFAIL and timeouts
+    #[serde(default, deserialize_with = "deserialize_response_codes")]
+    pub fall_through_rcodes: Vec<ResponseCode>,
+    /// Whether an NXDOMAIN answer is final, or the next strategy should be tried. Defaults to true.
+    #[serde(default = "default_nxdomain_ends_chain")]
+    pub nxdomain_ends_chain: bool,
+}
+
+fn default_nxdomain_ends_chain() -> bool {
+    true
+}
+
+/// Reads response codes by their mnemonic, e.g. `REFUSED` or `NotImp`
+fn deserialize_response_codes<'de, D>(deserializer: D) -> Result<Vec<ResponseCode>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| {
+            (0..=u16::from(ResponseCode::BADCOOKIE))
+                .map(<ResponseCode as From<u16>>::from)
+                .find(|code| format!("{code:?}").eq_ignore_ascii_case(name))
+                .ok_or_else(|| serde::de::Error::custom(format!("unknown response code {name}")))
+        })
+        .collect()
+}
+
+/// A way of answering queries that routes can point to
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum StrategyConfig {
+    /// Forward to a specific set of upstream nameservers
+    Upstream(ForwardConfig),
+    /// Forward to the nameservers the network handed out, as known by NetworkManager, optionally
+    /// only those of some of its connections
+    Network {
+        /// Nameservers used when the network didn't provide any, defaults to plain Google DNS
+        #[serde(default = "default_network_fallback")]
+        fallback: Vec<NameServerPreset>,
+        /// Only the nameservers of connections on this interface, e.g. `wg0`
+        #[serde(default)]
+        interface: Option<String>,
+        /// Only the nameservers of the connection with this ID, e.g. `Work VPN`
+        #[serde(default)]
+        connection: Option<String>,
+        /// Only the nameservers of VPN connections
+        #[serde(default)]
+        vpn: bool,
+    },
+    /// Answer from a local zone file
+    Zone {
+        /// Origin of the zone, e.g. `home.arpa.`
+        origin: Name,
+        /// Path to the zone file
+        zone_file_path: String,
+    },
+}
+
+fn default_network_fallback() -> Vec<NameServerPreset> {
+    vec![NameServerPreset::Google]
+}
+
+/// Picks the chain of strategies for a query from the configured routes
+#[derive(Debug)]
+pub struct Router {
+    routes: Vec<Route>,
+    default_chain: Chain,
+}
+
+#[derive(Debug)]
+struct Route {
+    suffix: LowerName,
+    query_types: Vec<RecordType>,
+    chain: Chain,
+}
+
+/// The strategies a query is sent through, and when to move on to the next one
+#[derive(Debug)]
+pub struct Chain {
+    /// Names of the strategies, in the order they are tried
+    pub strategies: Vec<String>,
+    fall_through_rcodes: Vec<ResponseCode>,
+    nxdomain_ends_chain: bool,
+}
+
+impl Chain {
+    /// Whether the next strategy should be tried after an answer with this response code.
+    ///
+    /// SERVFAIL always falls through, NOERROR (including NODATA) never does.
+    pub fn falls_through(&self, response_code: ResponseCode) -> bool {
+        match response_code {
+            ResponseCode::NoError => false,
+            ResponseCode::NXDomain => !self.nxdomain_ends_chain,
+            ResponseCode::ServFail => true,
+            code => self.fall_through_rcodes.contains(&code),
+        }
+    }
+
+    /// Whether the next strategy should be tried after this error.
+    ///
+    /// Failures that didn't produce an answer, like timeouts, always fall through.
+    pub fn falls_through_error(&self, err: &ResolveError) -> bool {
+        match err.proto().map(ProtoError::kind) {
+            Some(ProtoErrorKind::NoRecordsFound { response_code, .. }) => {
+                self.falls_through(*response_code)
+            }
+            _ => true,
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new(&[])
+    }
+}
+
+impl Router {
+    /// Creates a new Router from the route configs
+    pub fn new(routes: &[RouteConfig]) -> Self {
+        Self {
+            routes: routes
+                .iter()
+                .map(|route| Route {
+                    suffix: LowerName::from(&route.suffix),
+                    query_types: route.query_types.clone(),
+                    chain: Chain {
+                        strategies: route.strategies.clone(),
+                        fall_through_rcodes: route.fall_through_rcodes.clone(),
+                        nxdomain_ends_chain: route.nxdomain_ends_chain,
+                    },
+                })
+                .collect(),
+            default_chain: Chain {
+                strategies: vec![DEFAULT_STRATEGY.to_string()],
+                fall_through_rcodes: vec![],
+                nxdomain_ends_chain: true,
+            },
+        }
+    }
+
+    /// Returns the chain of strategies that should answer the query.
+    ///
+    /// The route with the longest matching suffix wins, on equal suffixes a route restricted to
+    /// query types is preferred. Without any matching route this is just [`DEFAULT_STRATEGY`].
+    pub fn route(&self, name: &LowerName, query_type: RecordType) -> &Chain {
+        self.routes
+            .iter()
+            .filter(|route| route.suffix.zone_of(name))
+            .filter(|route| route.query_types.is_empty() || route.query_types.contains(&query_type))
+            .max_by_key(|route| (route.suffix.num_labels(), !route.query_types.is_empty()))
+            .map_or(&self.default_chain, |route| &route.chain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn route(suffix: &str, query_types: &[RecordType], strategy: &str) -> RouteConfig {
+        RouteConfig {
+            suffix: Name::from_str(suffix).unwrap(),
+            query_types: query_types.to_vec(),
+            strategies: vec![strategy.to_string()],
+            fall_through_rcodes: vec![],
+            nxdomain_ends_chain: true,
+        }
+    }
+
+    fn lower(name: &str) -> LowerName {
+        LowerName::from(Name::from_str(name).unwrap())
+    }
+
+    #[test]
+    fn test_no_routes() {
+        let router = Router::default();
+        assert_eq!(
+            router
+                .route(&lower("example.com."), RecordType::A)
+                .strategies[0],
+            DEFAULT_STRATEGY
+        );
+    }
+
+    #[test]
+    fn test_longest_suffix() {
+        let router = Router::new(&[
+            route("com.", &[], "tld"),
+            route("corp.example.com.", &[], "corp"),
+            route("example.com.", &[], "example"),
+        ]);
+
+        assert_eq!(
+            router
+                .route(&lower("a.corp.example.com."), RecordType::A)
+                .strategies[0],
+            "corp"
+        );
+        assert_eq!(
+            router
+                .route(&lower("corp.example.com."), RecordType::A)
+                .strategies[0],
+            "corp"
+        );
+        assert_eq!(
+            router
+                .route(&lower("www.example.com."), RecordType::A)
+                .strategies[0],
+            "example"
+        );
+        assert_eq!(
+            router
+                .route(&lower("example.org."), RecordType::A)
+                .strategies[0],
+            DEFAULT_STRATEGY
+        );
+    }
+
+    #[test]
+    fn test_label_boundaries() {
+        let router = Router::new(&[route("nordvpn.com.", &[], "network")]);
+
+        assert_eq!(
+            router
+                .route(&lower("NordVPN.com."), RecordType::A)
+                .strategies[0],
+            "network"
+        );
+        assert_eq!(
+            router
+                .route(&lower("evilnordvpn.com."), RecordType::A)
+                .strategies[0],
+            DEFAULT_STRATEGY
+        );
+    }
+
+    #[test]
+    fn test_query_types() {
+        let router = Router::new(&[
+            route("example.com.", &[], "any"),
+            route("example.com.", &[RecordType::TXT], "txt"),
+            route("www.example.com.", &[RecordType::AAAA], "www-aaaa"),
+        ]);
+
+        assert_eq!(
+            router
+                .route(&lower("example.com."), RecordType::TXT)
+                .strategies[0],
+            "txt"
+        );
+        assert_eq!(
+            router
+                .route(&lower("example.com."), RecordType::A)
+                .strategies[0],
+            "any"
+        );
+        assert_eq!(
+            router
+                .route(&lower("www.example.com."), RecordType::AAAA)
+                .strategies[0],
+            "www-aaaa"
+        );
+        assert_eq!(
+            router
+                .route(&lower("www.example.com."), RecordType::A)
+                .strategies[0],
+            "any"
+        );
+    }
+
+    fn nx_error(response_code: ResponseCode) -> ResolveError {
+        let query = hickory_proto::op::Query::query(Name::root(), RecordType::A);
+        ProtoError::nx_error(
+            Box::new(query),
+            None,
+            None,
+            None,
+            response_code,
+            false,
+            None,
+        )
+        .into()
+    }
+
+    #[test]
+    fn test_falls_through() {
+        let chain = Router::new(&[]).default_chain;
+
+        assert!(chain.falls_through_error(&ProtoError::from(ProtoErrorKind::Timeout).into()));
+        assert!(chain.falls_through_error(&nx_error(ResponseCode::ServFail)));
+        assert!(!chain.falls_through_error(&nx_error(ResponseCode::NXDomain)));
+        assert!(chain.falls_through(ResponseCode::ServFail));
+        assert!(!chain.falls_through(ResponseCode::Refused));
+        assert!(!chain.falls_through(ResponseCode::NXDomain));
+        assert!(!chain.falls_through(ResponseCode::NoError));
+
+        let chain = Chain {
+            strategies: vec![],
+            fall_through_rcodes: vec![ResponseCode::Refused],
+            nxdomain_ends_chain: false,
+        };
+        assert!(chain.falls_through(ResponseCode::Refused));
+        assert!(chain.falls_through(ResponseCode::NXDomain));
+        assert!(!chain.falls_through(ResponseCode::NoError));
+    }
+
+    #[test]
+    fn test_route_config() {
+        #[derive(Deserialize)]
+        struct Routes {
+            routes: Vec<RouteConfig>,
+        }
+
+        let routes: Routes = toml::from_str(
+            r#"
+            [[routes]]
+            suffix = "example.com."
+            strategies = ["tls", "network"]
+            fall_through_rcodes = ["REFUSED", "NotImp"]
+            nxdomain_ends_chain = false
+        "#,
+        )
+        .unwrap();
+        let route = &routes.routes[0];
+        assert_eq!(route.strategies, vec!["tls", "network"]);
+        assert_eq!(
+            route.fall_through_rcodes,
+            vec![ResponseCode::Refused, ResponseCode::NotImp]
+        );
+        assert!(!route.nxdomain_ends_chain);
+
+        assert!(toml::from_str::<Routes>(
+            "[[routes]]\nsuffix = \".\"\nstrategies = []\nfall_through_rcodes = [\"NOPE\"]"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! JSON flavour of DNS-over-HTTPS, as served by Google and Cloudflare at `/resolve`
+//!
+//! A query like `/resolve?name=example.com&type=AAAA` is turned into a regular DNS message, and
+//! the response message is rendered as JSON:
+//!
+//! ```json
+//! {"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,
+//!  "Question":[{"name":"example.com.","type":28}],
+//!  "Answer":[{"name":"example.com.","type":28,"TTL":300,"data":"2606:2800:21f:cb07:6820:80da:af6b:8b2c"}]}
+//! ```
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use hickory_proto::op::{Edns, Message, Query};
+use hickory_proto::rr::{Name, Record, RecordType};
+use hickory_proto::serialize::binary::BinEncodable;
+use http::StatusCode;
+
+use crate::server::doh::DohError;
+
+/// Builds the DNS message asked for by the `name`, `type`, `do` and `cd` parameters
+pub(crate) fn query_message(params: &str) -> Result<Vec<u8>, DohError> {
+    let mut name = None;
+    let mut record_type = RecordType::A;
+    let mut dnssec_ok = false;
+    let mut checking_disabled = false;
+
+    for (key, value) in form_urlencoded::parse(params.as_bytes()) {
+        match &*key {
+            "name" => {
+                name = Some(Name::from_utf8(&*value).map_err(|_| {
+                    DohError::new(StatusCode::BAD_REQUEST, "name isn't a domain name")
+                })?)
+            }
+            "type" => record_type = parse_type(&value)?,
+            "do" => dnssec_ok = parse_flag(&value)?,
+            "cd" => checking_disabled = parse_flag(&value)?,
+            _ => {}
+        }
+    }
+    let mut name = name.ok_or(DohError::new(StatusCode::BAD_REQUEST, "no name parameter"))?;
+    name.set_fqdn(true);
+
+    let mut message = Message::new();
+    message
+        .add_query(Query::query(name, record_type))
+        .set_recursion_desired(true)
+        .set_checking_disabled(checking_disabled);
+    if dnssec_ok {
+        let mut edns = Edns::new();
+        edns.set_dnssec_ok(true);
+        message.set_edns(edns);
+    }
+    message
+        .to_bytes()
+        .map_err(|_| DohError::new(StatusCode::BAD_REQUEST, "query couldn't be encoded"))
+}
+
+/// A record type by name like `AAAA`, or by number like `28`
+fn parse_type(value: &str) -> Result<RecordType, DohError> {
+    value
+        .parse::<u16>()
+        .map(RecordType::from)
+        .or_else(|_| RecordType::from_str(&value.to_ascii_uppercase()))
+        .map_err(|_| DohError::new(StatusCode::BAD_REQUEST, "unknown record type"))
+}
+
+fn parse_flag(value: &str) -> Result<bool, DohError> {
+    match value {
+        "" | "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(DohError::new(StatusCode::BAD_REQUEST, "flags must be 0 or 1")),
+    }
+}
+
+/// Renders a response message as JSON
+pub(crate) fn render(message: &Message) -> String {
+    let mut json = String::with_capacity(512);
+    let _ = write!(
+        json,
+        r#"{{"Status":{},"TC":{},"RD":{},"RA":{},"AD":{},"CD":{}"#,
+        u16::from(message.response_code()),
+        message.truncated(),
+        message.recursion_desired(),
+        message.recursion_available(),
+        message.authentic_data(),
+        message.checking_disabled(),
+    );
+
+    json.push_str(r#","Question":["#);
+    for (i, query) in message.queries().iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str(r#"{"name":"#);
+        push_string(&mut json, &query.name().to_string());
+        let _ = write!(json, r#","type":{}}}"#, u16::from(query.query_type()));
+    }
+    json.push(']');
+
+    for (section, records) in [
+        ("Answer", message.answers()),
+        ("Authority", message.name_servers()),
+        ("Additional", message.additionals()),
+    ] {
+        if records.is_empty() {
+            continue;
+        }
+        let _ = write!(json, r#","{section}":["#);
+        for (i, record) in records.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_record(&mut json, record);
+        }
+        json.push(']');
+    }
+
+    json.push('}');
+    json
+}
+
+fn push_record(json: &mut String, record: &Record) {
+    json.push_str(r#"{"name":"#);
+    push_string(json, &record.name().to_string());
+    let _ = write!(
+        json,
+        r#","type":{},"TTL":{},"data":"#,
+        u16::from(record.record_type()),
+        record.ttl()
+    );
+    push_string(json, &record.data().to_string());
+    json.push('}');
+}
+
+/// Appends `value` as a quoted JSON string
+fn push_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{MessageType, ResponseCode};
+    use hickory_proto::rr::rdata::{A, TXT};
+    use hickory_proto::rr::RData;
+
+    #[test]
+    fn test_query_message() {
+        let message =
+            Message::from_vec(&query_message("name=example.com&type=aaaa&do=1&cd=0").unwrap())
+                .unwrap();
+        let query = &message.queries()[0];
+        assert_eq!(query.name(), &Name::from_ascii("example.com.").unwrap());
+        assert_eq!(query.query_type(), RecordType::AAAA);
+        assert!(message.recursion_desired());
+        assert!(!message.checking_disabled());
+        assert!(message.extensions().as_ref().unwrap().flags().dnssec_ok);
+
+        let message = Message::from_vec(&query_message("name=example.com.&type=16").unwrap())
+            .unwrap();
+        assert_eq!(message.queries()[0].query_type(), RecordType::TXT);
+        assert!(message.extensions().is_none());
+
+        let message = Message::from_vec(&query_message("name=example.com").unwrap()).unwrap();
+        assert_eq!(message.queries()[0].query_type(), RecordType::A);
+
+        for params in ["type=A", "name=example.com&type=BOGUS", "name=example.com&do=yes"] {
+            assert_eq!(
+                query_message(params).unwrap_err().status,
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_recursion_desired(true)
+            .set_recursion_available(true)
+            .set_response_code(ResponseCode::NoError)
+            .add_query(Query::query(name.clone(), RecordType::A))
+            .add_answer(Record::from_rdata(
+                name.clone(),
+                300,
+                RData::A(A::new(93, 184, 215, 14)),
+            ))
+            .add_answer(Record::from_rdata(
+                name,
+                60,
+                RData::TXT(TXT::new(vec!["say \"hi\"\n".to_string()])),
+            ));
+
+        assert_eq!(
+            render(&message),
+            concat!(
+                r#"{"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,"#,
+                r#""Question":[{"name":"example.com.","type":1}],"#,
+                r#""Answer":[{"name":"example.com.","type":1,"TTL":300,"data":"93.184.215.14"},"#,
+                r#"{"name":"example.com.","type":16,"TTL":60,"data":"say \"hi\"\n"}]}"#,
+            )
+        );
+    }
+}
@@ -0,0 +1,592 @@
+//! Requests and responses of DNS-over-HTTPS, [RFC 8484](https://www.rfc-editor.org/rfc/rfc8484)
+
+use std::io;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use data_encoding::BASE64URL_NOPAD;
+use hickory_proto::op::{Message, ResponseCode};
+use hickory_proto::rr::RData;
+use http::header::{
+    ACCEPT, ALLOW, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, WWW_AUTHENTICATE,
+};
+use http::{Method, Request, Response, StatusCode, Version};
+
+use crate::config::DOH_JSON_PATH;
+use crate::server::dns_json;
+
+/// Media type of a DNS message in wire format
+pub(crate) const MIME_APPLICATION_DNS: &str = "application/dns-message";
+
+/// Media type of the JSON API
+pub(crate) const MIME_APPLICATION_DNS_JSON: &str = "application/dns-json";
+
+/// Largest DNS message a request may carry
+pub(crate) const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Clients allowed to query a listener, by the token they authenticate with.
+///
+/// A client presents its token as `Authorization: Bearer <token>`, or as the last segment of a
+/// secret path like `/dns-query/<token>` for clients which can't set headers. Without any clients,
+/// the listener is open to everyone [`AccessControl`](crate::access::AccessControl) allows.
+#[derive(Clone, Debug, Default)]
+pub struct DohClients {
+    /// Name and token of each client
+    clients: Vec<(Arc<str>, String)>,
+}
+
+impl DohClients {
+    /// Clients from pairs of name and token
+    pub fn new(clients: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self {
+            clients: clients
+                .into_iter()
+                .map(|(name, token)| (Arc::from(name), token))
+                .collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Name of the client with the token `token`.
+    ///
+    /// Every token is compared in full, so the time taken doesn't tell how much of a token was
+    /// guessed right.
+    fn authenticate(&self, token: &str) -> Option<Arc<str>> {
+        let mut found = None;
+        for (name, client_token) in &self.clients {
+            if constant_time_eq(client_token.as_bytes(), token.as_bytes()) {
+                found = Some(name.clone());
+            }
+        }
+        found
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Where the DNS message of a request is
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum DohRequest {
+    /// Decoded from the `dns` parameter of a GET request
+    Get(Vec<u8>),
+    /// In the body of a POST request, which is still to be read
+    Post,
+    /// Built from the parameters of a JSON API request, answered in JSON
+    Json(Vec<u8>),
+}
+
+impl DohRequest {
+    /// How the response to the request is encoded
+    pub(crate) fn format(&self) -> ResponseFormat {
+        match self {
+            Self::Get(_) | Self::Post => ResponseFormat::Wire,
+            Self::Json(_) => ResponseFormat::Json,
+        }
+    }
+}
+
+/// Encoding of the DNS message in a response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ResponseFormat {
+    /// `application/dns-message`, the message as sent over UDP
+    Wire,
+    /// `application/dns-json`, see [`dns_json`]
+    Json,
+}
+
+/// Why a request can't be answered with a DNS message, sent back as the HTTP status
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DohError {
+    pub(crate) status: StatusCode,
+    pub(crate) reason: &'static str,
+}
+
+impl DohError {
+    pub(crate) fn new(status: StatusCode, reason: &'static str) -> Self {
+        Self { status, reason }
+    }
+
+    /// An empty response with the status of the error
+    pub(crate) fn response(&self, version: Version) -> Response<()> {
+        let mut response = Response::builder()
+            .status(self.status)
+            .version(version)
+            .header(CONTENT_LENGTH, 0);
+        match self.status {
+            StatusCode::METHOD_NOT_ALLOWED => response = response.header(ALLOW, "GET, POST"),
+            StatusCode::UNAUTHORIZED => response = response.header(WWW_AUTHENTICATE, "Bearer"),
+            _ => {}
+        }
+        response.body(()).expect("valid error response")
+    }
+}
+
+/// The response carrying the DNS message `message`, and its body in `format`.
+///
+/// HTTP caches may keep it as long as the records in it stay valid, see [`max_age`].
+pub(crate) fn dns_response(
+    version: Version,
+    format: ResponseFormat,
+    message: Vec<u8>,
+) -> io::Result<(Response<()>, Bytes)> {
+    let parsed = Message::from_vec(&message)?;
+    let cache_control = match max_age(&parsed) {
+        Some(max_age) => format!("max-age={max_age}"),
+        None => "no-store".to_string(),
+    };
+    let (content_type, body) = match format {
+        ResponseFormat::Wire => (MIME_APPLICATION_DNS, Bytes::from(message)),
+        ResponseFormat::Json => (
+            MIME_APPLICATION_DNS_JSON,
+            Bytes::from(dns_json::render(&parsed)),
+        ),
+    };
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .version(version)
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, body.len())
+        .header(CACHE_CONTROL, cache_control)
+        .body(())
+        .expect("valid dns response");
+    Ok((response, body))
+}
+
+/// Seconds a response may be cached, as recommended by RFC 8484 section 5.1: the smallest TTL of
+/// the answers, or the negative caching TTL of the SOA (RFC 2308) for an answer without records.
+///
+/// Failures and answers without a TTL to go by must not be cached.
+fn max_age(message: &Message) -> Option<u32> {
+    if !matches!(
+        message.response_code(),
+        ResponseCode::NoError | ResponseCode::NXDomain
+    ) || message.truncated()
+    {
+        return None;
+    }
+    if let Some(ttl) = message.answers().iter().map(|record| record.ttl()).min() {
+        return Some(ttl);
+    }
+    message
+        .name_servers()
+        .iter()
+        .find_map(|record| match record.data() {
+            RData::SOA(soa) => Some(record.ttl().min(soa.minimum())),
+            _ => None,
+        })
+}
+
+/// Checks that the request is a DNS query for `endpoint` or [`DOH_JSON_PATH`], and finds its
+/// message and the name of the client which sent it.
+///
+/// With `dns_hostname` set, requests for any other host are rejected as misdirected. With
+/// `clients`, requests without a token are unauthorized (401) and ones with an unknown token are
+/// forbidden (403).
+pub(crate) fn parse_request<T>(
+    dns_hostname: Option<&str>,
+    endpoint: &str,
+    clients: &DohClients,
+    request: &Request<T>,
+) -> Result<(DohRequest, Option<Arc<str>>), DohError> {
+    let uri = request.uri();
+    let (json, secret) = split_path(endpoint, uri.path())
+        .ok_or(DohError::new(StatusCode::NOT_FOUND, "unknown path"))?;
+    if let Some(dns_hostname) = dns_hostname {
+        if uri.host().is_some_and(|host| host != dns_hostname) {
+            return Err(DohError::new(
+                StatusCode::MISDIRECTED_REQUEST,
+                "unknown host",
+            ));
+        }
+    }
+    let client_name = authenticate(clients, secret, request)?;
+    let doh_request = if json {
+        parse_json_request(request)?
+    } else {
+        parse_message_request(request)?
+    };
+    Ok((doh_request, client_name))
+}
+
+/// Whether `path` is the JSON API rather than `endpoint`, and the secret segment after either of
+/// them
+fn split_path<'a>(endpoint: &str, path: &'a str) -> Option<(bool, Option<&'a str>)> {
+    [(endpoint, false), (DOH_JSON_PATH, true)]
+        .into_iter()
+        .find_map(|(base, json)| match path.strip_prefix(base)? {
+            "" => Some((json, None)),
+            rest => Some((json, Some(rest.strip_prefix('/')?))),
+        })
+}
+
+/// Name of the client whose token is the secret path segment, or the bearer token of the request
+fn authenticate<T>(
+    clients: &DohClients,
+    secret: Option<&str>,
+    request: &Request<T>,
+) -> Result<Option<Arc<str>>, DohError> {
+    if clients.is_empty() {
+        return match secret {
+            Some(_) => Err(DohError::new(StatusCode::NOT_FOUND, "unknown path")),
+            None => Ok(None),
+        };
+    }
+    let token = secret
+        .or_else(|| bearer_token(request))
+        .ok_or(DohError::new(StatusCode::UNAUTHORIZED, "no token"))?;
+    clients
+        .authenticate(token)
+        .map(Some)
+        .ok_or(DohError::new(StatusCode::FORBIDDEN, "unknown token"))
+}
+
+/// The token of an `Authorization: Bearer` header
+fn bearer_token<T>(request: &Request<T>) -> Option<&str> {
+    let authorization = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("Bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+/// A GET or POST request with a message in wire format
+fn parse_message_request<T>(request: &Request<T>) -> Result<DohRequest, DohError> {
+    let uri = request.uri();
+    if !accepts(request, MIME_APPLICATION_DNS) {
+        return Err(DohError::new(
+            StatusCode::NOT_ACCEPTABLE,
+            "client doesn't accept application/dns-message",
+        ));
+    }
+
+    match *request.method() {
+        Method::GET => {
+            let dns = uri
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="))
+                .ok_or(DohError::new(StatusCode::BAD_REQUEST, "no dns parameter"))?;
+            // the padding must be left out, but some clients send it anyway
+            BASE64URL_NOPAD
+                .decode(dns.trim_end_matches('=').as_bytes())
+                .map(DohRequest::Get)
+                .map_err(|_| DohError::new(StatusCode::BAD_REQUEST, "dns parameter isn't base64url"))
+        }
+        Method::POST => {
+            if media_type(request.headers().get(CONTENT_TYPE)) != Some(MIME_APPLICATION_DNS) {
+                return Err(DohError::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "body isn't application/dns-message",
+                ));
+            }
+            let content_length = request
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+            if content_length.is_some_and(|length| length > MAX_MESSAGE_SIZE) {
+                return Err(DohError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "body is larger than a dns message can be",
+                ));
+            }
+            Ok(DohRequest::Post)
+        }
+        _ => Err(DohError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "only GET and POST are supported",
+        )),
+    }
+}
+
+/// A GET request of the JSON API, its parameters are checked by [`dns_json::query_message`]
+fn parse_json_request<T>(request: &Request<T>) -> Result<DohRequest, DohError> {
+    if request.method() != Method::GET {
+        return Err(DohError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "only GET is supported",
+        ));
+    }
+    if !accepts(request, MIME_APPLICATION_DNS_JSON) && !accepts(request, "application/json") {
+        return Err(DohError::new(
+            StatusCode::NOT_ACCEPTABLE,
+            "client doesn't accept application/dns-json",
+        ));
+    }
+    dns_json::query_message(request.uri().query().unwrap_or_default()).map(DohRequest::Json)
+}
+
+/// Whether the Accept header allows `mime`, no header accepts everything
+fn accepts<T>(request: &Request<T>, mime: &str) -> bool {
+    let Some(accept) = request.headers().get(ACCEPT) else {
+        return true;
+    };
+    let Ok(accept) = accept.to_str() else {
+        return false;
+    };
+    accept
+        .split(',')
+        .map(|media| media.split(';').next().unwrap_or_default().trim())
+        .any(|media| media == mime || matches!(media, "application/*" | "*/*"))
+}
+
+/// The media type of a Content-Type header, without its parameters
+fn media_type(content_type: Option<&http::HeaderValue>) -> Option<&str> {
+    let content_type = content_type?.to_str().ok()?;
+    Some(content_type.split(';').next().unwrap_or_default().trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, uri: &str) -> http::request::Builder {
+        Request::builder().method(method).uri(uri)
+    }
+
+    fn parse<T>(request: &Request<T>) -> Result<DohRequest, DohError> {
+        parse_request(None, "/dns-query", &DohClients::default(), request)
+            .map(|(request, _)| request)
+    }
+
+    #[test]
+    fn test_get() {
+        // the example query of RFC 8484 section 4.1.1
+        let request = request(
+            Method::GET,
+            "https://dns.example.com/dns-query?dns=AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB",
+        )
+        .header(ACCEPT, MIME_APPLICATION_DNS)
+        .body(())
+        .unwrap();
+        let Ok(DohRequest::Get(message)) = parse(&request) else {
+            panic!("GET request not accepted");
+        };
+        assert_eq!(&message[..4], &[0, 0, 1, 0]);
+        assert_eq!(message.len(), 33);
+
+        let request = self::request(Method::GET, "/dns-query?ct&dns=AAAB====")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            parse(&request),
+            Ok(DohRequest::Get(vec![0, 0, 1]))
+        );
+
+        for uri in ["/dns-query", "/dns-query?dns=!!"] {
+            let request = self::request(Method::GET, uri).body(()).unwrap();
+            assert_eq!(
+                parse(&request).unwrap_err().status,
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[test]
+    fn test_post() {
+        let request = request(Method::POST, "/dns-query")
+            .header(CONTENT_TYPE, MIME_APPLICATION_DNS)
+            .header(ACCEPT, "application/*;q=0.9")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            parse(&request),
+            Ok(DohRequest::Post)
+        );
+
+        let request = self::request(Method::POST, "/dns-query")
+            .header(CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            parse(&request).unwrap_err().status,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+
+        let request = self::request(Method::POST, "/dns-query")
+            .header(CONTENT_TYPE, MIME_APPLICATION_DNS)
+            .header(CONTENT_LENGTH, 70000)
+            .body(())
+            .unwrap();
+        assert_eq!(
+            parse(&request).unwrap_err().status,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let request = request(Method::GET, "/resolve?name=example.com&type=AAAA")
+            .header(ACCEPT, "application/json")
+            .body(())
+            .unwrap();
+        let request = parse(&request).unwrap();
+        assert_eq!(request.format(), ResponseFormat::Json);
+
+        let request = self::request(Method::POST, "/resolve").body(()).unwrap();
+        assert_eq!(
+            parse(&request).unwrap_err().status,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+
+        let request = self::request(Method::GET, "/resolve?name=example.com")
+            .header(ACCEPT, MIME_APPLICATION_DNS)
+            .body(())
+            .unwrap();
+        assert_eq!(
+            parse(&request).unwrap_err().status,
+            StatusCode::NOT_ACCEPTABLE
+        );
+    }
+
+    #[test]
+    fn test_max_age() {
+        use hickory_proto::op::{MessageType, Query};
+        use hickory_proto::rr::rdata::{A, SOA};
+        use hickory_proto::rr::{Name, Record, RecordType};
+
+        let name = Name::from_ascii("www.example.com.").unwrap();
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .add_query(Query::query(name.clone(), RecordType::A));
+        let soa = Record::from_rdata(
+            Name::from_ascii("example.com.").unwrap(),
+            3600,
+            RData::SOA(SOA::new(
+                Name::from_ascii("ns.example.com.").unwrap(),
+                Name::from_ascii("hostmaster.example.com.").unwrap(),
+                1,
+                3600,
+                600,
+                86400,
+                300,
+            )),
+        );
+
+        // no records to go by
+        assert_eq!(max_age(&message), None);
+
+        let mut negative = message.clone();
+        negative
+            .set_response_code(ResponseCode::NXDomain)
+            .add_name_server(soa);
+        assert_eq!(max_age(&negative), Some(300));
+
+        let mut answered = message.clone();
+        answered
+            .add_answer(Record::from_rdata(name.clone(), 60, RData::A(A::new(192, 0, 2, 1))))
+            .add_answer(Record::from_rdata(name, 3600, RData::A(A::new(192, 0, 2, 2))));
+        assert_eq!(max_age(&answered), Some(60));
+
+        answered.set_response_code(ResponseCode::ServFail);
+        assert_eq!(max_age(&answered), None);
+
+        let (response, _) =
+            dns_response(Version::HTTP_2, ResponseFormat::Wire, negative.to_vec().unwrap())
+                .unwrap();
+        assert_eq!(response.headers()[CACHE_CONTROL], "max-age=300");
+    }
+
+    #[test]
+    fn test_rejected() {
+        let status = |request: Request<()>| {
+            parse_request(
+                Some("dns.example.com"),
+                "/dns-query",
+                &DohClients::default(),
+                &request,
+            )
+            .unwrap_err()
+                .status
+        };
+
+        assert_eq!(
+            status(request(Method::GET, "/query?dns=AAAB").body(()).unwrap()),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(
+                request(Method::GET, "https://other.example.com/dns-query?dns=AAAB")
+                    .body(())
+                    .unwrap()
+            ),
+            StatusCode::MISDIRECTED_REQUEST
+        );
+        assert_eq!(
+            status(
+                request(Method::GET, "/dns-query?dns=AAAB")
+                    .header(ACCEPT, "text/html")
+                    .body(())
+                    .unwrap()
+            ),
+            StatusCode::NOT_ACCEPTABLE
+        );
+        assert_eq!(
+            status(request(Method::PUT, "/dns-query").body(()).unwrap()),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        // secret paths don't exist without clients
+        assert_eq!(
+            status(request(Method::GET, "/dns-query/s3cret?dns=AAAB").body(()).unwrap()),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let clients = DohClients::new([
+            ("alice".to_string(), "alice-token".to_string()),
+            ("bob".to_string(), "bob-token".to_string()),
+        ]);
+        let parse = |request: Request<()>| {
+            parse_request(None, "/dns-query", &clients, &request).map(|(_, name)| name)
+        };
+
+        let request = request(Method::GET, "/dns-query?dns=AAAB")
+            .header(AUTHORIZATION, "bearer bob-token")
+            .body(())
+            .unwrap();
+        assert_eq!(parse(request), Ok(Some(Arc::from("bob"))));
+        let request = self::request(Method::GET, "/dns-query/alice-token?dns=AAAB")
+            .body(())
+            .unwrap();
+        assert_eq!(parse(request), Ok(Some(Arc::from("alice"))));
+        let request = self::request(Method::GET, "/resolve/alice-token?name=example.com")
+            .body(())
+            .unwrap();
+        assert_eq!(parse(request), Ok(Some(Arc::from("alice"))));
+
+        let request = self::request(Method::GET, "/dns-query?dns=AAAB")
+            .header(AUTHORIZATION, "Basic YWxpY2U6cGFzcw==")
+            .body(())
+            .unwrap();
+        let err = parse(request).unwrap_err();
+        assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            err.response(Version::HTTP_2).headers()[WWW_AUTHENTICATE],
+            "Bearer"
+        );
+
+        for request in [
+            self::request(Method::GET, "/dns-query?dns=AAAB")
+                .header(AUTHORIZATION, "Bearer alice-tokem")
+                .body(())
+                .unwrap(),
+            self::request(Method::GET, "/dns-query/alice?dns=AAAB")
+                .body(())
+                .unwrap(),
+        ] {
+            assert_eq!(parse(request).unwrap_err().status, StatusCode::FORBIDDEN);
+        }
+    }
+}
@@ -5,34 +5,58 @@
 // https://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use std::{io, net::SocketAddr, sync::Arc};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
 use bytes::{Bytes, BytesMut};
 use futures_util::lock::Mutex;
-use h2::server;
-use hickory_proto::{http::Version, rr::Record};
+use h2::{server, RecvStream};
+use hickory_proto::{rr::Record, xfer::Protocol};
+use http::{Request, StatusCode, Version};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{self, Instant};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
 
 use crate::{
     access::AccessControl,
     authority::MessageResponse,
-    hickory_proto::h2::h2_server,
-    hickory_proto::xfer::Protocol,
     server::{
-        request_handler::RequestHandler, response_handler::ResponseHandler, server_future,
-        ResponseInfo,
+        doh::{self, DohClients, DohError, DohRequest, ResponseFormat},
+        request_handler::{ClientIdentity, RequestHandler},
+        response_handler::ResponseHandler,
+        server_future, ResponseInfo,
     },
 };
 
+/// Limits of the HTTP/2 connections of the DNS-over-HTTPS listener
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Http2Limits {
+    /// Most requests a client may have open at once on one connection
+    pub max_concurrent_streams: u32,
+    /// How long a connection without open requests is kept before it is closed
+    pub idle_timeout: Duration,
+}
+
+impl Default for Http2Limits {
+    fn default() -> Self {
+        Self {
+            max_concurrent_streams: 100,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn h2_handler<T, I>(
     access: Arc<AccessControl>,
     handler: Arc<T>,
     io: I,
     src_addr: SocketAddr,
+    client: ClientIdentity,
     dns_hostname: Option<Arc<str>>,
     http_endpoint: Arc<str>,
+    clients: Arc<DohClients>,
+    limits: Http2Limits,
     shutdown: CancellationToken,
 ) where
     T: RequestHandler,
@@ -42,14 +66,27 @@ pub(crate) async fn h2_handler<T, I>(
     let http_endpoint = http_endpoint.clone();
 
     // Start the HTTP/2.0 connection handshake
-    let mut h2 = match server::handshake(io).await {
-        Ok(h2) => h2,
-        Err(err) => {
+    let handshake = server::Builder::new()
+        .max_concurrent_streams(limits.max_concurrent_streams)
+        .handshake(io);
+    let mut h2 = match time::timeout(limits.idle_timeout, handshake).await {
+        Ok(Ok(h2)) => h2,
+        Ok(Err(err)) => {
             warn!("handshake error from {}: {}", src_addr, err);
             return;
         }
+        Err(_) => {
+            debug!("handshake from {} timed out", src_addr);
+            return;
+        }
     };
 
+    // every request task holds a clone, the connection is idle when this is the only one
+    let in_flight = Arc::new(());
+    let idle = time::sleep(limits.idle_timeout);
+    tokio::pin!(idle);
+    let mut closing = false;
+
     // Accept all inbound HTTP/2.0 streams sent over the
     // connection.
     loop {
@@ -64,23 +101,58 @@ pub(crate) async fn h2_handler<T, I>(
                     return;
                 }
             },
+            _ = &mut idle => {
+                if closing {
+                    // the client didn't close the connection after our GOAWAY
+                    return;
+                }
+                if Arc::strong_count(&in_flight) == 1 {
+                    debug!("closing idle connection from {}", src_addr);
+                    h2.graceful_shutdown();
+                    closing = true;
+                }
+                idle.as_mut().reset(Instant::now() + limits.idle_timeout);
+                continue;
+            },
             _ = shutdown.cancelled() => {
                 // A graceful shutdown was initiated.
                 return
             },
         };
+        idle.as_mut().reset(Instant::now() + limits.idle_timeout);
 
-        debug!("Received request: {:#?}", request);
+        // the path and headers may hold a client's token
+        debug!("Received {} request from {}", request.method(), src_addr);
+        let in_flight = in_flight.clone();
+        let mut client = client.clone();
         let dns_hostname = dns_hostname.clone();
         let http_endpoint = http_endpoint.clone();
+        let clients = clients.clone();
         let handler = handler.clone();
         let access = access.clone();
-        let responder = HttpsResponseHandle(Arc::new(Mutex::new(respond)));
+        let respond = Arc::new(Mutex::new(respond));
 
         tokio::spawn(async move {
-            match h2_server::message_from(dns_hostname, http_endpoint, request).await {
-                Ok(bytes) => handle_request(bytes, src_addr, access, handler, responder).await,
-                Err(err) => warn!("error while handling request from {}: {}", src_addr, err),
+            let _in_flight = in_flight;
+            let message = message_from(
+                dns_hostname.as_deref(),
+                &http_endpoint,
+                &clients,
+                request,
+                &mut client,
+            );
+            match message.await {
+                Ok((bytes, format)) => {
+                    let responder = HttpsResponseHandle { respond, format };
+                    handle_request(bytes, src_addr, client, access, handler, responder).await
+                }
+                Err(err) => {
+                    debug!(
+                        "rejecting request from {}: {} {}",
+                        src_addr, err.status, err.reason
+                    );
+                    send_error(&respond, &err).await;
+                }
             };
         });
 
@@ -88,9 +160,47 @@ pub(crate) async fn h2_handler<T, I>(
     }
 }
 
+/// Reads the DNS message from the query parameter of a GET, or the body of a POST request, and
+/// finds the format of the response. The name of an authenticated client is added to `client`.
+async fn message_from(
+    dns_hostname: Option<&str>,
+    http_endpoint: &str,
+    clients: &DohClients,
+    request: Request<RecvStream>,
+    client: &mut ClientIdentity,
+) -> Result<(BytesMut, ResponseFormat), DohError> {
+    let (doh_request, client_name) =
+        doh::parse_request(dns_hostname, http_endpoint, clients, &request)?;
+    client.name = client_name;
+    let format = doh_request.format();
+    match doh_request {
+        DohRequest::Get(message) | DohRequest::Json(message) => {
+            Ok((BytesMut::from(&message[..]), format))
+        }
+        DohRequest::Post => {
+            let mut body = request.into_body();
+            let mut bytes = BytesMut::with_capacity(512);
+            while let Some(data) = body.data().await {
+                let data = data
+                    .map_err(|_| DohError::new(StatusCode::BAD_REQUEST, "body couldn't be read"))?;
+                let _ = body.flow_control().release_capacity(data.len());
+                bytes.extend_from_slice(&data);
+                if bytes.len() > doh::MAX_MESSAGE_SIZE {
+                    return Err(DohError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "body is larger than a dns message can be",
+                    ));
+                }
+            }
+            Ok((bytes, format))
+        }
+    }
+}
+
 async fn handle_request<T>(
     bytes: BytesMut,
     src_addr: SocketAddr,
+    client: ClientIdentity,
     access: Arc<AccessControl>,
     handler: Arc<T>,
     responder: HttpsResponseHandle,
@@ -101,6 +211,7 @@ async fn handle_request<T>(
         &bytes,
         src_addr,
         Protocol::Https,
+        client,
         access,
         handler,
         responder,
@@ -108,8 +219,19 @@ async fn handle_request<T>(
     .await
 }
 
+/// Answers with the HTTP status of the error instead of a DNS message
+async fn send_error(respond: &Mutex<server::SendResponse<Bytes>>, err: &DohError) {
+    let response = err.response(Version::HTTP_2);
+    if let Err(err) = respond.lock().await.send_response(response, true) {
+        debug!("failed to send error response: {}", err);
+    }
+}
+
 #[derive(Clone)]
-struct HttpsResponseHandle(Arc<Mutex<server::SendResponse<Bytes>>>);
+struct HttpsResponseHandle {
+    respond: Arc<Mutex<server::SendResponse<Bytes>>>,
+    format: ResponseFormat,
+}
 
 #[async_trait::async_trait]
 impl ResponseHandler for HttpsResponseHandle {
@@ -123,9 +245,10 @@ impl ResponseHandler for HttpsResponseHandle {
             impl Iterator<Item = &'a Record> + Send + 'a,
             impl Iterator<Item = &'a Record> + Send + 'a,
         >,
+        _millis: u128,
+        _ipv6_enabled: bool,
     ) -> io::Result<ResponseInfo> {
         use hickory_proto::h2::HttpsError;
-        use hickory_proto::http::response;
         use hickory_proto::serialize::binary::BinEncoder;
 
         let mut bytes = Vec::with_capacity(512);
@@ -134,12 +257,11 @@ impl ResponseHandler for HttpsResponseHandle {
             let mut encoder = BinEncoder::new(&mut bytes);
             response.destructive_emit(&mut encoder)?
         };
-        let bytes = Bytes::from(bytes);
-        let response = response::new(Version::Http2, bytes.len())?;
+        let (response, bytes) = doh::dns_response(Version::HTTP_2, self.format, bytes)?;
 
         debug!("sending response: {:#?}", response);
         let mut stream = self
-            .0
+            .respond
             .lock()
             .await
             .send_response(response, false)
@@ -7,10 +7,14 @@
 
 use std::{io, net::SocketAddr, sync::Arc};
 
-use bytes::{Buf, Bytes};
+use bytes::{Buf, Bytes, BytesMut};
 use futures_util::lock::Mutex;
-use h3::server::RequestStream;
+use h3::server::{Connection, RequestStream};
 use h3_quinn::BidiStream;
+use http::{Request, StatusCode, Version};
+use quinn::{crypto::rustls::QuicServerConfig, Endpoint, EndpointConfig, TransportConfig, VarInt};
+use rustls::ServerConfig;
+use tokio::net;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
 
@@ -18,24 +22,51 @@ use crate::{
     access::AccessControl,
     authority::MessageResponse,
     server::{
-        request_handler::RequestHandler, response_handler::ResponseHandler, server_future,
-        ResponseInfo,
+        doh::{self, DohClients, DohError, DohRequest, ResponseFormat},
+        request_handler::{ClientIdentity, RequestHandler},
+        response_handler::ResponseHandler,
+        server_future, ResponseInfo,
     },
 };
-use hickory_proto::{
-    h3::{h3_server::H3Connection, H3Error},
-    http::Version,
-    rr::Record,
-    xfer::Protocol,
-    ProtoError,
-};
+use hickory_proto::{h3::H3Error, rr::Record, xfer::Protocol, ProtoError};
+
+type H3Stream = RequestStream<BidiStream<Bytes>, Bytes>;
+
+/// ALPN protocol of HTTP/3
+pub(crate) const H3_ALPN: &[u8] = b"h3";
+
+/// Binds a QUIC endpoint for DNS-over-HTTP/3 to the socket, `tls_config` must offer [`H3_ALPN`]
+pub(crate) fn h3_endpoint(
+    socket: net::UdpSocket,
+    tls_config: Arc<ServerConfig>,
+) -> io::Result<Endpoint> {
+    let crypto = QuicServerConfig::try_from(tls_config).map_err(io::Error::other)?;
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+    let mut transport_config = TransportConfig::default();
+    transport_config.datagram_receive_buffer_size(None);
+    transport_config.datagram_send_buffer_size(0);
+    // SETTINGS, the QPACK encoder and decoder and a reserved (GREASE) one
+    transport_config.max_concurrent_uni_streams(VarInt::from_u32(4));
+    server_config.transport = Arc::new(transport_config);
+
+    Endpoint::new(
+        EndpointConfig::default(),
+        Some(server_config),
+        socket.into_std()?,
+        Arc::new(quinn::TokioRuntime),
+    )
+}
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn h3_handler<T>(
     access: Arc<AccessControl>,
     handler: Arc<T>,
-    mut connection: H3Connection,
+    mut connection: Connection<h3_quinn::Connection, Bytes>,
     src_addr: SocketAddr,
-    _dns_hostname: Option<Arc<str>>,
+    dns_hostname: Option<Arc<str>>,
+    http_endpoint: Arc<str>,
+    clients: Arc<DohClients>,
     shutdown: CancellationToken,
 ) -> Result<(), ProtoError>
 where
@@ -46,16 +77,16 @@ where
 
     // Accept all inbound requests sent over the connection.
     loop {
-        let (_, mut stream) = tokio::select! {
+        let (request, mut stream) = tokio::select! {
             result = connection.accept() => match result {
-                Some(Ok(next_request)) => next_request,
-                Some(Err(err)) => {
-                    warn!("error accepting request {}: {}", src_addr, err);
-                    return Err(err);
-                }
-                None => {
+                Ok(Some(next_request)) => next_request,
+                Ok(None) => {
                     break;
                 }
+                Err(err) => {
+                    warn!("error accepting request {}: {}", src_addr, err);
+                    return Err(ProtoError::from(format!("h3 request failed: {err}")));
+                }
             },
             _ = shutdown.cancelled() => {
                 // A graceful shutdown was initiated.
@@ -63,32 +94,48 @@ where
             },
         };
 
-        let request = match stream
-            .recv_data()
-            .await
-            .map_err(|e| ProtoError::from(format!("h3 stream receive data failed: {e}")))?
-        {
-            Some(mut request) => request.copy_to_bytes(request.remaining()),
-            None => continue,
-        };
-
-        debug!(
-            "Received bytes {} from {src_addr} {request:?}",
-            request.remaining()
-        );
+        // the path and headers may hold a client's token
+        debug!("Received {} request from {}", request.method(), src_addr);
+        let dns_hostname = dns_hostname.clone();
+        let http_endpoint = http_endpoint.clone();
+        let clients = clients.clone();
         let handler = handler.clone();
         let access = access.clone();
-        let stream = Arc::new(Mutex::new(stream));
-        let responder = H3ResponseHandle(stream.clone());
 
-        tokio::spawn(handle_request(
-            request, src_addr, access, handler, responder,
-        ));
+        tokio::spawn(async move {
+            let mut client = ClientIdentity::default();
+            let message = message_from(
+                dns_hostname.as_deref(),
+                &http_endpoint,
+                &clients,
+                request,
+                &mut stream,
+                &mut client,
+            );
+            match message.await {
+                Ok((bytes, format)) => {
+                    let responder = H3ResponseHandle {
+                        stream: Arc::new(Mutex::new(stream)),
+                        format,
+                    };
+                    handle_request(bytes, src_addr, client, access, handler, responder).await
+                }
+                Err(err) => {
+                    debug!(
+                        "rejecting request from {}: {} {}",
+                        src_addr, err.status, err.reason
+                    );
+                    send_error(&mut stream, &err).await;
+                }
+            };
+        });
 
         max_requests -= 1;
         if max_requests == 0 {
             warn!("exceeded request count, shutting down h3 conn: {src_addr}");
-            connection.shutdown().await?;
+            connection.shutdown(0).await.map_err(|err| {
+                ProtoError::from(format!("h3 connection shutdown failed: {err}"))
+            })?;
             break;
         }
         // we'll continue handling requests from here.
@@ -97,20 +144,82 @@ where
     Ok(())
 }
 
+/// Reads the DNS message from the query parameter of a GET, or the body of a POST request, and
+/// finds the format of the response. The name of an authenticated client is added to `client`.
+async fn message_from(
+    dns_hostname: Option<&str>,
+    http_endpoint: &str,
+    clients: &DohClients,
+    request: Request<()>,
+    stream: &mut H3Stream,
+    client: &mut ClientIdentity,
+) -> Result<(BytesMut, ResponseFormat), DohError> {
+    let (doh_request, client_name) =
+        doh::parse_request(dns_hostname, http_endpoint, clients, &request)?;
+    client.name = client_name;
+    let format = doh_request.format();
+    match doh_request {
+        DohRequest::Get(message) | DohRequest::Json(message) => {
+            Ok((BytesMut::from(&message[..]), format))
+        }
+        DohRequest::Post => {
+            let mut bytes = BytesMut::with_capacity(512);
+            while let Some(mut data) = stream
+                .recv_data()
+                .await
+                .map_err(|_| DohError::new(StatusCode::BAD_REQUEST, "body couldn't be read"))?
+            {
+                bytes.extend_from_slice(&data.copy_to_bytes(data.remaining()));
+                if bytes.len() > doh::MAX_MESSAGE_SIZE {
+                    return Err(DohError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "body is larger than a dns message can be",
+                    ));
+                }
+            }
+            Ok((bytes, format))
+        }
+    }
+}
+
+/// Answers with the HTTP status of the error instead of a DNS message
+async fn send_error(stream: &mut H3Stream, err: &DohError) {
+    let sent = match stream.send_response(err.response(Version::HTTP_3)).await {
+        Ok(()) => stream.finish().await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = sent {
+        debug!("failed to send error response: {}", err);
+    }
+}
+
 async fn handle_request<T>(
-    bytes: Bytes,
+    bytes: BytesMut,
     src_addr: SocketAddr,
+    client: ClientIdentity,
     access: Arc<AccessControl>,
     handler: Arc<T>,
     responder: H3ResponseHandle,
 ) where
     T: RequestHandler,
 {
-    server_future::handle_request(&bytes, src_addr, Protocol::H3, access, handler, responder).await
+    server_future::handle_request(
+        &bytes,
+        src_addr,
+        Protocol::H3,
+        client,
+        access,
+        handler,
+        responder,
+    )
+    .await
 }
 
 #[derive(Clone)]
-struct H3ResponseHandle(Arc<Mutex<RequestStream<BidiStream<Bytes>, Bytes>>>);
+struct H3ResponseHandle {
+    stream: Arc<Mutex<H3Stream>>,
+    format: ResponseFormat,
+}
 
 #[async_trait::async_trait]
 impl ResponseHandler for H3ResponseHandle {
@@ -124,8 +233,9 @@ impl ResponseHandler for H3ResponseHandle {
             impl Iterator<Item = &'a Record> + Send + 'a,
             impl Iterator<Item = &'a Record> + Send + 'a,
         >,
+        _millis: u128,
+        _ipv6_enabled: bool,
     ) -> io::Result<ResponseInfo> {
-        use hickory_proto::http::response;
         use hickory_proto::serialize::binary::BinEncoder;
 
         let mut bytes = Vec::with_capacity(512);
@@ -134,11 +244,10 @@ impl ResponseHandler for H3ResponseHandle {
             let mut encoder = BinEncoder::new(&mut bytes);
             response.destructive_emit(&mut encoder)?
         };
-        let bytes = Bytes::from(bytes);
-        let response = response::new(Version::Http3, bytes.len())?;
+        let (response, bytes) = doh::dns_response(Version::HTTP_3, self.format, bytes)?;
 
         debug!("sending response: {:#?}", response);
-        let mut stream = self.0.lock().await;
+        let mut stream = self.stream.lock().await;
         stream
             .send_response(response)
             .await
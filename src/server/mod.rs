@@ -7,6 +7,10 @@
 
 //! `Server` component for hosting a domain name servers operations.
 
+#[cfg(any(feature = "dns-over-https-rustls", feature = "dns-over-h3"))]
+mod dns_json;
+#[cfg(any(feature = "dns-over-https-rustls", feature = "dns-over-h3"))]
+mod doh;
 #[cfg(feature = "dns-over-https-rustls")]
 mod h2_handler;
 #[cfg(feature = "dns-over-h3")]
@@ -18,7 +22,13 @@ mod response_handler;
 mod server_future;
 mod timeout_stream;
 
-pub use self::request_handler::{Request, RequestHandler, RequestInfo, ResponseInfo};
+#[cfg(any(feature = "dns-over-https-rustls", feature = "dns-over-h3"))]
+pub use self::doh::DohClients;
+#[cfg(feature = "dns-over-https-rustls")]
+pub use self::h2_handler::Http2Limits;
+pub use self::request_handler::{
+    ClientIdentity, Request, RequestHandler, RequestInfo, ResponseInfo,
+};
 pub use self::response_handler::{ResponseHandle, ResponseHandler};
 pub use self::server_future::ServerFuture;
 pub use self::timeout_stream::TimeoutStream;
@@ -9,29 +9,63 @@ use std::{io, net::SocketAddr, sync::Arc};
 
 use bytes::{Bytes, BytesMut};
 use futures_util::lock::Mutex;
+use quinn::{
+    crypto::rustls::QuicServerConfig, Connection, Endpoint, EndpointConfig, RecvStream,
+    SendStream, TransportConfig, VarInt,
+};
+use rustls::ServerConfig;
+use tokio::net;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
 
+use hickory_proto::{quic::DoqErrorCode, rr::Record, xfer::Protocol, ProtoError, ProtoErrorKind};
+
 use crate::{
     access::AccessControl,
     authority::MessageResponse,
-    hickory_proto::{
-        quic::QuicStreams,
-        quic::{DoqErrorCode, QuicStream},
-        rr::Record,
-        xfer::Protocol,
-        ProtoError,
-    },
     server::{
-        request_handler::RequestHandler, response_handler::ResponseHandler, server_future,
-        ResponseInfo,
+        request_handler::{ClientIdentity, RequestHandler},
+        response_handler::ResponseHandler,
+        server_future, ResponseInfo,
     },
 };
 
+/// ALPN protocol of DNS-over-QUIC, RFC 9250
+pub(crate) const DOQ_ALPN: &[u8] = b"doq";
+
+/// Binds a QUIC endpoint for DNS-over-QUIC to the socket, `tls_config` must offer [`DOQ_ALPN`]
+pub(crate) fn quic_endpoint(
+    socket: net::UdpSocket,
+    tls_config: Arc<ServerConfig>,
+) -> io::Result<Endpoint> {
+    let crypto = QuicServerConfig::try_from(tls_config).map_err(io::Error::other)?;
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+    // queries only come in on bidirectional streams, and there are no datagrams
+    let mut transport_config = TransportConfig::default();
+    transport_config.max_concurrent_uni_streams(VarInt::from_u32(0));
+    transport_config.datagram_receive_buffer_size(None);
+    transport_config.datagram_send_buffer_size(0);
+    server_config.transport = Arc::new(transport_config);
+
+    // the same maximum as the QUIC client of hickory
+    let mut endpoint_config = EndpointConfig::default();
+    endpoint_config
+        .max_udp_payload_size(0x45ac)
+        .expect("max udp payload size exceeded");
+
+    Endpoint::new(
+        endpoint_config,
+        Some(server_config),
+        socket.into_std()?,
+        Arc::new(quinn::TokioRuntime),
+    )
+}
+
 pub(crate) async fn quic_handler<T>(
     access: Arc<AccessControl>,
     handler: Arc<T>,
-    mut quic_streams: QuicStreams,
+    connection: Connection,
     src_addr: SocketAddr,
     _dns_hostname: Option<Arc<str>>,
     shutdown: CancellationToken,
@@ -45,14 +79,11 @@ where
     // Accept all inbound quic streams sent over the connection.
     loop {
         let mut request_stream = tokio::select! {
-            result = quic_streams.next() => match result {
-                Some(Ok(next_request)) => next_request,
-                Some(Err(err)) => {
+            result = connection.accept_bi() => match result {
+                Ok((send, receive)) => DoqStream { send, receive },
+                Err(err) => {
                     warn!("error accepting request {}: {}", src_addr, err);
-                    return Err(err);
-                }
-                None => {
-                    break;
+                    return Err(err.into());
                 }
             },
             _ = shutdown.cancelled() => {
@@ -87,6 +118,51 @@ where
     Ok(())
 }
 
+/// The stream of one query and its response. Both are sent with a 2 byte length in front, like
+/// over TCP, RFC 9250 section 4.2.
+struct DoqStream {
+    send: SendStream,
+    receive: RecvStream,
+}
+
+impl DoqStream {
+    async fn receive_bytes(&mut self) -> Result<BytesMut, ProtoError> {
+        let mut len = [0u8; 2];
+        self.receive.read_exact(&mut len).await?;
+        let len = u16::from_be_bytes(len) as usize;
+
+        let mut bytes = BytesMut::zeroed(len);
+        if let Err(err) = self.receive.read_exact(&mut bytes).await {
+            debug!("received bad packet len: {} bytes: {:?}", len, bytes);
+            if self.send.reset(DoqErrorCode::ProtocolError.into()).is_err() {
+                debug!("stream already closed");
+            }
+            return Err(err.into());
+        }
+        Ok(bytes)
+    }
+
+    async fn send_bytes(&mut self, bytes: Bytes) -> Result<(), ProtoError> {
+        let len = u16::try_from(bytes.len())
+            .map_err(|_| ProtoErrorKind::MaxBufferSizeExceeded(bytes.len()))?;
+        let len = Bytes::copy_from_slice(&len.to_be_bytes());
+        self.send.write_all_chunks(&mut [len, bytes]).await?;
+        Ok(())
+    }
+
+    /// There will be no more data sent to the client
+    fn finish(&mut self) -> Result<(), ProtoError> {
+        self.send.finish()?;
+        Ok(())
+    }
+
+    fn stop(&mut self, code: DoqErrorCode) -> Result<(), ProtoError> {
+        self.receive
+            .stop(code.into())
+            .map_err(|_| ProtoError::from(ProtoErrorKind::QuinnUnknownStreamError))
+    }
+}
+
 async fn handle_request<T>(
     bytes: BytesMut,
     src_addr: SocketAddr,
@@ -96,12 +172,20 @@ async fn handle_request<T>(
 ) where
     T: RequestHandler,
 {
-    server_future::handle_request(&bytes, src_addr, Protocol::Quic, access, handler, responder)
-        .await
+    server_future::handle_request(
+        &bytes,
+        src_addr,
+        Protocol::Quic,
+        ClientIdentity::default(),
+        access,
+        handler,
+        responder,
+    )
+    .await
 }
 
 #[derive(Clone)]
-struct QuicResponseHandle(Arc<Mutex<QuicStream>>);
+struct QuicResponseHandle(Arc<Mutex<DoqStream>>);
 
 #[async_trait::async_trait]
 impl ResponseHandler for QuicResponseHandle {
@@ -116,6 +200,8 @@ impl ResponseHandler for QuicResponseHandle {
             impl Iterator<Item = &'a Record> + Send + 'a,
             impl Iterator<Item = &'a Record> + Send + 'a,
         >,
+        _millis: u128,
+        _ipv6_enabled: bool,
     ) -> io::Result<ResponseInfo> {
         use hickory_proto::serialize::binary::BinEncoder;
 
@@ -132,7 +218,7 @@ impl ResponseHandler for QuicResponseHandle {
         debug!("sending quic response: {}", bytes.len());
         let mut lock = self.0.lock().await;
         lock.send_bytes(bytes).await?;
-        lock.finish().await?;
+        lock.finish()?;
 
         Ok(info)
     }
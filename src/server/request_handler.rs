@@ -12,7 +12,17 @@ use hickory_proto::{
     op::{Header, LowerQuery, ResponseCode},
     xfer::Protocol,
 };
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
+
+/// Who a client authenticated as, beyond the address it connected from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// Subject of the certificate the client authenticated with, see [`RequestInfo::client_subject`]
+    pub subject: Option<Arc<str>>,
+    /// Name of the DNS-over-HTTPS client whose token was presented, see
+    /// [`RequestInfo::client_name`]
+    pub name: Option<Arc<str>>,
+}
 
 /// An incoming request to the DNS catalog
 #[derive(Debug)]
@@ -23,17 +33,26 @@ pub struct Request {
     src: SocketAddr,
     /// Protocol of the request
     protocol: Protocol,
+    /// Who the client authenticated as
+    client: ClientIdentity,
 }
 
 impl Request {
-    /// Build a new requests with the inbound message, source address, and protocol.
+    /// Build a new requests with the inbound message, source address, protocol, and who the
+    /// client authenticated as.
     ///
     /// This will return an error on bad verification.
-    pub fn new(message: MessageRequest, src: SocketAddr, protocol: Protocol) -> Self {
+    pub fn new(
+        message: MessageRequest,
+        src: SocketAddr,
+        protocol: Protocol,
+        client: ClientIdentity,
+    ) -> Self {
         Self {
             message,
             src,
             protocol,
+            client,
         }
     }
 
@@ -44,6 +63,8 @@ impl Request {
             protocol: self.protocol,
             header: self.message.header(),
             query: self.message.query(),
+            client_subject: self.client.subject.as_deref(),
+            client_name: self.client.name.as_deref(),
         }
     }
 
@@ -56,6 +77,16 @@ impl Request {
     pub fn protocol(&self) -> Protocol {
         self.protocol
     }
+
+    /// Subject of the client's certificate, see [`RequestInfo::client_subject`]
+    pub fn client_subject(&self) -> Option<&str> {
+        self.client.subject.as_deref()
+    }
+
+    /// Name of the DNS-over-HTTPS client, see [`RequestInfo::client_name`]
+    pub fn client_name(&self) -> Option<&str> {
+        self.client.name.as_deref()
+    }
 }
 
 impl std::ops::Deref for Request {
@@ -79,6 +110,13 @@ pub struct RequestInfo<'a> {
     pub header: &'a Header,
     /// The query from the request
     pub query: &'a LowerQuery,
+    /// Subject of the certificate the client authenticated with over mutual TLS, as an RFC 4514
+    /// string like `CN=laptop.example.com,O=Example`. `None` when the listener doesn't ask for
+    /// client certificates.
+    pub client_subject: Option<&'a str>,
+    /// Name of the client whose token authenticated a DNS-over-HTTPS request. `None` when the
+    /// listener doesn't require tokens.
+    pub client_name: Option<&'a str>,
 }
 
 impl<'a> RequestInfo<'a> {
@@ -101,6 +139,8 @@ impl<'a> RequestInfo<'a> {
             protocol,
             header,
             query,
+            client_subject: None,
+            client_name: None,
         }
     }
 }
@@ -148,6 +188,18 @@ pub trait RequestHandler: Send + Sync + Unpin + 'static {
     ) -> ResponseInfo;
 }
 
+/// Lets a handler be shared with whatever else answers queries, like the D-Bus API
+#[async_trait::async_trait]
+impl<T: RequestHandler> RequestHandler for Arc<T> {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        (**self).handle_request(request, response_handle).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -79,8 +79,9 @@ impl ResponseHandle {
                 if let Some(edns) = response.get_edns() {
                     edns.max_payload()
                 } else {
-                    // No EDNS, use the recommended max from RFC6891.
-                    hickory_proto::udp::MAX_RECEIVE_BUFFER_SIZE as u16
+                    // No EDNS, the client only takes the 512 bytes of RFC 1035. Anything beyond
+                    // that is cut off with the TC bit set, so the client retries over TCP.
+                    512
                 }
             }
             _ => u16::MAX,
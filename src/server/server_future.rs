@@ -29,10 +29,16 @@ use hickory_proto::openssl::tls_server::*;
 use crate::{
     access::AccessControl,
     authority::{MessageRequest, MessageResponseBuilder},
-    server::{Request, RequestHandler, ResponseHandle, ResponseHandler, TimeoutStream},
+    server::{
+        ClientIdentity, Request, RequestHandler, ResponseHandle, ResponseHandler, TimeoutStream,
+    },
 };
+#[cfg(feature = "dns-over-https-rustls")]
+use crate::server::Http2Limits;
+#[cfg(any(feature = "dns-over-https-rustls", feature = "dns-over-h3"))]
+use crate::server::DohClients;
 use hickory_proto::{
-    op::{Header, LowerQuery, Query, ResponseCode},
+    op::{Header, LowerQuery, OpCode, Query, ResponseCode},
     runtime::iocompat::AsyncIoTokioAsStd,
     serialize::binary::{BinDecodable, BinDecoder},
     tcp::TcpStream,
@@ -137,7 +143,14 @@ impl<T: RequestHandler> ServerFuture<T> {
                     let stream_handle = stream_handle.with_remote_addr(src_addr);
 
                     inner_join_set.spawn(async move {
-                        handle_raw_request(message, Protocol::Udp, access, handler, stream_handle)
+                        handle_raw_request(
+                            message,
+                            Protocol::Udp,
+                            ClientIdentity::default(),
+                            access,
+                            handler,
+                            stream_handle,
+                        )
                             .await;
                     });
 
@@ -238,6 +251,7 @@ impl<T: RequestHandler> ServerFuture<T> {
                         handle_raw_request(
                             message,
                             Protocol::Tcp,
+                            ClientIdentity::default(),
                             access.clone(),
                             handler.clone(),
                             stream_handle.clone(),
@@ -390,6 +404,7 @@ impl<T: RequestHandler> ServerFuture<T> {
                         self::handle_raw_request(
                             message,
                             Protocol::Tls,
+                            ClientIdentity::default(),
                             access.clone(),
                             handler.clone(),
                             stream_handle.clone(),
@@ -512,12 +527,14 @@ impl<T: RequestHandler> ServerFuture<T> {
                     let tls_stream = tls_acceptor.accept(tcp_stream).await;
 
                     let tls_stream = match tls_stream {
-                        Ok(tls_stream) => AsyncIoTokioAsStd(tls_stream),
+                        Ok(tls_stream) => tls_stream,
                         Err(e) => {
                             debug!("tls handshake src: {} error: {}", src_addr, e);
                             return;
                         }
                     };
+                    let client = peer_identity(tls_stream.get_ref().1);
+                    let tls_stream = AsyncIoTokioAsStd(tls_stream);
                     debug!("accepted TLS request from: {}", src_addr);
                     let (buf_stream, stream_handle) = tls_from_stream(tls_stream, src_addr);
                     let mut timeout_stream = TimeoutStream::new(buf_stream, timeout);
@@ -538,6 +555,7 @@ impl<T: RequestHandler> ServerFuture<T> {
                         handle_raw_request(
                             message,
                             Protocol::Tls,
+                            client.clone(),
                             access.clone(),
                             handler.clone(),
                             stream_handle.clone(),
@@ -609,32 +627,64 @@ impl<T: RequestHandler> ServerFuture<T> {
     pub fn register_https_listener(
         &mut self,
         listener: net::TcpListener,
-        // TODO: need to set a timeout between requests.
-        _timeout: Duration,
+        timeout: Duration,
         certificate_and_key: (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>),
         dns_hostname: Option<String>,
         http_endpoint: String,
+    ) -> io::Result<()> {
+        use hickory_proto::rustls::tls_server;
+
+        let tls_acceptor = tls_server::new_acceptor(certificate_and_key.0, certificate_and_key.1)
+            .map_err(|e| io::Error::other(format!("error creating TLS acceptor: {e}")))?;
+
+        self.register_https_listener_with_tls_config(
+            listener,
+            timeout,
+            Arc::new(tls_acceptor),
+            dns_hostname,
+            http_endpoint,
+            DohClients::default(),
+            Http2Limits::default(),
+        )
+    }
+
+    /// Register a TcpListener for HTTPS (h2) to the Server for supporting DoH (dns-over-https),
+    /// with an already built rustls server config. The config must offer `h2` by ALPN.
+    ///
+    /// # Arguments
+    /// * `listener` - a bound TCP (needs to be on a different port from standard TCP connections) socket
+    /// * `handshake_timeout` - timeout of the TLS handshake, connections that don't finish it in
+    ///   time are closed
+    /// * `tls_config` - rustls server config
+    /// * `dns_hostname` - when set, requests for other hosts are rejected
+    /// * `http_endpoint` - path of the DNS queries, like `/dns-query`
+    /// * `clients` - clients that must authenticate with a token, anyone may query without any
+    /// * `limits` - limits of the HTTP/2 connections
+    #[cfg(feature = "dns-over-https-rustls")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn register_https_listener_with_tls_config(
+        &mut self,
+        listener: net::TcpListener,
+        handshake_timeout: Duration,
+        tls_config: Arc<ServerConfig>,
+        dns_hostname: Option<String>,
+        http_endpoint: String,
+        clients: DohClients,
+        limits: Http2Limits,
     ) -> io::Result<()> {
         use tokio_rustls::TlsAcceptor;
 
         use crate::server::h2_handler::h2_handler;
-        use hickory_proto::rustls::tls_server;
 
         let dns_hostname: Option<Arc<str>> = dns_hostname.map(|n| n.into());
         let http_endpoint: Arc<str> = Arc::from(http_endpoint);
+        let clients = Arc::new(clients);
 
         let handler = self.handler.clone();
         let access = self.access.clone();
         debug!("registered https: {listener:?}");
 
-        let tls_acceptor = tls_server::new_acceptor(certificate_and_key.0, certificate_and_key.1)
-            .map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("error creating TLS acceptor: {e}"),
-                )
-            })?;
-        let tls_acceptor = TlsAcceptor::from(Arc::new(tls_acceptor));
+        let tls_acceptor = TlsAcceptor::from(tls_config);
 
         // for each incoming request...
         let shutdown = self.shutdown_token.clone();
@@ -670,30 +720,40 @@ impl<T: RequestHandler> ServerFuture<T> {
                 let tls_acceptor = tls_acceptor.clone();
                 let dns_hostname = dns_hostname.clone();
                 let http_endpoint = http_endpoint.clone();
+                let clients = clients.clone();
 
                 inner_join_set.spawn(async move {
                     debug!("starting HTTPS request from: {src_addr}");
 
-                    // TODO: need to consider timeout of total connect...
                     // take the created stream...
-                    let tls_stream = tls_acceptor.accept(tcp_stream).await;
+                    let tls_stream =
+                        tokio::time::timeout(handshake_timeout, tls_acceptor.accept(tcp_stream))
+                            .await;
 
                     let tls_stream = match tls_stream {
-                        Ok(tls_stream) => tls_stream,
-                        Err(e) => {
+                        Ok(Ok(tls_stream)) => tls_stream,
+                        Ok(Err(e)) => {
                             debug!("https handshake src: {src_addr} error: {e}");
                             return;
                         }
+                        Err(_) => {
+                            debug!("https handshake src: {src_addr} timed out");
+                            return;
+                        }
                     };
                     debug!("accepted HTTPS request from: {src_addr}");
+                    let client = peer_identity(tls_stream.get_ref().1);
 
                     h2_handler(
                         access,
                         handler,
                         tls_stream,
                         src_addr,
+                        client,
                         dns_hostname,
                         http_endpoint,
+                        clients,
+                        limits,
                         shutdown.clone(),
                     )
                         .await;
@@ -715,27 +775,38 @@ impl<T: RequestHandler> ServerFuture<T> {
     /// Register a UdpSocket to the Server for supporting DoQ (dns-over-quic). The UdpSocket should already be bound to either an
     /// IPv6 or an IPv4 address.
     ///
-    /// To make the server more resilient to DOS issues, there is a timeout. Care should be taken
-    ///  to not make this too low depending on use cases.
-    ///
     /// # Arguments
-    /// * `listener` - a bound TCP (needs to be on a different port from standard TCP connections) socket
-    /// * `timeout` - timeout duration of incoming requests, any connection that does not send
-    ///               requests within this time period will be closed. In the future it should be
-    ///               possible to create long-lived queries, but these should be from trusted sources
-    ///               only, this would require some type of whitelisting.
-    /// * `pkcs12` - certificate used to announce to clients
+    /// * `socket` - a bound UDP socket
+    /// * `certificate_and_key` - certificate and key used to announce to clients
+    /// * `dns_hostname` - the name of the server, currently not checked
     #[cfg(feature = "dns-over-quic")]
     pub fn register_quic_listener(
         &mut self,
         socket: net::UdpSocket,
-        // TODO: need to set a timeout between requests.
-        _timeout: Duration,
         certificate_and_key: (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>),
         dns_hostname: Option<String>,
     ) -> io::Result<()> {
-        use crate::server::quic_handler::quic_handler;
-        use hickory_proto::quic::QuicServer;
+        use crate::server::quic_handler::DOQ_ALPN;
+
+        let tls_config = quic_tls_config(certificate_and_key, DOQ_ALPN)?;
+        self.register_quic_listener_with_tls_config(socket, tls_config, dns_hostname)
+    }
+
+    /// Register a UdpSocket to the Server for supporting DoQ (dns-over-quic), with an already
+    /// built rustls server config. The config must be limited to TLS 1.3 and offer `doq` by ALPN.
+    ///
+    /// # Arguments
+    /// * `socket` - a bound UDP socket
+    /// * `tls_config` - rustls server config
+    /// * `dns_hostname` - the name of the server, currently not checked
+    #[cfg(feature = "dns-over-quic")]
+    pub fn register_quic_listener_with_tls_config(
+        &mut self,
+        socket: net::UdpSocket,
+        tls_config: Arc<ServerConfig>,
+        dns_hostname: Option<String>,
+    ) -> io::Result<()> {
+        use crate::server::quic_handler::{quic_endpoint, quic_handler};
 
         let dns_hostname: Option<Arc<str>> = dns_hostname.map(|n| n.into());
 
@@ -743,8 +814,7 @@ impl<T: RequestHandler> ServerFuture<T> {
         let access = self.access.clone();
 
         debug!("registered quic: {:?}", socket);
-        let mut server =
-            QuicServer::with_socket(socket, certificate_and_key.0, certificate_and_key.1)?;
+        let endpoint = quic_endpoint(socket, tls_config)?;
 
         // for each incoming request...
         let shutdown = self.shutdown_token.clone();
@@ -752,20 +822,18 @@ impl<T: RequestHandler> ServerFuture<T> {
             let mut inner_join_set = JoinSet::new();
             loop {
                 let shutdown = shutdown.clone();
-                let (streams, src_addr) = tokio::select! {
-                    result = server.next() => match result {
-                        Ok(Some(c)) => c,
-                        Ok(None) => continue,
-                        Err(e) => {
-                            debug!("error receiving quic connection: {e}");
-                            continue;
-                        }
+                let incoming = tokio::select! {
+                    incoming = endpoint.accept() => match incoming {
+                        Some(incoming) => incoming,
+                        // the endpoint was closed
+                        None => break,
                     },
                     _ = shutdown.cancelled() => {
                         // A graceful shutdown was initiated. Break out of the loop.
                         break;
                     },
                 };
+                let src_addr = incoming.remote_address();
 
                 // verify that the src address is safe for responses
                 // TODO: we're relying the quinn library to actually validate responses before we get here, but this check is still worth doing
@@ -775,6 +843,7 @@ impl<T: RequestHandler> ServerFuture<T> {
                         src_addr = src_addr,
                         e = e
                     );
+                    incoming.refuse();
                     continue;
                 }
 
@@ -783,13 +852,21 @@ impl<T: RequestHandler> ServerFuture<T> {
                 let dns_hostname = dns_hostname.clone();
 
                 inner_join_set.spawn(async move {
+                    // the handshake runs here, so a slow client doesn't hold up the others
+                    let connection = match incoming.await {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            debug!("error receiving quic connection: {e}");
+                            return;
+                        }
+                    };
                     debug!("starting quic stream request from: {src_addr}");
 
                     // TODO: need to consider timeout of total connect...
                     let result = quic_handler(
                         access,
                         handler,
-                        streams,
+                        connection,
                         src_addr,
                         dns_hostname,
                         shutdown.clone(),
@@ -813,36 +890,62 @@ impl<T: RequestHandler> ServerFuture<T> {
     /// Register a UdpSocket to the Server for supporting DoH3 (dns-over-h3). The UdpSocket should already be bound to either an
     /// IPv6 or an IPv4 address.
     ///
-    /// To make the server more resilient to DOS issues, there is a timeout. Care should be taken
-    ///  to not make this too low depending on use cases.
-    ///
     /// # Arguments
-    /// * `listener` - a bound TCP (needs to be on a different port from standard TCP connections) socket
-    /// * `timeout` - timeout duration of incoming requests, any connection that does not send
-    ///               requests within this time period will be closed. In the future it should be
-    ///               possible to create long-lived queries, but these should be from trusted sources
-    ///               only, this would require some type of whitelisting.
-    /// * `pkcs12` - certificate used to announce to clients
+    /// * `socket` - a bound UDP socket
+    /// * `certificate_and_key` - certificate and key used to announce to clients
+    /// * `dns_hostname` - when set, requests for other hosts are rejected
+    /// * `http_endpoint` - path of the DNS queries, like `/dns-query`
+    /// * `clients` - clients that must authenticate with a token, anyone may query without any
     #[cfg(feature = "dns-over-h3")]
     pub fn register_h3_listener(
         &mut self,
         socket: net::UdpSocket,
-        // TODO: need to set a timeout between requests.
-        _timeout: Duration,
         certificate_and_key: (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>),
         dns_hostname: Option<String>,
+        http_endpoint: String,
+        clients: DohClients,
+    ) -> io::Result<()> {
+        use crate::server::h3_handler::H3_ALPN;
+
+        let tls_config = quic_tls_config(certificate_and_key, H3_ALPN)?;
+        self.register_h3_listener_with_tls_config(
+            socket,
+            tls_config,
+            dns_hostname,
+            http_endpoint,
+            clients,
+        )
+    }
+
+    /// Register a UdpSocket to the Server for supporting DoH3 (dns-over-h3), with an already
+    /// built rustls server config. The config must be limited to TLS 1.3 and offer `h3` by ALPN.
+    ///
+    /// # Arguments
+    /// * `socket` - a bound UDP socket
+    /// * `tls_config` - rustls server config
+    /// * `dns_hostname` - when set, requests for other hosts are rejected
+    /// * `http_endpoint` - path of the DNS queries, like `/dns-query`
+    /// * `clients` - clients that must authenticate with a token, anyone may query without any
+    #[cfg(feature = "dns-over-h3")]
+    pub fn register_h3_listener_with_tls_config(
+        &mut self,
+        socket: net::UdpSocket,
+        tls_config: Arc<ServerConfig>,
+        dns_hostname: Option<String>,
+        http_endpoint: String,
+        clients: DohClients,
     ) -> io::Result<()> {
-        use crate::server::h3_handler::h3_handler;
-        use hickory_proto::h3::h3_server::H3Server;
+        use crate::server::h3_handler::{h3_endpoint, h3_handler};
 
         let dns_hostname: Option<Arc<str>> = dns_hostname.map(|n| n.into());
+        let http_endpoint: Arc<str> = Arc::from(http_endpoint);
+        let clients = Arc::new(clients);
 
         let handler = self.handler.clone();
         let access = self.access.clone();
 
         debug!("registered h3: {:?}", socket);
-        let mut server =
-            H3Server::with_socket(socket, certificate_and_key.0, certificate_and_key.1)?;
+        let endpoint = h3_endpoint(socket, tls_config)?;
 
         // for each incoming request...
         let shutdown = self.shutdown_token.clone();
@@ -850,20 +953,18 @@ impl<T: RequestHandler> ServerFuture<T> {
             let mut inner_join_set = JoinSet::new();
             loop {
                 let shutdown = shutdown.clone();
-                let (streams, src_addr) = tokio::select! {
-                    result = server.accept() => match result {
-                        Ok(Some(c)) => c,
-                        Ok(None) => continue,
-                        Err(e) => {
-                            debug!("error receiving h3 connection: {e}");
-                            continue;
-                        }
+                let incoming = tokio::select! {
+                    incoming = endpoint.accept() => match incoming {
+                        Some(incoming) => incoming,
+                        // the endpoint was closed
+                        None => break,
                     },
                     _ = shutdown.cancelled() => {
                         // A graceful shutdown was initiated. Break out of the loop.
                         break;
                     },
                 };
+                let src_addr = incoming.remote_address();
 
                 // verify that the src address is safe for responses
                 // TODO: we're relying the quinn library to actually validate responses before we get here, but this check is still worth doing
@@ -873,23 +974,46 @@ impl<T: RequestHandler> ServerFuture<T> {
                         src_addr = src_addr,
                         e = e
                     );
+                    incoming.refuse();
                     continue;
                 }
 
                 let handler = handler.clone();
                 let access = access.clone();
                 let dns_hostname = dns_hostname.clone();
+                let http_endpoint = http_endpoint.clone();
+                let clients = clients.clone();
 
                 inner_join_set.spawn(async move {
+                    // the handshake runs here, so a slow client doesn't hold up the others
+                    let connection = match incoming.await {
+                        Ok(connection) => {
+                            h3::server::Connection::new(h3_quinn::Connection::new(connection))
+                                .await
+                        }
+                        Err(e) => {
+                            debug!("error receiving h3 connection: {e}");
+                            return;
+                        }
+                    };
+                    let connection = match connection {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            debug!("error receiving h3 connection: {e}");
+                            return;
+                        }
+                    };
                     debug!("starting h3 stream request from: {src_addr}");
 
                     // TODO: need to consider timeout of total connect...
                     let result = h3_handler(
                         access,
                         handler,
-                        streams,
+                        connection,
                         src_addr,
                         dns_hostname,
+                        http_endpoint,
+                        clients,
                         shutdown.clone(),
                     )
                         .await;
@@ -959,9 +1083,41 @@ fn reap_tasks(join_set: &mut JoinSet<()>) {
     {}
 }
 
+/// A TLS 1.3 server config presenting the certificate, as QUIC needs it
+#[cfg(any(feature = "dns-over-quic", feature = "dns-over-h3"))]
+fn quic_tls_config(
+    certificate_and_key: (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>),
+    alpn: &[u8],
+) -> io::Result<Arc<ServerConfig>> {
+    let mut tls_config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_single_cert(certificate_and_key.0, certificate_and_key.1)
+            .map_err(io::Error::other)?;
+    tls_config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(Arc::new(tls_config))
+}
+
+/// The subject of the certificate a client authenticated with, if the listener asked for one
+#[cfg(feature = "dns-over-rustls")]
+fn peer_identity(connection: &rustls::ServerConnection) -> ClientIdentity {
+    let subject = connection
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .and_then(crate::tls::certificate_subject)
+        .map(Arc::from);
+    ClientIdentity {
+        subject,
+        name: None,
+    }
+}
+
 pub(crate) async fn handle_raw_request<T: RequestHandler>(
     message: SerialMessage,
     protocol: Protocol,
+    client: ClientIdentity,
     access: Arc<AccessControl>,
     request_handler: Arc<T>,
     response_handler: BufDnsStreamHandle,
@@ -973,6 +1129,7 @@ pub(crate) async fn handle_raw_request<T: RequestHandler>(
         message.bytes(),
         src_addr,
         protocol,
+        client,
         access,
         request_handler,
         response_handler,
@@ -1047,6 +1204,7 @@ pub(crate) async fn handle_request<R: ResponseHandler, T: RequestHandler>(
     message_bytes: &[u8],
     src_addr: SocketAddr,
     protocol: Protocol,
+    client: ClientIdentity,
     access: Arc<AccessControl>,
     request_handler: Arc<T>,
     response_handler: R,
@@ -1066,7 +1224,7 @@ pub(crate) async fn handle_request<R: ResponseHandler, T: RequestHandler>(
         let message_type = message.message_type();
         let is_dnssec = message.edns().map_or(false, |edns| edns.flags().dnssec_ok);
 
-        let request = Request::new(message, src_addr, protocol);
+        let request = Request::new(message, src_addr, protocol, client);
 
         let info = request.request_info();
         let query = info.query.clone();
@@ -1075,11 +1233,15 @@ pub(crate) async fn handle_request<R: ResponseHandler, T: RequestHandler>(
         let query_class = info.query.query_class();
 
         debug!(
-            "request:{id} src:{proto}://{addr}#{port} type:{message_type} dnssec:{is_dnssec} {op}:{query}:{qtype}:{class} qflags:{qflags}",
+            "request:{id} src:{proto}://{addr}#{port} client:{client} type:{message_type} dnssec:{is_dnssec} {op}:{query}:{qtype}:{class} qflags:{qflags}",
             id = id,
             proto = protocol,
             addr = src_addr.ip(),
             port = src_addr.port(),
+            client = request
+                .client_name()
+                .or(request.client_subject())
+                .unwrap_or("-"),
             message_type= message_type,
             is_dnssec = is_dnssec,
             op = qop_code,
@@ -1173,15 +1335,46 @@ pub(crate) async fn handle_request<R: ResponseHandler, T: RequestHandler>(
             )
                 .await;
         }
-        Err(error) => info!(
-            "request:Failed src:{proto}://{addr}#{port} error:{error}",
-            proto = protocol,
-            addr = src_addr.ip(),
-            port = src_addr.port(),
-        ),
+        Err(error) => match unknown_op_code_header(message_bytes) {
+            Some(header) => {
+                let query = LowerQuery::query(Query::default());
+
+                error_response_handler(
+                    protocol,
+                    src_addr,
+                    header,
+                    query,
+                    ResponseCode::NotImp,
+                    Box::new(error),
+                    response_handler,
+                )
+                    .await;
+            }
+            None => info!(
+                "request:Failed src:{proto}://{addr}#{port} error:{error}",
+                proto = protocol,
+                addr = src_addr.ip(),
+                port = src_addr.port(),
+            ),
+        },
     }
 }
 
+/// The header of a query with an opcode hickory can't decode, like the obsolete IQUERY, so it can
+/// be answered NOTIMP
+///
+/// `OpCode` has no variant for these, the header is read as a QUERY and so is the response's.
+fn unknown_op_code_header(message_bytes: &[u8]) -> Option<Header> {
+    let mut header: [u8; 12] = message_bytes.get(..12)?.try_into().ok()?;
+    let is_response = header[2] & 0b1000_0000 != 0;
+    if is_response || OpCode::from_u8((header[2] & 0b0111_1000) >> 3).is_ok() {
+        return None;
+    }
+
+    header[2] &= !0b0111_1000;
+    Header::from_bytes(&header).ok()
+}
+
 /// Checks if the IP address is safe for returning messages
 ///
 /// Examples of unsafe addresses are any with a port of `0`
@@ -1273,6 +1466,69 @@ mod tests {
         endpoints.rebind_all().await;
     }
 
+    /// Answers too big for a UDP client without EDNS are cut to 512 bytes with the TC bit set,
+    /// and come in full over TCP
+    #[tokio::test]
+    async fn truncate_over_udp() {
+        use crate::authority::ZoneType;
+        use crate::store::in_memory::InMemoryAuthority;
+        use hickory_proto::op::{Message, Query};
+        use hickory_proto::rr::rdata::{SOA, TXT};
+        use hickory_proto::rr::{Name, RData, RecordType};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpStream;
+
+        let origin = Name::from_ascii("example.com.").unwrap();
+        let txt = Name::from_ascii("txt.example.com.").unwrap();
+        let mut authority = InMemoryAuthority::empty(origin.clone(), ZoneType::Primary, false);
+        let soa = SOA::new(origin.clone(), origin.clone(), 1, 3600, 600, 86400, 300);
+        authority.upsert_mut(Record::from_rdata(origin.clone(), 3600, RData::SOA(soa)), 1);
+        for digit in '0'..='7' {
+            let data = RData::TXT(TXT::new(vec![digit.to_string().repeat(200)]));
+            authority.upsert_mut(Record::from_rdata(txt.clone(), 300, data), 1);
+        }
+        let mut catalog = Catalog::new();
+        catalog.upsert(origin.into(), vec![Arc::new(authority)]);
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        let mut server_future = ServerFuture::new(catalog);
+        server_future.register_socket(udp);
+        server_future.register_listener(tcp, Duration::from_secs(5));
+
+        let mut query = Message::new();
+        query.add_query(Query::query(txt, RecordType::TXT));
+        let query = query.to_vec().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&query, addr).await.unwrap();
+        let mut buffer = [0; 4096];
+        let (len, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buffer))
+            .await
+            .expect("timed out waiting for the UDP response")
+            .unwrap();
+        assert!(len <= 512);
+        let response = Message::from_vec(&buffer[..len]).unwrap();
+        assert!(response.truncated());
+        assert!(response.answers().len() < 8);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&(query.len() as u16).to_be_bytes()).await.unwrap();
+        stream.write_all(&query).await.unwrap();
+        let len = timeout(Duration::from_secs(5), stream.read_u16())
+            .await
+            .expect("timed out waiting for the TCP response")
+            .unwrap();
+        let mut buffer = vec![0; len as usize];
+        stream.read_exact(&mut buffer).await.unwrap();
+        let response = Message::from_vec(&buffer).unwrap();
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 8);
+
+        server_future.shutdown_gracefully().await.unwrap();
+    }
+
     #[test]
     fn test_sanitize_src_addr() {
         // ipv4 tests
@@ -1392,7 +1648,6 @@ mod tests {
                 server
                     .register_quic_listener(
                         UdpSocket::bind(self.quic_addr).await.unwrap(),
-                        Duration::from_secs(1),
                         cert_key,
                         None,
                     )
@@ -1405,9 +1660,10 @@ mod tests {
                 server
                     .register_h3_listener(
                         UdpSocket::bind(self.h3_addr).await.unwrap(),
-                        Duration::from_secs(1),
                         cert_key,
                         None,
+                        "/dns-query".into(),
+                        DohClients::default(),
                     )
                     .unwrap();
             }
@@ -1429,25 +1685,304 @@ mod tests {
         }
     }
 
+    /// A catalog answering `www.example.com. A` with 127.0.0.2
+    #[cfg(any(feature = "dns-over-quic", feature = "dns-over-h3"))]
+    fn example_catalog() -> Catalog {
+        use crate::authority::ZoneType;
+        use crate::store::in_memory::InMemoryAuthority;
+        use hickory_proto::rr::rdata::{A, SOA};
+        use hickory_proto::rr::{Name, RData};
+
+        let origin = Name::from_ascii("example.com.").unwrap();
+        let mut authority = InMemoryAuthority::empty(origin.clone(), ZoneType::Primary, false);
+        let soa = SOA::new(
+            Name::from_ascii("ns.example.com.").unwrap(),
+            Name::from_ascii("hostmaster.example.com.").unwrap(),
+            1,
+            3600,
+            600,
+            86400,
+            300,
+        );
+        authority.upsert_mut(Record::from_rdata(origin.clone(), 3600, RData::SOA(soa)), 1);
+        authority.upsert_mut(
+            Record::from_rdata(
+                Name::from_ascii("www.example.com.").unwrap(),
+                300,
+                RData::A(A::new(127, 0, 0, 2)),
+            ),
+            1,
+        );
+
+        let mut catalog = Catalog::new();
+        catalog.upsert(origin.into(), vec![Arc::new(authority)]);
+        catalog
+    }
+
+    /// Client config trusting the test CA, which signed the `localhost` certificate
+    #[cfg(any(feature = "dns-over-quic", feature = "dns-over-h3"))]
+    fn client_config() -> rustls::ClientConfig {
+        use hickory_proto::rustls::tls_server;
+        use std::path::Path;
+
+        let ca = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/ca.pem");
+        let mut roots = rustls::RootCertStore::empty();
+        let (_, ignored) = roots.add_parsable_certificates(tls_server::read_cert(&ca).unwrap());
+        assert_eq!(ignored, 0);
+
+        rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+    }
+
+    /// Sends `www.example.com. A` and checks the answer of [`example_catalog`]
+    #[cfg(any(feature = "dns-over-quic", feature = "dns-over-h3"))]
+    async fn assert_resolves(client: &mut impl hickory_proto::xfer::DnsRequestSender) {
+        use hickory_proto::op::{Message, Query};
+        use hickory_proto::rr::{Name, RData, RecordType};
+        use hickory_proto::xfer::{DnsRequest, DnsRequestOptions};
+
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_ascii("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let request = DnsRequest::new(message, DnsRequestOptions::default());
+
+        let response = timeout(Duration::from_secs(5), client.send_message(request).next())
+            .await
+            .expect("timed out waiting for the response")
+            .expect("no response")
+            .expect("failed response");
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        let answers = response.answers();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].data(), &RData::A("127.0.0.2".parse().unwrap()));
+    }
+
+    #[cfg(feature = "dns-over-quic")]
+    #[tokio::test]
+    async fn resolve_over_quic() {
+        use hickory_proto::quic::QuicClientStream;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut server_future = ServerFuture::new(example_catalog());
+        server_future
+            .register_quic_listener(socket, rustls_cert_key(), None)
+            .unwrap();
+
+        let mut builder = QuicClientStream::builder();
+        builder.crypto_config(client_config());
+        let mut client = builder.build(addr, "localhost".into()).await.unwrap();
+        assert_resolves(&mut client).await;
+
+        server_future.shutdown_gracefully().await.unwrap();
+    }
+
+    #[cfg(feature = "dns-over-h3")]
+    #[tokio::test]
+    async fn resolve_over_h3() {
+        use hickory_proto::h3::H3ClientStream;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut server_future = ServerFuture::new(example_catalog());
+        server_future
+            .register_h3_listener(
+                socket,
+                rustls_cert_key(),
+                None,
+                "/dns-query".into(),
+                DohClients::default(),
+            )
+            .unwrap();
+
+        let mut builder = H3ClientStream::builder();
+        builder.crypto_config(client_config());
+        let mut client = builder
+            .build(addr, "localhost".into(), "/dns-query".into())
+            .await
+            .unwrap();
+        assert_resolves(&mut client).await;
+
+        server_future.shutdown_gracefully().await.unwrap();
+    }
+
+    #[cfg(feature = "dns-over-quic")]
+    #[tokio::test]
+    async fn quic_certificate_reload() {
+        use crate::config::TlsConfig;
+        use crate::tls::{doq_server_config, CertificateResolver};
+        use hickory_proto::quic::QuicClientStream;
+        use std::fs;
+        use std::path::Path;
+
+        let test_data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        let dir = std::env::temp_dir().join(format!("mushroom-quic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            cert_chain_path: dir.join("cert.pem"),
+            private_key_path: dir.join("key.pem"),
+            client_ca_path: None,
+        };
+        let install = |cert: &str, key: &str| {
+            fs::copy(test_data.join(cert), &config.cert_chain_path).unwrap();
+            fs::copy(test_data.join(key), &config.private_key_path).unwrap();
+        };
+        install("server.pem", "server.key");
+        let certificate = CertificateResolver::new(&config).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut server_future = ServerFuture::new(example_catalog());
+        let tls_config = doq_server_config(certificate.clone()).unwrap();
+        server_future
+            .register_quic_listener_with_tls_config(socket, tls_config, None)
+            .unwrap();
+
+        let mut builder = QuicClientStream::builder();
+        builder.crypto_config(client_config());
+        let mut client = builder.build(addr, "localhost".into()).await.unwrap();
+        assert_resolves(&mut client).await;
+
+        // the renewed certificate isn't for localhost, new handshakes must get it
+        install("client.pem", "client.key");
+        certificate.reload().unwrap();
+        let mut builder = QuicClientStream::builder();
+        builder.crypto_config(client_config());
+        assert!(builder.build(addr, "localhost".into()).await.is_err());
+
+        server_future.shutdown_gracefully().await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Refuses every query, remembering the certificate subject of the client
+    #[cfg(feature = "dns-over-rustls")]
+    #[derive(Clone, Default)]
+    struct SubjectRecorder(Arc<std::sync::Mutex<Vec<Option<String>>>>);
+
+    #[cfg(feature = "dns-over-rustls")]
+    #[async_trait::async_trait]
+    impl RequestHandler for SubjectRecorder {
+        async fn handle_request<R: ResponseHandler>(
+            &self,
+            request: &Request,
+            mut response_handle: R,
+        ) -> crate::server::ResponseInfo {
+            let subject = request.request_info().client_subject.map(str::to_string);
+            self.0.lock().unwrap().push(subject);
+            let response = MessageResponseBuilder::new(Some(request.raw_query()));
+            response_handle
+                .send_response(
+                    response.error_msg(request.header(), ResponseCode::Refused),
+                    0,
+                    false,
+                )
+                .await
+                .unwrap()
+        }
+    }
+
+    #[cfg(feature = "dns-over-rustls")]
+    #[tokio::test]
+    async fn mutual_tls() {
+        use crate::config::TlsConfig;
+        use hickory_proto::op::Message;
+        use hickory_proto::rustls::tls_server;
+        use rustls::pki_types::ServerName;
+        use std::path::Path;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::TlsConnector;
+
+        let test_data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        let config = TlsConfig {
+            cert_chain_path: test_data.join("server.pem"),
+            private_key_path: test_data.join("server.key"),
+            client_ca_path: Some(test_data.join("ca.pem")),
+        };
+        let certificate = crate::tls::CertificateResolver::new(&config).unwrap();
+        let tls_config = crate::tls::dot_server_config(&config, certificate).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let recorder = SubjectRecorder::default();
+        let mut server_future = ServerFuture::new(recorder.clone());
+        server_future
+            .register_tls_listener_with_tls_config(listener, Duration::from_secs(5), tls_config)
+            .unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_parsable_certificates(tls_server::read_cert(&test_data.join("ca.pem")).unwrap());
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+
+        // sends a query over DoT, and returns the response if there is one
+        let query = |client_config: rustls::ClientConfig| async move {
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut tls = TlsConnector::from(Arc::new(client_config))
+                .connect(ServerName::try_from("localhost").unwrap(), tcp)
+                .await
+                .ok()?;
+
+            let mut message = Message::new();
+            message.add_query(Query::query(
+                "www.example.com.".parse().unwrap(),
+                hickory_proto::rr::RecordType::A,
+            ));
+            let bytes = message.to_vec().unwrap();
+            tls.write_all(&(bytes.len() as u16).to_be_bytes()).await.ok()?;
+            tls.write_all(&bytes).await.ok()?;
+            let len = tls.read_u16().await.ok()?;
+            let mut response = vec![0; usize::from(len)];
+            tls.read_exact(&mut response).await.ok()?;
+            Some(Message::from_vec(&response).unwrap())
+        };
+
+        let cert_chain = tls_server::read_cert(&test_data.join("client.pem")).unwrap();
+        let key = tls_server::read_key(&test_data.join("client.key")).unwrap();
+        let with_cert = builder
+            .clone()
+            .with_client_auth_cert(cert_chain, key)
+            .unwrap();
+        let response = timeout(Duration::from_secs(5), query(with_cert))
+            .await
+            .unwrap()
+            .expect("no response with a client certificate");
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+
+        let without_cert = builder.with_no_client_auth();
+        let response = timeout(Duration::from_secs(5), query(without_cert))
+            .await
+            .unwrap();
+        assert!(response.is_none());
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![Some("CN=laptop.example.com,O=Mushroom Test".to_string())]
+        );
+        server_future.shutdown_gracefully().await.unwrap();
+    }
+
     #[cfg(feature = "dns-over-rustls")]
     fn rustls_cert_key() -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
         use hickory_proto::rustls::tls_server;
-        use std::env;
         use std::path::Path;
 
-        let server_path = env::var("TDNS_WORKSPACE_ROOT").unwrap_or_else(|_| "../..".to_owned());
+        let test_data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
 
-        let cert = tls_server::read_cert(Path::new(&format!(
-            "{}/tests/test-data/cert.pem",
-            server_path
-        )))
+        let cert = tls_server::read_cert(&test_data.join("server.pem"))
             .map_err(|e| format!("error reading cert: {e}"))
             .unwrap();
-        let key = tls_server::read_key(Path::new(&format!(
-            "{}/tests/test-data/cert.key",
-            server_path
-        )))
-            .unwrap();
+        let key = tls_server::read_key(&test_data.join("server.key")).unwrap();
 
         (cert, key)
     }
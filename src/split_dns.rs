@@ -0,0 +1,393 @@
+//! Per-link split DNS, as systemd-resolved does it
+//!
+//! Every interface has its nameservers and routing domains, queries for names below one of its
+//! domains go to its nameservers. NetworkManager reports them, entries of the config file override
+//! what it reports for an interface. Names below no routing domain stay with the encrypted
+//! upstreams.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+
+use hickory_proto::rr::{LowerName, Name};
+use hickory_proto::ProtoError;
+use hickory_resolver::config::{NameServerConfig, NameServerConfigGroup};
+use hickory_resolver::proto::xfer::Protocol;
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::lookup::NetworkResolver;
+use crate::network::Links;
+use crate::upstream::Upstream;
+
+/// Nameservers and routing domains of an interface, replacing those NetworkManager reports for it
+#[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LinkConfig {
+    /// Interface the entry is for, e.g. `wg0`
+    pub interface: String,
+    /// Nameservers of the interface, NetworkManager's are kept when empty
+    #[serde(default)]
+    pub name_servers: Vec<IpAddr>,
+    /// Routing domains of the interface, e.g. `~corp.example`, NetworkManager's are kept when
+    /// empty. The `~` marking routing-only domains is optional, search domains route all the same.
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+/// Parses a search or routing domain, `None` for the root domain: it would route every name to the
+/// interface, the encrypted upstreams stay the default
+pub fn routing_domain(domain: &str) -> Result<Option<LowerName>, ProtoError> {
+    let mut name = Name::from_str(domain.strip_prefix('~').unwrap_or(domain))?;
+    name.set_fqdn(true);
+    Ok((!name.is_root()).then(|| LowerName::from(name)))
+}
+
+/// Nameservers and routing domains of an interface
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LinkDns {
+    pub name_servers: Vec<IpAddr>,
+    pub domains: Vec<LowerName>,
+}
+
+impl LinkDns {
+    fn add_name_servers<'a>(&mut self, name_servers: impl IntoIterator<Item = &'a IpAddr>) {
+        for name_server in name_servers {
+            if !self.name_servers.contains(name_server) {
+                self.name_servers.push(*name_server);
+            }
+        }
+    }
+
+    fn add_domains(&mut self, domains: impl IntoIterator<Item = LowerName>) {
+        for domain in domains {
+            if !self.domains.contains(&domain) {
+                self.domains.push(domain);
+            }
+        }
+    }
+}
+
+/// An interface in use, with the forwarder to its nameservers
+struct ActiveLink {
+    dns: LinkDns,
+    upstream: Arc<Upstream>,
+}
+
+/// What replaces the nameservers or domains NetworkManager reports for an interface, `None` keeps
+/// them
+#[derive(Clone, Debug, Default)]
+struct LinkOverride {
+    name_servers: Option<Vec<IpAddr>>,
+    domains: Option<Vec<LowerName>>,
+}
+
+impl LinkOverride {
+    fn apply(&self, dns: &mut LinkDns) {
+        if let Some(name_servers) = &self.name_servers {
+            dns.name_servers.clone_from(name_servers);
+        }
+        if let Some(domains) = &self.domains {
+            dns.domains.clone_from(domains);
+        }
+    }
+}
+
+/// What the table is built from
+#[derive(Default)]
+struct Sources {
+    network: Links,
+    ipv6_support: bool,
+    /// Set over D-Bus, on top of the config
+    dynamic: BTreeMap<String, LinkOverride>,
+}
+
+/// The per-interface table of nameservers and routing domains
+pub struct SplitDns {
+    configured: BTreeMap<String, LinkOverride>,
+    sources: Mutex<Sources>,
+    links: RwLock<BTreeMap<String, ActiveLink>>,
+}
+
+impl SplitDns {
+    /// Sets up the table from the config entries, call [`Self::refresh`] to add NetworkManager's
+    /// links
+    pub fn new(configs: &[LinkConfig]) -> Result<Self, String> {
+        let mut configured = BTreeMap::new();
+        for config in configs {
+            let mut dns = LinkDns::default();
+            dns.add_name_servers(&config.name_servers);
+            for domain in &config.domains {
+                let domain = routing_domain(domain).map_err(|err| {
+                    format!(
+                        "invalid domain {domain} of link {}: {err}",
+                        config.interface
+                    )
+                })?;
+                dns.add_domains(domain);
+            }
+            let link = LinkOverride {
+                name_servers: (!dns.name_servers.is_empty()).then_some(dns.name_servers),
+                domains: (!dns.domains.is_empty()).then_some(dns.domains),
+            };
+            configured.insert(config.interface.clone(), link);
+        }
+        Ok(Self {
+            configured,
+            sources: Mutex::default(),
+            links: RwLock::default(),
+        })
+    }
+
+    /// Rebuilds the table with NetworkManager's links
+    pub fn refresh(&self, links: &Links, ipv6_support: bool) {
+        let mut sources = self.sources.lock().expect("split dns lock poisoned");
+        sources.network.clone_from(links);
+        sources.ipv6_support = ipv6_support;
+        self.rebuild(&sources);
+    }
+
+    /// Replaces the nameservers of the interface, until it is reverted
+    pub fn set_name_servers(&self, interface: &str, name_servers: Vec<IpAddr>) {
+        let mut sources = self.sources.lock().expect("split dns lock poisoned");
+        let link = sources.dynamic.entry(interface.to_string()).or_default();
+        link.name_servers = Some(name_servers);
+        self.rebuild(&sources);
+    }
+
+    /// Replaces the routing domains of the interface, until it is reverted
+    pub fn set_domains(&self, interface: &str, domains: Vec<LowerName>) {
+        let mut sources = self.sources.lock().expect("split dns lock poisoned");
+        let link = sources.dynamic.entry(interface.to_string()).or_default();
+        link.domains = Some(domains);
+        self.rebuild(&sources);
+    }
+
+    /// Goes back to the nameservers and domains of NetworkManager and the config for the interface
+    pub fn revert(&self, interface: &str) {
+        let mut sources = self.sources.lock().expect("split dns lock poisoned");
+        if sources.dynamic.remove(interface).is_some() {
+            self.rebuild(&sources);
+        }
+    }
+
+    /// Rebuilds the table from NetworkManager's links, the config and what was set over D-Bus,
+    /// keeping the forwarders (and their caches) of interfaces whose nameservers didn't change
+    fn rebuild(&self, sources: &Sources) {
+        let mut table: BTreeMap<String, LinkDns> = BTreeMap::new();
+        for link in &sources.network {
+            if link.interface.is_empty() {
+                debug!(
+                    "Connection {} has no interface, skipping its DNS",
+                    link.connection
+                );
+                continue;
+            }
+            let dns = table.entry(link.interface.clone()).or_default();
+            dns.add_name_servers(&link.name_servers);
+            for domain in &link.domains {
+                match routing_domain(domain) {
+                    Ok(domain) => dns.add_domains(domain),
+                    Err(err) => debug!("Skipping domain {domain} of {}: {err}", link.interface),
+                }
+            }
+        }
+        for overrides in [&self.configured, &sources.dynamic] {
+            for (interface, link) in overrides {
+                link.apply(table.entry(interface.clone()).or_default());
+            }
+        }
+
+        let mut current = self.links.write().expect("split dns lock poisoned");
+        let mut next = BTreeMap::new();
+        for (interface, mut dns) in table {
+            dns.name_servers
+                .retain(|name_server| sources.ipv6_support || name_server.is_ipv4());
+            if dns.name_servers.is_empty() {
+                continue;
+            }
+            let upstream = match current.get(&interface) {
+                Some(link) if link.dns.name_servers == dns.name_servers => link.upstream.clone(),
+                _ => {
+                    let name_servers = dns
+                        .name_servers
+                        .iter()
+                        .map(|ip| NameServerConfig::new(SocketAddr::new(*ip, 53), Protocol::Udp));
+                    let name_servers =
+                        NameServerConfigGroup::from(name_servers.collect::<Vec<_>>());
+                    Arc::new(NetworkResolver::build_upstream(&name_servers))
+                }
+            };
+            next.insert(interface, ActiveLink { dns, upstream });
+        }
+
+        let changed = !current.keys().eq(next.keys())
+            || current
+                .values()
+                .zip(next.values())
+                .any(|(a, b)| a.dns != b.dns);
+        if changed {
+            let table: Vec<String> = next
+                .iter()
+                .map(|(interface, link)| {
+                    let domains: Vec<String> =
+                        link.dns.domains.iter().map(|d| format!("~{d}")).collect();
+                    format!(
+                        "{interface} {:?} {}",
+                        link.dns.name_servers,
+                        domains.join(" ")
+                    )
+                })
+                .collect();
+            info!("Split DNS links are now {}", table.join(", "));
+        }
+        *current = next;
+    }
+
+    /// The forwarder to the nameservers of the interface, `None` when it has none
+    pub fn upstream(&self, interface: &str) -> Option<Arc<Upstream>> {
+        let links = self.links.read().expect("split dns lock poisoned");
+        links.get(interface).map(|link| link.upstream.clone())
+    }
+
+    /// The forwarders of all interfaces
+    pub fn upstreams(&self) -> Vec<Arc<Upstream>> {
+        let links = self.links.read().expect("split dns lock poisoned");
+        links.values().map(|link| link.upstream.clone()).collect()
+    }
+
+    /// The interface and the forwarder to its nameservers for the name, by the longest routing
+    /// domain it is below. `None` when it is below none, the encrypted upstreams answer it then.
+    pub fn route(&self, name: &LowerName) -> Option<(String, Arc<Upstream>)> {
+        let links = self.links.read().expect("split dns lock poisoned");
+        links
+            .iter()
+            .flat_map(|(interface, link)| {
+                link.dns
+                    .domains
+                    .iter()
+                    .map(move |domain| (domain, interface))
+            })
+            .filter(|(domain, _)| domain.zone_of(name))
+            .max_by_key(|(domain, _)| domain.num_labels())
+            .map(|(_, interface)| (interface.clone(), links[interface].upstream.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Link;
+
+    fn lower(name: &str) -> LowerName {
+        LowerName::from(Name::from_str(name).unwrap())
+    }
+
+    fn link(interface: &str, name_servers: &[&str], domains: &[&str]) -> Link {
+        Link {
+            interface: interface.to_string(),
+            connection: interface.to_uppercase(),
+            vpn: false,
+            name_servers: name_servers.iter().map(|ip| ip.parse().unwrap()).collect(),
+            domains: domains.iter().map(|domain| domain.to_string()).collect(),
+        }
+    }
+
+    fn routed(split_dns: &SplitDns, name: &str) -> Option<String> {
+        split_dns
+            .route(&lower(name))
+            .map(|(interface, _)| interface)
+    }
+
+    #[test]
+    fn test_routing_domain() {
+        assert_eq!(
+            routing_domain("~Corp.Example").unwrap(),
+            Some(lower("corp.example."))
+        );
+        assert_eq!(routing_domain("lan").unwrap(), Some(lower("lan.")));
+        assert_eq!(routing_domain("~.").unwrap(), None);
+        assert!(routing_domain("bad..domain").is_err());
+    }
+
+    #[test]
+    fn test_split_dns() {
+        let configs = [
+            LinkConfig {
+                interface: "wg0".to_string(),
+                name_servers: vec![],
+                domains: vec!["~corp.example".to_string(), "~.".to_string()],
+            },
+            LinkConfig {
+                interface: "tun0".to_string(),
+                name_servers: vec!["10.9.0.1".parse().unwrap()],
+                domains: vec!["~lab.corp.example".to_string()],
+            },
+        ];
+        let split_dns = SplitDns::new(&configs).unwrap();
+        let links = vec![
+            link("wlan0", &["192.168.1.1", "fd00::1"], &["lan", "~fritz.box"]),
+            link("wg0", &["10.8.0.1"], &["vpn.example"]),
+            link("", &["10.7.0.1"], &["gone.example"]),
+        ];
+        split_dns.refresh(&links, true);
+
+        assert_eq!(routed(&split_dns, "printer.lan."), Some("wlan0".into()));
+        assert_eq!(routed(&split_dns, "fritz.box."), Some("wlan0".into()));
+        assert_eq!(routed(&split_dns, "git.corp.example."), Some("wg0".into()));
+        assert_eq!(
+            routed(&split_dns, "x.lab.corp.example."),
+            Some("tun0".into())
+        );
+        // the configured domains replace NetworkManager's, the root domain isn't routed
+        assert_eq!(routed(&split_dns, "vpn.example."), None);
+        assert_eq!(routed(&split_dns, "gone.example."), None);
+        assert_eq!(routed(&split_dns, "example.com."), None);
+
+        let (_, upstream) = split_dns.route(&lower("lan.")).unwrap();
+        assert_eq!(upstream.name_servers().count(), 2);
+        split_dns.refresh(&links, false);
+        let (_, ipv4_upstream) = split_dns.route(&lower("lan.")).unwrap();
+        assert_eq!(ipv4_upstream.name_servers().count(), 1);
+        // forwarders, and their caches, are kept while the nameservers stay the same
+        split_dns.refresh(&links, false);
+        let (_, kept) = split_dns.route(&lower("lan.")).unwrap();
+        assert!(Arc::ptr_eq(&ipv4_upstream, &kept));
+
+        // an interface without any nameservers left isn't routed to
+        split_dns.refresh(&vec![link("wlan0", &["fd00::1"], &["lan"])], false);
+        assert_eq!(routed(&split_dns, "printer.lan."), None);
+        assert_eq!(
+            routed(&split_dns, "x.lab.corp.example."),
+            Some("tun0".into())
+        );
+        assert!(split_dns.upstream("wlan0").is_none());
+        assert!(split_dns.upstream("tun0").is_some());
+    }
+
+    #[test]
+    fn test_set_link() {
+        let split_dns = SplitDns::new(&[]).unwrap();
+        split_dns.refresh(&vec![link("wg0", &["10.8.0.1"], &["corp.example"])], true);
+        let (_, upstream) = split_dns.route(&lower("git.corp.example.")).unwrap();
+
+        split_dns.set_domains("wg0", vec![lower("vpn.example.")]);
+        assert_eq!(routed(&split_dns, "git.corp.example."), None);
+        let (_, kept) = split_dns.route(&lower("vpn.example.")).unwrap();
+        assert!(Arc::ptr_eq(&upstream, &kept));
+        // what was set survives NetworkManager's updates
+        split_dns.refresh(&vec![link("wg0", &["10.8.0.2"], &["corp.example"])], true);
+        assert_eq!(routed(&split_dns, "vpn.example."), Some("wg0".into()));
+
+        split_dns.set_name_servers("lo", vec!["127.0.0.2".parse().unwrap()]);
+        assert!(split_dns.upstream("lo").is_some());
+        assert_eq!(split_dns.upstreams().len(), 2);
+        split_dns.set_name_servers("lo", vec![]);
+        assert!(split_dns.upstream("lo").is_none());
+
+        split_dns.revert("wg0");
+        assert_eq!(routed(&split_dns, "git.corp.example."), Some("wg0".into()));
+        assert_eq!(routed(&split_dns, "vpn.example."), None);
+    }
+}
@@ -60,7 +60,7 @@ impl<P: ConnectionProvider> ForwardAuthority<P> {
     ) -> Result<Self, String> {
         info!("loading forwarder config: {}", origin);
 
-        let name_servers = config.name_servers.clone();
+        let name_servers = config.all_name_servers();
         let mut options = config.options.clone().unwrap_or_default();
 
         // See RFC 1034, Section 4.3.2:
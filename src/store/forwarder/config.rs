@@ -7,14 +7,74 @@
 
 use serde::Deserialize;
 
-use crate::resolver::config::{NameServerConfigGroup, ResolverOpts};
+use hickory_resolver::config::{NameServerConfigGroup, ResolverOpts};
 
 /// Configuration for file based zones
 #[derive(Clone, Deserialize, PartialEq, Eq, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct ForwardConfig {
+    /// well known public name_server sets to forward to, before `name_servers`
+    #[serde(default)]
+    pub presets: Vec<NameServerPreset>,
     /// upstream name_server configurations
+    #[serde(default)]
     pub name_servers: NameServerConfigGroup,
     /// Resolver options
     pub options: Option<ResolverOpts>,
 }
+
+impl ForwardConfig {
+    /// All upstream name_servers, those of the presets followed by `name_servers`
+    pub fn all_name_servers(&self) -> NameServerConfigGroup {
+        let mut name_servers = NameServerConfigGroup::new();
+        for preset in &self.presets {
+            name_servers.merge(preset.name_servers());
+        }
+        name_servers.merge(self.name_servers.clone());
+        name_servers
+    }
+}
+
+/// Well known public name_server sets, as provided by [`NameServerConfigGroup`]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NameServerPreset {
+    /// Google Public DNS over plain UDP/TCP
+    Google,
+    /// Google Public DNS over TLS
+    GoogleTls,
+    /// Google Public DNS over HTTPS
+    GoogleHttps,
+    /// Google Public DNS over HTTP/3
+    GoogleH3,
+    /// Cloudflare's 1.1.1.1 over plain UDP/TCP
+    Cloudflare,
+    /// Cloudflare's 1.1.1.1 over TLS
+    CloudflareTls,
+    /// Cloudflare's 1.1.1.1 over HTTPS
+    CloudflareHttps,
+    /// Quad9 over plain UDP/TCP
+    Quad9,
+    /// Quad9 over TLS
+    Quad9Tls,
+    /// Quad9 over HTTPS
+    Quad9Https,
+}
+
+impl NameServerPreset {
+    /// The name_servers belonging to this preset
+    pub fn name_servers(self) -> NameServerConfigGroup {
+        match self {
+            Self::Google => NameServerConfigGroup::google(),
+            Self::GoogleTls => NameServerConfigGroup::google_tls(),
+            Self::GoogleHttps => NameServerConfigGroup::google_https(),
+            Self::GoogleH3 => NameServerConfigGroup::google_h3(),
+            Self::Cloudflare => NameServerConfigGroup::cloudflare(),
+            Self::CloudflareTls => NameServerConfigGroup::cloudflare_tls(),
+            Self::CloudflareHttps => NameServerConfigGroup::cloudflare_https(),
+            Self::Quad9 => NameServerConfigGroup::quad9(),
+            Self::Quad9Tls => NameServerConfigGroup::quad9_tls(),
+            Self::Quad9Https => NameServerConfigGroup::quad9_https(),
+        }
+    }
+}
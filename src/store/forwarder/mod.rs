@@ -5,13 +5,12 @@
 // https://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-#![cfg(feature = "hickory-resolver")]
-
 //! Forwarding resolver related types
 
+#[cfg(feature = "hickory-resolver")]
 mod authority;
 mod config;
 
-pub use self::authority::ForwardAuthority;
-pub use self::authority::ForwardLookup;
-pub use self::config::ForwardConfig;
+#[cfg(feature = "hickory-resolver")]
+pub use self::authority::{ForwardAuthority, ForwardLookup};
+pub use self::config::{ForwardConfig, NameServerPreset};
@@ -0,0 +1,535 @@
+//! Certificates and TLS settings of the encrypted listeners
+
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use hickory_proto::rustls::tls_server::{read_cert, read_key};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use tracing::{error, info, warn};
+use webpki::EndEntityCert;
+
+use crate::config::TlsConfig;
+
+/// ALPN protocol of DNS-over-TLS, as registered by RFC 7858
+const DOT_ALPN: &[u8] = b"dot";
+
+/// ALPN protocol of DNS-over-HTTPS, which RFC 8484 runs over HTTP/2
+#[cfg(feature = "dns-over-https-rustls")]
+const DOH_ALPN: &[u8] = b"h2";
+
+/// ALPN protocol of DNS-over-QUIC, RFC 9250
+#[cfg(feature = "dns-over-quic")]
+const DOQ_ALPN: &[u8] = b"doq";
+
+/// ALPN protocol of DNS-over-HTTP/3
+#[cfg(feature = "dns-over-h3")]
+const H3_ALPN: &[u8] = b"h3";
+
+/// How often the certificate files are checked for changes
+const CERTIFICATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Builds the server config of the DNS-over-TLS listeners
+pub fn dot_server_config(
+    config: &TlsConfig,
+    certificate: Arc<CertificateResolver>,
+) -> Result<Arc<ServerConfig>, String> {
+    server_config(config, certificate, DOT_ALPN)
+}
+
+/// Builds the server config of the DNS-over-HTTPS listeners
+#[cfg(feature = "dns-over-https-rustls")]
+pub fn doh_server_config(
+    config: &TlsConfig,
+    certificate: Arc<CertificateResolver>,
+) -> Result<Arc<ServerConfig>, String> {
+    server_config(config, certificate, DOH_ALPN)
+}
+
+/// Builds the server config of the DNS-over-QUIC listeners
+#[cfg(feature = "dns-over-quic")]
+pub fn doq_server_config(
+    certificate: Arc<CertificateResolver>,
+) -> Result<Arc<ServerConfig>, String> {
+    quic_server_config(certificate, DOQ_ALPN)
+}
+
+/// Builds the server config of the DNS-over-HTTP/3 listeners
+#[cfg(feature = "dns-over-h3")]
+pub fn doh3_server_config(
+    certificate: Arc<CertificateResolver>,
+) -> Result<Arc<ServerConfig>, String> {
+    quic_server_config(certificate, H3_ALPN)
+}
+
+/// Loads the certificate chain and key
+fn cert_and_key(
+    config: &TlsConfig,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+    let cert_chain = read_cert(&config.cert_chain_path).map_err(|err| err.to_string())?;
+    let key = read_key(&config.private_key_path).map_err(|err| err.to_string())?;
+    Ok((cert_chain, key))
+}
+
+fn server_config(
+    config: &TlsConfig,
+    certificate: Arc<CertificateResolver>,
+    alpn: &[u8],
+) -> Result<Arc<ServerConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?;
+    let builder = match &config.client_ca_path {
+        Some(client_ca_path) => {
+            builder.with_client_cert_verifier(client_verifier(client_ca_path, provider)?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(certificate);
+    server_config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(Arc::new(server_config))
+}
+
+/// QUIC only runs over TLS 1.3, and its listeners don't ask clients for a certificate
+#[cfg(any(feature = "dns-over-quic", feature = "dns-over-h3"))]
+fn quic_server_config(
+    certificate: Arc<CertificateResolver>,
+    alpn: &[u8],
+) -> Result<Arc<ServerConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let mut server_config = ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|err| err.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(certificate);
+    server_config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(Arc::new(server_config))
+}
+
+/// Certificate of the encrypted listeners, which can be replaced while they run.
+///
+/// Handshakes after a [`reload`](Self::reload) get the new certificate, connections that are
+/// already established keep using the old one.
+#[derive(Debug)]
+pub struct CertificateResolver {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Modification times of the certificate chain and key when they were last loaded
+    modified: Mutex<[Option<SystemTime>; 2]>,
+}
+
+impl CertificateResolver {
+    /// Loads the certificate chain and key
+    pub fn new(config: &TlsConfig) -> Result<Arc<Self>, String> {
+        let modified = Self::modification_times(config);
+        let certified_key = Self::load(config)?;
+        Ok(Arc::new(Self {
+            config: config.clone(),
+            current: RwLock::new(Arc::new(certified_key)),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    /// Loads the certificate chain and key again. The current ones are kept if they can't be
+    /// loaded, or don't belong together.
+    pub fn reload(&self) -> Result<(), String> {
+        *self.modified.lock().unwrap() = Self::modification_times(&self.config);
+        let certified_key = Self::load(&self.config)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+
+    /// Whether the certificate chain or key changed on disk since they were last loaded
+    fn files_changed(&self) -> bool {
+        *self.modified.lock().unwrap() != Self::modification_times(&self.config)
+    }
+
+    fn modification_times(config: &TlsConfig) -> [Option<SystemTime>; 2] {
+        [&config.cert_chain_path, &config.private_key_path]
+            .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+    }
+
+    fn load(config: &TlsConfig) -> Result<CertifiedKey, String> {
+        let (cert_chain, key) = cert_and_key(config)?;
+        let provider = rustls::crypto::ring::default_provider();
+        let key = provider.key_provider.load_private_key(key).map_err(|err| {
+            format!("invalid key {}: {err}", config.private_key_path.display())
+        })?;
+        let certified_key = CertifiedKey::new(cert_chain, key);
+        certified_key.keys_match().map_err(|err| {
+            format!(
+                "invalid certificate {}: {err}",
+                config.cert_chain_path.display()
+            )
+        })?;
+        Ok(certified_key)
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Reloads the certificate whenever its files change, or the daemon gets SIGHUP
+pub fn spawn_certificate_reload(certificate: Arc<CertificateResolver>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CERTIFICATE_CHECK_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let certificate = certificate.clone();
+            let reloaded = tokio::task::spawn_blocking(move || {
+                let requested = crate::reload::take_request();
+                if !requested && !certificate.files_changed() {
+                    return;
+                }
+                let path = certificate.config.cert_chain_path.display();
+                match certificate.reload() {
+                    Ok(()) => info!("Reloaded certificate {path}"),
+                    Err(err) => warn!("Keeping the current certificate: {err}"),
+                }
+            })
+            .await;
+            if let Err(err) = reloaded {
+                error!("Reloading the certificate failed: {err}");
+            }
+        }
+    });
+}
+
+/// Requires clients to present a certificate signed by one of the CAs in `client_ca_path`
+fn client_verifier(
+    client_ca_path: &Path,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn ClientCertVerifier>, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_cert(client_ca_path).map_err(|err| err.to_string())? {
+        roots.add(cert).map_err(|err| {
+            format!("invalid client CA {}: {err}", client_ca_path.display())
+        })?;
+    }
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .build()
+        .map_err(|err| format!("invalid client CA {}: {err}", client_ca_path.display()))
+}
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_SET: u8 = 0x31;
+const DER_OID: u8 = 0x06;
+const DER_UTF8_STRING: u8 = 0x0c;
+const DER_PRINTABLE_STRING: u8 = 0x13;
+const DER_TELETEX_STRING: u8 = 0x14;
+const DER_IA5_STRING: u8 = 0x16;
+const DER_UNIVERSAL_STRING: u8 = 0x1c;
+const DER_BMP_STRING: u8 = 0x1e;
+
+/// Subject of a DER certificate as an RFC 4514 string, like `CN=laptop.example.com,O=Example`
+pub fn certificate_subject(cert: &CertificateDer<'_>) -> Option<String> {
+    let cert = EndEntityCert::try_from(cert).ok()?;
+    distinguished_name(cert.subject())
+}
+
+/// RFC 4514 string of the RDNs in the contents of a DER `Name`
+fn distinguished_name(mut name: &[u8]) -> Option<String> {
+    let mut rdns = Vec::new();
+    while !name.is_empty() {
+        let (mut rdn, rest) = der_expect(name, DER_SET)?;
+        name = rest;
+        let mut attributes = Vec::new();
+        while !rdn.is_empty() {
+            let (attribute, rest) = der_expect(rdn, DER_SEQUENCE)?;
+            rdn = rest;
+            let (oid, value) = der_expect(attribute, DER_OID)?;
+            attributes.push(format!("{}={}", attribute_type(oid), attribute_value(value)?));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    // RFC 4514 starts with the last RDN
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+/// The string of an attribute value, escaped, or `#` and the hex of its DER encoding when it
+/// isn't a string
+fn attribute_value(value: &[u8]) -> Option<String> {
+    let (tag, contents, rest) = der_next(value)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    let string = match tag {
+        DER_UTF8_STRING => String::from_utf8(contents.to_vec()).ok(),
+        DER_PRINTABLE_STRING | DER_IA5_STRING if contents.is_ascii() => {
+            String::from_utf8(contents.to_vec()).ok()
+        }
+        // in practice Latin-1, as OpenSSL reads it
+        DER_TELETEX_STRING => Some(contents.iter().map(|&octet| char::from(octet)).collect()),
+        DER_BMP_STRING if contents.len() % 2 == 0 => {
+            let units = contents.chunks_exact(2).map(|unit| u16::from_be_bytes([unit[0], unit[1]]));
+            char::decode_utf16(units).collect::<Result<_, _>>().ok()
+        }
+        DER_UNIVERSAL_STRING if contents.len() % 4 == 0 => contents
+            .chunks_exact(4)
+            .map(|c| char::from_u32(u32::from_be_bytes([c[0], c[1], c[2], c[3]])))
+            .collect(),
+        _ => None,
+    };
+    Some(match string {
+        Some(string) => escape_attribute_value(&string),
+        None => {
+            let hex: String = value.iter().map(|octet| format!("{octet:02x}")).collect();
+            format!("#{hex}")
+        }
+    })
+}
+
+/// Splits the element at the start of `input` into its tag, contents and the rest of `input`
+fn der_next(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        usize::from(first)
+    } else {
+        let octets = usize::from(first & 0x7f);
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let (len, contents) = rest.split_at(octets);
+        rest = contents;
+        len.iter().fold(0, |len, octet| len << 8 | usize::from(*octet))
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// [`der_next`], if the element has the tag `tag`
+fn der_expect(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match der_next(input)? {
+        (actual, contents, rest) if actual == tag => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+/// Short name of a well known attribute type, or its OID in dotted form
+fn attribute_type(oid: &[u8]) -> String {
+    let name = match oid {
+        [0x55, 0x04, 0x03] => "CN",
+        [0x55, 0x04, 0x06] => "C",
+        [0x55, 0x04, 0x07] => "L",
+        [0x55, 0x04, 0x08] => "ST",
+        [0x55, 0x04, 0x09] => "STREET",
+        [0x55, 0x04, 0x0a] => "O",
+        [0x55, 0x04, 0x0b] => "OU",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC",
+        _ => return oid_to_string(oid),
+    };
+    name.to_string()
+}
+
+fn oid_to_string(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut arc = 0u64;
+    for octet in oid {
+        arc = arc << 7 | u64::from(octet & 0x7f);
+        if octet & 0x80 == 0 {
+            if arcs.is_empty() {
+                // the first octets hold the first two arcs
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    arcs.iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Escapes the characters RFC 4514 doesn't allow in attribute values
+fn escape_attribute_value(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, '"' | '+' | ',' | ';' | '<' | '>' | '\\')
+            || (i == 0 && matches!(c, '#' | ' '))
+            || (i == last && c == ' ');
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_data(file: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data")
+            .join(file)
+    }
+
+    fn server_tls_config() -> TlsConfig {
+        TlsConfig {
+            cert_chain_path: test_data("server.pem"),
+            private_key_path: test_data("server.key"),
+            client_ca_path: None,
+        }
+    }
+
+    #[test]
+    fn test_dot_server_config() {
+        let config = server_tls_config();
+        let certificate = CertificateResolver::new(&config).unwrap();
+        let server_config = dot_server_config(&config, certificate).unwrap();
+        assert_eq!(server_config.alpn_protocols, vec![b"dot".to_vec()]);
+
+        let config = TlsConfig {
+            private_key_path: test_data("missing.key"),
+            ..config
+        };
+        assert!(CertificateResolver::new(&config).is_err());
+        // a key that isn't the certificate's
+        let config = TlsConfig {
+            private_key_path: test_data("client.key"),
+            ..config
+        };
+        assert!(CertificateResolver::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_client_ca() {
+        let config = TlsConfig {
+            client_ca_path: Some(test_data("ca.pem")),
+            ..server_tls_config()
+        };
+        let certificate = CertificateResolver::new(&config).unwrap();
+        assert!(dot_server_config(&config, certificate.clone()).is_ok());
+
+        let config = TlsConfig {
+            client_ca_path: Some(test_data("missing.pem")),
+            ..config
+        };
+        assert!(dot_server_config(&config, certificate).is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("mushroom-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            cert_chain_path: dir.join("cert.pem"),
+            private_key_path: dir.join("key.pem"),
+            client_ca_path: None,
+        };
+        let install = |cert: &str, key: &str| {
+            fs::copy(test_data(cert), &config.cert_chain_path).unwrap();
+            fs::copy(test_data(key), &config.private_key_path).unwrap();
+        };
+        let subject = |certificate: &CertificateResolver| {
+            let current = certificate.current.read().unwrap().clone();
+            certificate_subject(current.end_entity_cert().unwrap())
+        };
+
+        install("server.pem", "server.key");
+        let certificate = CertificateResolver::new(&config).unwrap();
+        assert!(!certificate.files_changed());
+        assert_eq!(subject(&certificate).as_deref(), Some("CN=localhost"));
+
+        // a renewal that is only half written is rejected, the old certificate stays
+        fs::copy(test_data("client.pem"), &config.cert_chain_path).unwrap();
+        *certificate.modified.lock().unwrap() = [None, None];
+        assert!(certificate.files_changed());
+        assert!(certificate.reload().is_err());
+        assert_eq!(subject(&certificate).as_deref(), Some("CN=localhost"));
+
+        install("client.pem", "client.key");
+        certificate.reload().unwrap();
+        assert!(!certificate.files_changed());
+        assert_eq!(
+            subject(&certificate).as_deref(),
+            Some("CN=laptop.example.com,O=Mushroom Test")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_certificate_subject() {
+        let subject = |file| {
+            let cert = read_cert(&test_data(file)).unwrap().remove(0);
+            certificate_subject(&cert)
+        };
+        assert_eq!(subject("server.pem").as_deref(), Some("CN=localhost"));
+        assert_eq!(
+            subject("client.pem").as_deref(),
+            Some("CN=laptop.example.com,O=Mushroom Test")
+        );
+        assert_eq!(subject("bmp.pem").as_deref(), Some("CN=キノコ,O=Mushroom Test"));
+        assert_eq!(certificate_subject(&CertificateDer::from(vec![0x30, 0x05, 0x30])), None);
+    }
+
+    #[test]
+    fn test_attribute_value() {
+        assert_eq!(attribute_type(&[0x55, 0x04, 0x0b]), "OU");
+        // emailAddress
+        assert_eq!(
+            attribute_type(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01]),
+            "1.2.840.113549.1.9.1"
+        );
+        assert_eq!(escape_attribute_value("Mushroom, Inc. "), "Mushroom\\, Inc.\\ ");
+        assert_eq!(escape_attribute_value("#1"), "\\#1");
+
+        assert_eq!(attribute_value(b"\x13\x02#1").as_deref(), Some("\\#1"));
+        assert_eq!(attribute_value(b"\x1e\x04\x30\xad\x30\xce").as_deref(), Some("キノ"));
+        assert_eq!(attribute_value(b"\x1e\x04\x30\xad\x30\xcf").as_deref(), Some("キハ"));
+        let universal = b"\x1c\x08\x00\x01\xf3\x44\x00\x00\x00\x21";
+        assert_eq!(attribute_value(universal).as_deref(), Some("🍄!"));
+        // values that aren't strings, or strings that don't decode, as the hex of their DER
+        assert_eq!(attribute_value(b"\x02\x01\x01").as_deref(), Some("#020101"));
+        assert_eq!(attribute_value(b"\x0c\x01\xff").as_deref(), Some("#0c01ff"));
+        assert_eq!(attribute_value(b"\x13\x01\xe9").as_deref(), Some("#1301e9"));
+        assert_eq!(attribute_value(b"\x1e\x02\xd8\x00").as_deref(), Some("#1e02d800"));
+        assert_eq!(attribute_value(b"\x0c\x01a\x00"), None);
+    }
+
+    #[cfg(feature = "dns-over-https-rustls")]
+    #[test]
+    fn test_doh_server_config() {
+        let config = server_tls_config();
+        let certificate = CertificateResolver::new(&config).unwrap();
+        let server_config = doh_server_config(&config, certificate).unwrap();
+        assert_eq!(server_config.alpn_protocols, vec![b"h2".to_vec()]);
+    }
+
+    #[cfg(all(feature = "dns-over-quic", feature = "dns-over-h3"))]
+    #[test]
+    fn test_quic_server_config() {
+        let certificate = CertificateResolver::new(&server_tls_config()).unwrap();
+        let server_config = doq_server_config(certificate.clone()).unwrap();
+        assert_eq!(server_config.alpn_protocols, vec![b"doq".to_vec()]);
+        let server_config = doh3_server_config(certificate).unwrap();
+        assert_eq!(server_config.alpn_protocols, vec![b"h3".to_vec()]);
+    }
+}
@@ -0,0 +1,550 @@
+//! Forwarding of queries to upstream nameservers, keeping their responses intact
+//!
+//! `TokioResolver` turns responses into a `Lookup`, dropping the authority and additional sections
+//! and the header flags. Clients of a forwarder should see what the upstream sent, so this talks to
+//! the upstream connections directly and caches whole messages.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use hickory_proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::{RData, Record};
+use hickory_proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions, FirstAnswer, Protocol};
+use hickory_proto::{ProtoError, ProtoErrorKind};
+use hickory_resolver::config::{
+    NameServerConfig, NameServerConfigGroup, ResolveHosts, ResolverOpts, ServerOrderingStrategy,
+};
+use hickory_resolver::name_server::{
+    ConnectionProvider, GenericConnection, TokioConnectionProvider,
+};
+use hickory_resolver::{Hosts, ResolveError};
+use tracing::debug;
+
+/// EDNS UDP payload size we advertise to upstreams, see <https://www.dnsflagday.net/2020/>
+const UPSTREAM_MAX_PAYLOAD: u16 = 1232;
+
+/// Round trip time a failed query counts as in the server statistics
+const FAILURE_RTT: Duration = Duration::from_secs(5);
+
+/// A set of upstream nameservers with their connections, statistics and response cache
+pub struct Upstream {
+    servers: Vec<UpstreamServer>,
+    options: ResolverOpts,
+    provider: TokioConnectionProvider,
+    hosts: Option<Hosts>,
+    cache: ResponseCache,
+    /// Where the next query starts in the server list, when shuffling servers
+    next_server: AtomicUsize,
+}
+
+struct UpstreamServer {
+    config: NameServerConfig,
+    connection: tokio::sync::Mutex<Option<GenericConnection>>,
+    /// Smoothed round trip time in microseconds
+    srtt_micros: AtomicU32,
+}
+
+impl Upstream {
+    /// Creates an Upstream forwarding to the name servers, connections are opened on first use
+    pub fn new(name_servers: &NameServerConfigGroup, options: ResolverOpts) -> Self {
+        let hosts = match options.use_hosts_file {
+            ResolveHosts::Always | ResolveHosts::Auto => Some(Hosts::new()),
+            ResolveHosts::Never => None,
+        };
+
+        Self {
+            servers: name_servers
+                .iter()
+                .map(|config| UpstreamServer {
+                    config: config.clone(),
+                    connection: tokio::sync::Mutex::new(None),
+                    srtt_micros: AtomicU32::new(0),
+                })
+                .collect(),
+            cache: ResponseCache::new(&options),
+            options,
+            provider: TokioConnectionProvider::default(),
+            hosts,
+            next_server: AtomicUsize::new(0),
+        }
+    }
+
+    /// The nameservers queries are forwarded to
+    pub fn name_servers(&self) -> impl Iterator<Item = &NameServerConfig> {
+        self.servers.iter().map(|server| &server.config)
+    }
+
+    /// Drops all cached responses
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
+
+    /// Forgets the round trip times of the servers, as if none had been queried yet
+    pub fn reset_statistics(&self) {
+        for server in &self.servers {
+            server.srtt_micros.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Answers the query from the hosts file, the cache or else the upstream nameservers.
+    ///
+    /// With `dnssec_ok` the DO bit is set upstream, so DNSSEC records come back with the answer.
+    /// Responses with an error rcode are returned as they are, errors are only returned when no
+    /// nameserver answered at all. The AA bit is cleared, we aren't authoritative for the answer.
+    pub async fn lookup(&self, query: Query, dnssec_ok: bool) -> Result<Message, ResolveError> {
+        if let Some(lookup) = self
+            .hosts
+            .as_ref()
+            .and_then(|hosts| hosts.lookup_static_host(&query))
+        {
+            let mut message = Message::new();
+            message
+                .set_message_type(MessageType::Response)
+                .set_recursion_available(true)
+                .add_query(query)
+                .add_answers(lookup.records().iter().cloned());
+            return Ok(message);
+        }
+
+        let key = (query, dnssec_ok);
+        if let Some(message) = self.cache.get(&key) {
+            debug!(
+                "{} {} answered from cache",
+                key.0.name(),
+                key.0.query_type()
+            );
+            return Ok(message);
+        }
+
+        let mut message = self.send(&key.0, dnssec_ok).await?;
+        // the upstream may own the zone, we only forward its answer
+        message.set_authoritative(false);
+        self.cache.insert(key, &message);
+        Ok(message)
+    }
+
+    /// Tries the servers in order until one gives a usable answer, `num_concurrent_reqs` at a time
+    async fn send(&self, query: &Query, dnssec_ok: bool) -> Result<Message, ResolveError> {
+        let mut servers = self.servers.iter().collect::<Vec<_>>();
+        match self.options.server_ordering_strategy {
+            ServerOrderingStrategy::QueryStatistics => {
+                servers.sort_by_key(|server| server.srtt_micros.load(Ordering::Relaxed))
+            }
+            ServerOrderingStrategy::UserProvidedOrder => {}
+        }
+        if self.options.shuffle_dns_servers && !servers.is_empty() {
+            let start = self.next_server.fetch_add(1, Ordering::Relaxed) % servers.len();
+            servers.rotate_left(start);
+        }
+
+        let (provider, options) = (&self.provider, &self.options);
+        let concurrent = options.num_concurrent_reqs.max(1);
+        let mut last = Err(ResolveError::from("no upstream nameservers"));
+        for _ in 0..options.attempts.max(1) {
+            let mut waiting = servers.iter();
+            let mut in_flight = FuturesUnordered::new();
+            loop {
+                // a server that failed or gave up makes room for the next one
+                while in_flight.len() < concurrent {
+                    let Some(server) = waiting.next() else {
+                        break;
+                    };
+                    let query = query.clone();
+                    in_flight.push(async move {
+                        (server, server.send(provider, options, query, dnssec_ok).await)
+                    });
+                }
+                let Some((server, result)) = in_flight.next().await else {
+                    break;
+                };
+                match result {
+                    Ok(message) => match message.response_code() {
+                        // maybe just this server is having problems, the next one may do better
+                        ResponseCode::ServFail | ResponseCode::Refused => {
+                            debug!(
+                                "{} answered {}, trying the next nameserver",
+                                server.config.socket_addr,
+                                message.response_code()
+                            );
+                            last = Ok(message);
+                        }
+                        _ => return Ok(message),
+                    },
+                    Err(err) => {
+                        debug!("{} failed: {}", server.config.socket_addr, err);
+                        if last.is_err() {
+                            last = Err(err.into());
+                        }
+                    }
+                }
+            }
+        }
+        last
+    }
+}
+
+impl UpstreamServer {
+    async fn send(
+        &self,
+        provider: &TokioConnectionProvider,
+        options: &ResolverOpts,
+        query: Query,
+        dnssec_ok: bool,
+    ) -> Result<Message, ProtoError> {
+        let request = build_request(query, dnssec_ok);
+        let start = Instant::now();
+        let result = match self.connection(provider, options).await {
+            Ok(connection) => exchange(&connection, request.clone(), options.timeout).await,
+            Err(err) => Err(err),
+        };
+        let message = match result {
+            Ok(message) => message,
+            Err(err) => {
+                *self.connection.lock().await = None;
+                self.record_rtt(FAILURE_RTT);
+                return Err(err);
+            }
+        };
+        self.record_rtt(start.elapsed());
+
+        // the full answer didn't fit in a datagram, ask again over TCP
+        if message.truncated() && self.config.protocol == Protocol::Udp {
+            let mut config = self.config.clone();
+            config.protocol = Protocol::Tcp;
+            let connection = provider.new_connection(&config, options)?.await?;
+            return exchange(&connection, request, options.timeout).await;
+        }
+
+        Ok(message)
+    }
+
+    async fn connection(
+        &self,
+        provider: &TokioConnectionProvider,
+        options: &ResolverOpts,
+    ) -> Result<GenericConnection, ProtoError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = &*connection {
+            return Ok(connection.clone());
+        }
+
+        let connected = provider.new_connection(&self.config, options)?.await?;
+        *connection = Some(connected.clone());
+        Ok(connected)
+    }
+
+    fn record_rtt(&self, rtt: Duration) {
+        let rtt = u32::try_from(rtt.as_micros()).unwrap_or(u32::MAX);
+        let _ = self
+            .srtt_micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |srtt| {
+                if srtt == 0 {
+                    Some(rtt)
+                } else {
+                    Some(((u64::from(srtt) * 7 + u64::from(rtt)) / 8) as u32)
+                }
+            });
+    }
+}
+
+fn build_request(query: Query, dnssec_ok: bool) -> DnsRequest {
+    let mut message = Message::new();
+    message
+        .add_query(query)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        // ask for the AD bit, RFC 6840 section 5.7
+        .set_authentic_data(true);
+
+    let mut edns = Edns::new();
+    edns.set_max_payload(UPSTREAM_MAX_PAYLOAD)
+        .set_version(0)
+        .set_dnssec_ok(dnssec_ok);
+    message.set_edns(edns);
+
+    DnsRequest::new(message, DnsRequestOptions::default())
+}
+
+async fn exchange(
+    connection: &GenericConnection,
+    request: DnsRequest,
+    timeout: Duration,
+) -> Result<Message, ProtoError> {
+    match tokio::time::timeout(timeout, connection.send(request).first_answer()).await {
+        Ok(response) => Ok(response?.into_message()),
+        Err(_) => Err(ProtoErrorKind::Timeout.into()),
+    }
+}
+
+/// A query and whether it was sent with the DO bit, as responses differ by it
+type CacheKey = (Query, bool);
+
+/// Upstream responses by query, kept for as long as their TTLs allow
+struct ResponseCache {
+    max_entries: usize,
+    positive_ttl: (Duration, Duration),
+    negative_ttl: (Duration, Duration),
+    entries: Mutex<HashMap<CacheKey, CachedResponse>>,
+}
+
+struct CachedResponse {
+    message: Message,
+    valid_until: Instant,
+    inserted: Instant,
+}
+
+impl ResponseCache {
+    fn new(options: &ResolverOpts) -> Self {
+        let max_ttl = Duration::from_secs(u64::from(u32::MAX));
+        Self {
+            max_entries: options.cache_size,
+            positive_ttl: (
+                options.positive_min_ttl.unwrap_or_default(),
+                options.positive_max_ttl.unwrap_or(max_ttl),
+            ),
+            negative_ttl: (
+                options.negative_min_ttl.unwrap_or_default(),
+                options.negative_max_ttl.unwrap_or(max_ttl),
+            ),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Message> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("response cache lock poisoned");
+        let cached = entries.get(key)?;
+        if cached.valid_until <= now {
+            entries.remove(key);
+            return None;
+        }
+
+        let mut message = cached.message.clone();
+        let elapsed = u32::try_from(now.duration_since(cached.inserted).as_secs()).unwrap_or(0);
+        let age = |records: &mut Vec<Record>| {
+            for record in records {
+                record.set_ttl(record.ttl().saturating_sub(elapsed));
+            }
+        };
+        age(message.answers_mut());
+        age(message.name_servers_mut());
+        age(message.additionals_mut());
+        Some(message)
+    }
+
+    fn insert(&self, key: CacheKey, message: &Message) {
+        let Some(ttl) = self.ttl(message) else {
+            return;
+        };
+        if self.max_entries == 0 || ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("response cache lock poisoned");
+        if entries.len() >= self.max_entries {
+            entries.retain(|_, cached| cached.valid_until > now);
+        }
+        if entries.len() >= self.max_entries {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, cached)| cached.inserted)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            key,
+            CachedResponse {
+                message: message.clone(),
+                valid_until: now + ttl,
+                inserted: now,
+            },
+        );
+    }
+
+    /// How long a response may be cached, None if it may not be cached at all
+    fn ttl(&self, message: &Message) -> Option<Duration> {
+        if message.truncated() {
+            return None;
+        }
+
+        match message.response_code() {
+            ResponseCode::NoError if !message.answers().is_empty() => {
+                let ttl = message.answers().iter().map(Record::ttl).min()?;
+                let (min, max) = self.positive_ttl;
+                Some(Duration::from_secs(u64::from(ttl)).clamp(min, max))
+            }
+            ResponseCode::NoError | ResponseCode::NXDomain => {
+                let ttl = message
+                    .name_servers()
+                    .iter()
+                    .find_map(|record| match record.data() {
+                        RData::SOA(soa) => Some(record.ttl().min(soa.minimum())),
+                        _ => None,
+                    })?;
+                let (min, max) = self.negative_ttl;
+                Some(Duration::from_secs(u64::from(ttl)).clamp(min, max))
+            }
+            _ => None,
+        }
+    }
+
+    fn clear(&self) {
+        self.entries
+            .lock()
+            .expect("response cache lock poisoned")
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::rdata::{A, SOA};
+    use hickory_proto::rr::{Name, RecordType};
+    use std::str::FromStr;
+
+    fn query() -> Query {
+        Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::A)
+    }
+
+    fn response(response_code: ResponseCode) -> Message {
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_response_code(response_code)
+            .add_query(query());
+        message
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> Record {
+        let origin = Name::from_str("example.com.").unwrap();
+        Record::from_rdata(
+            origin.clone(),
+            ttl,
+            RData::SOA(SOA::new(
+                origin.clone(),
+                origin,
+                1,
+                3600,
+                600,
+                86400,
+                minimum,
+            )),
+        )
+    }
+
+    #[test]
+    fn test_request_dnssec_ok() {
+        let request = build_request(query(), true);
+        let edns = request.extensions().as_ref().unwrap();
+        assert!(edns.flags().dnssec_ok);
+        assert_eq!(edns.max_payload(), UPSTREAM_MAX_PAYLOAD);
+        assert!(request.recursion_desired());
+
+        let request = build_request(query(), false);
+        assert!(!request.extensions().as_ref().unwrap().flags().dnssec_ok);
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let cache = ResponseCache::new(&ResolverOpts::default());
+
+        let mut answer = response(ResponseCode::NoError);
+        answer.add_answer(Record::from_rdata(
+            query().name().clone(),
+            60,
+            RData::A(A::new(127, 0, 0, 1)),
+        ));
+        answer.add_additional(soa(30, 30));
+        assert_eq!(cache.ttl(&answer), Some(Duration::from_secs(60)));
+
+        let mut nxdomain = response(ResponseCode::NXDomain);
+        nxdomain.add_name_server(soa(3600, 300));
+        assert_eq!(cache.ttl(&nxdomain), Some(Duration::from_secs(300)));
+
+        assert_eq!(cache.ttl(&response(ResponseCode::NoError)), None);
+        assert_eq!(cache.ttl(&response(ResponseCode::ServFail)), None);
+    }
+
+    #[test]
+    fn test_cache_keeps_sections() {
+        let cache = ResponseCache::new(&ResolverOpts::default());
+
+        let mut answer = response(ResponseCode::NoError);
+        answer
+            .set_authentic_data(true)
+            .set_recursion_available(true)
+            .add_answer(Record::from_rdata(
+                query().name().clone(),
+                60,
+                RData::A(A::new(127, 0, 0, 1)),
+            ))
+            .add_name_server(soa(3600, 300))
+            .add_additional(soa(3600, 300));
+        cache.insert((query(), false), &answer);
+        assert!(cache.get(&(query(), true)).is_none());
+
+        let cached = cache.get(&(query(), false)).unwrap();
+        assert_eq!(cached.header(), answer.header());
+        assert_eq!(cached.answers(), answer.answers());
+        assert_eq!(cached.name_servers(), answer.name_servers());
+        assert_eq!(cached.additionals(), answer.additionals());
+
+        cache.clear();
+        assert!(cache.get(&(query(), false)).is_none());
+    }
+
+    #[test]
+    fn test_concurrent_requests() {
+        // takes queries and never answers them
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let answering = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = answering.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            while let Ok((len, peer)) = answering.recv_from(&mut buffer) {
+                let request = Message::from_vec(&buffer[..len]).unwrap();
+                let mut answer = response(ResponseCode::NoError);
+                answer
+                    .set_id(request.id())
+                    .set_authoritative(true)
+                    .add_answer(Record::from_rdata(
+                        query().name().clone(),
+                        60,
+                        RData::A(A::new(10, 9, 9, 9)),
+                    ));
+                answering.send_to(&answer.to_vec().unwrap(), peer).unwrap();
+            }
+        });
+
+        let mut name_servers = NameServerConfigGroup::new();
+        for socket_addr in [silent.local_addr().unwrap(), address] {
+            name_servers.push(NameServerConfig::new(socket_addr, Protocol::Udp));
+        }
+        let mut options = ResolverOpts::default();
+        options.server_ordering_strategy = ServerOrderingStrategy::UserProvidedOrder;
+        options.shuffle_dns_servers = false;
+        options.use_hosts_file = ResolveHosts::Never;
+        options.attempts = 1;
+        options.timeout = Duration::from_secs(5);
+        options.num_concurrent_reqs = 2;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let start = Instant::now();
+        let message = runtime
+            .block_on(Upstream::new(&name_servers, options).lookup(query(), false))
+            .unwrap();
+        assert_eq!(message.answers()[0].data(), &RData::A(A::new(10, 9, 9, 9)));
+        assert!(!message.authoritative());
+        // the silent server was not waited for
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,28 @@
+#!/bin/sh
+# Regenerates the test certificates: a CA, a server certificate for localhost and a client
+# certificate for mutual TLS, both signed by the CA, and a self-signed one with a BMPString
+# subject.
+set -e
+cd "$(dirname "$0")"
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -days 36500 \
+    -subj "/CN=mushroom-dnresolver test CA" -keyout ca.key -out ca.pem
+
+openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+    -subj "/CN=localhost" -keyout server.key -out server.csr
+printf 'subjectAltName=DNS:localhost,IP:127.0.0.1,IP:::1\nbasicConstraints=CA:FALSE\nextendedKeyUsage=serverAuth\n' > server.ext
+openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 36500 \
+    -extfile server.ext -out server.pem
+
+openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+    -subj "/O=Mushroom Test/CN=laptop.example.com" -keyout client.key -out client.csr
+printf 'basicConstraints=CA:FALSE\nextendedKeyUsage=clientAuth\n' > client.ext
+openssl x509 -req -in client.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 36500 \
+    -extfile client.ext -out client.pem
+
+# a certificate with its subject in BMPStrings, which some tools still issue
+printf '[req]\ndistinguished_name=dn\nstring_mask=MASK:0x800\n[dn]\n' > bmp.cnf
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -days 36500 -config bmp.cnf \
+    -utf8 -subj "/O=Mushroom Test/CN=キノコ" -keyout bmp.key -out bmp.pem
+
+rm -f server.csr server.ext client.csr client.ext ca.srl bmp.cnf bmp.key